@@ -0,0 +1,169 @@
+//! Loopback benchmark comparing a file transfer over a single stream with one split over
+//! several streams.
+//!
+//! Each stream reads, hashes, compresses and encrypts its segment on its own task, so the
+//! speedup grows with the number of cores. Both an incompressible file and a compressible
+//! one are sent, the latter keeping zstd busy.
+//!
+//! Run with `cargo bench --bench transfer`. `DEZAP_BENCH_MIB` sets the file size in MiB
+//! (default 256).
+
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dezap::config::AppConfig;
+use dezap::service::{DezapService, ServiceCommand, ServiceEvent};
+use rand::RngCore;
+
+const STREAM_COUNTS: [usize; 2] = [1, 4];
+
+fn config(dir: &Path, name: &str, streams: usize) -> Result<AppConfig> {
+    let mut config = AppConfig::default();
+    config.identity.username = name.into();
+    config.listen.bind_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    config.discovery.enabled = false;
+    config.transfer.streams = streams;
+    config.paths.download_dir = dir.join("downloads");
+    config.paths.history_dir = dir.join("history");
+    config.paths.peers_file = dir.join("peers.json");
+    config.paths.chat_log = None;
+    config.paths.normalize()?;
+    Ok(config)
+}
+
+/// Waits for the first event matching `wanted`, failing on errors.
+async fn next_event(
+    service: &mut DezapService,
+    wanted: impl Fn(&ServiceEvent) -> bool,
+) -> Result<ServiceEvent> {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(300), service.next_event())
+            .await
+            .context("timed out waiting for the transfer")?
+            .context("service stopped")?;
+        match event {
+            ServiceEvent::Error { message, .. } => bail!(message),
+            event if wanted(&event) => return Ok(event),
+            _ => {}
+        }
+    }
+}
+
+/// Sends `source` between two services on loopback and returns how long it took from
+/// accepting the offer until the verified file was in place.
+async fn transfer(dir: &Path, source: &Path, streams: usize) -> Result<Duration> {
+    let port = UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let run = dir.join(format!("{streams}-streams"));
+    let mut receiver = DezapService::new(config(&run.join("receiver"), "receiver", streams)?);
+    let mut sender = DezapService::new(config(&run.join("sender"), "sender", streams)?);
+
+    receiver
+        .send(ServiceCommand::Listen {
+            addr,
+            password: None,
+        })
+        .await?;
+    next_event(&mut receiver, |event| {
+        matches!(event, ServiceEvent::Listening { .. })
+    })
+    .await?;
+    sender
+        .send(ServiceCommand::Connect {
+            addr,
+            password: None,
+        })
+        .await?;
+    next_event(&mut sender, |event| {
+        matches!(event, ServiceEvent::PeerProfile { .. })
+    })
+    .await?;
+    let ServiceEvent::PeerProfile { peer, .. } = next_event(&mut receiver, |event| {
+        matches!(event, ServiceEvent::PeerProfile { .. })
+    })
+    .await?
+    else {
+        unreachable!()
+    };
+
+    // Progress reports wait for room in the event channel, so keep the sender's drained.
+    let commands = sender.command_sender();
+    let drain = tokio::spawn(async move { while sender.next_event().await.is_some() {} });
+    commands
+        .send(ServiceCommand::SendFile {
+            peer: addr,
+            paths: vec![source.to_path_buf()],
+        })
+        .await?;
+    let ServiceEvent::FileOffer(offer) = next_event(&mut receiver, |event| {
+        matches!(event, ServiceEvent::FileOffer(_))
+    })
+    .await?
+    else {
+        unreachable!()
+    };
+    let started = Instant::now();
+    receiver
+        .send(ServiceCommand::AcceptFile {
+            peer,
+            id: offer.id,
+            path: run.join("received.bin"),
+            on_collision: None,
+        })
+        .await?;
+    next_event(
+        &mut receiver,
+        |event| matches!(event, ServiceEvent::FileTransfer(progress) if progress.completed),
+    )
+    .await?;
+    let elapsed = started.elapsed();
+    drain.abort();
+    Ok(elapsed)
+}
+
+/// Writes the benchmark files: random bytes, which are sent as they are, and repetitive
+/// text, which is compressed.
+fn write_sources(dir: &Path, mib: usize) -> Result<Vec<(&'static str, PathBuf)>> {
+    let size = mib * 1024 * 1024;
+    let mut random = vec![0u8; size];
+    rand::rng().fill_bytes(&mut random);
+    let random_path = dir.join("random.bin");
+    std::fs::write(&random_path, &random)?;
+
+    let mut text = Vec::with_capacity(size);
+    let mut line = 0u64;
+    while text.len() < size {
+        text.extend_from_slice(
+            format!("{line:>12} dezap benchmark line {}\n", line % 97).as_bytes(),
+        );
+        line += 1;
+    }
+    text.truncate(size);
+    let text_path = dir.join("text.log");
+    std::fs::write(&text_path, &text)?;
+    Ok(vec![("random", random_path), ("text", text_path)])
+}
+
+fn main() -> Result<()> {
+    let mib: usize = match std::env::var("DEZAP_BENCH_MIB") {
+        Ok(value) => value.parse().context("DEZAP_BENCH_MIB must be a number")?,
+        Err(_) => 256,
+    };
+    let dir = tempfile::tempdir()?;
+    let sources = write_sources(dir.path(), mib)?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    for (kind, source) in &sources {
+        for streams in STREAM_COUNTS {
+            let run = dir.path().join(kind);
+            let elapsed = runtime.block_on(transfer(&run, source, streams))?;
+            let rate = mib as f64 / elapsed.as_secs_f64();
+            println!(
+                "{kind:>6}, {streams} stream(s): {mib} MiB in {elapsed:.2?} ({rate:.1} MiB/s)"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! Transfer speed measurement and rate limiting.
+//!
+//! [`RateLimiter`] is a token bucket: it lets up to one second worth of data through at once
+//! and then spaces writes out to the configured rate. [`Throughput`] smooths the observed
+//! speed of a single transfer for progress reports.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Shortest span the speed estimate is measured over, so a burst of chunks landing together
+/// does not make it jump around.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Weight of the newest sample in the moving average.
+const SMOOTHING: f64 = 0.3;
+
+/// Token bucket capping a stream of bytes to a rate. A rate of 0 means unlimited.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket and returns how long to wait before sending them.
+    ///
+    /// The bucket may go into debt for writes larger than what is left; later callers wait
+    /// until it is paid back, which keeps limiters shared between transfers fair.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        self.reserve_at(bytes, Instant::now())
+    }
+
+    fn reserve_at(&self, bytes: usize, now: Instant) -> Duration {
+        if self.bytes_per_sec == 0 {
+            return Duration::ZERO;
+        }
+        let rate = self.bytes_per_sec as f64;
+        let mut bucket = self.bucket.lock();
+        let elapsed = now.saturating_duration_since(bucket.refilled);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.refilled = bucket.refilled.max(now);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+/// Waits until `bytes` are allowed through every one of `limiters`.
+pub async fn throttle(limiters: &[&RateLimiter], bytes: usize) {
+    let wait = limiters
+        .iter()
+        .map(|limiter| limiter.reserve(bytes))
+        .max()
+        .unwrap_or_default();
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Smoothed speed and remaining time of one transfer.
+pub struct Throughput {
+    total: u64,
+    transferred: u64,
+    sample_start: Instant,
+    sample_bytes: u64,
+    bytes_per_sec: Option<f64>,
+    restart: bool,
+}
+
+impl Throughput {
+    /// Starts measuring a transfer of `total` bytes of which `transferred` are already done.
+    pub fn new(transferred: u64, total: u64) -> Self {
+        Self {
+            total,
+            transferred,
+            sample_start: Instant::now(),
+            sample_bytes: transferred,
+            bytes_per_sec: None,
+            restart: false,
+        }
+    }
+
+    /// Records that the transfer reached `transferred` bytes.
+    pub fn update(&mut self, transferred: u64) {
+        self.update_at(transferred, Instant::now());
+    }
+
+    /// Leaves the time until the next update out of the estimate, e.g. while paused.
+    pub fn pause(&mut self) {
+        self.restart = true;
+    }
+
+    /// Smoothed speed in bytes per second, once enough has been measured.
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.bytes_per_sec.map(|rate| rate.round() as u64)
+    }
+
+    /// Time left at the current speed.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.bytes_per_sec.filter(|rate| *rate >= 1.0)?;
+        let left = self.total.saturating_sub(self.transferred);
+        Some(Duration::from_secs_f64(left as f64 / rate))
+    }
+
+    fn update_at(&mut self, transferred: u64, now: Instant) {
+        self.transferred = transferred;
+        if self.restart {
+            self.restart = false;
+            self.sample_start = now;
+            self.sample_bytes = transferred;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.sample_start);
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+        let sample = transferred.saturating_sub(self.sample_bytes) as f64 / elapsed.as_secs_f64();
+        self.bytes_per_sec = Some(match self.bytes_per_sec {
+            Some(rate) => rate + SMOOTHING * (sample - rate),
+            None => sample,
+        });
+        self.sample_start = now;
+        self.sample_bytes = transferred;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_paces_to_the_rate() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        assert_eq!(limiter.reserve_at(1000, start), Duration::ZERO);
+        assert_eq!(limiter.reserve_at(500, start), Duration::from_millis(500));
+        // Debt is paid back before anything else goes through.
+        let later = start + Duration::from_millis(250);
+        assert_eq!(limiter.reserve_at(250, later), Duration::from_millis(500));
+        // Idle time refills the bucket, but never beyond one second worth.
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(limiter.reserve_at(1000, idle), Duration::ZERO);
+        assert!(RateLimiter::new(0).reserve(usize::MAX).is_zero());
+    }
+
+    #[test]
+    fn throughput_is_smoothed_and_skips_pauses() {
+        let start = Instant::now();
+        let mut meter = Throughput::new(0, 10_000);
+        meter.sample_start = start;
+        meter.update_at(100, start + Duration::from_millis(100));
+        assert_eq!(meter.bytes_per_sec(), None);
+        meter.update_at(1000, start + Duration::from_secs(1));
+        assert_eq!(meter.bytes_per_sec(), Some(1000));
+        assert_eq!(meter.eta(), Some(Duration::from_secs(9)));
+
+        meter.pause();
+        meter.update_at(1000, start + Duration::from_secs(60));
+        meter.update_at(3000, start + Duration::from_secs(61));
+        // 0.7 * 1000 + 0.3 * 2000
+        assert_eq!(meter.bytes_per_sec(), Some(1300));
+    }
+}
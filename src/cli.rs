@@ -53,7 +53,7 @@ pub enum Commands {
 }
 
 /// Parameters for the TUI mode.
-#[derive(Debug, Clone, Args)]
+#[derive(Debug, Clone, Default, Args)]
 pub struct TuiCommand {
     /// Automatically start listening using this bind address when the TUI boots.
     #[arg(long)]
@@ -68,16 +68,6 @@ pub struct TuiCommand {
     pub disable_discovery: bool,
 }
 
-impl Default for TuiCommand {
-    fn default() -> Self {
-        Self {
-            bind: None,
-            connect: None,
-            disable_discovery: false,
-        }
-    }
-}
-
 /// Parameters for the headless listener.
 #[derive(Debug, Clone, Args)]
 pub struct ListenCommand {
@@ -109,7 +99,7 @@ pub struct SendFileCommand {
     #[arg(long, value_name = "HOST:PORT")]
     pub to: SocketAddr,
 
-    /// Path to the local file that should be transmitted.
-    #[arg(long, value_name = "PATH")]
-    pub path: PathBuf,
+    /// File or directory to transmit; repeat to send several as one transfer.
+    #[arg(long = "path", value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
 }
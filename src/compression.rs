@@ -0,0 +1,324 @@
+//! On-the-fly compression for file streams.
+//!
+//! Each stream uses one codec negotiated for the file. Stream codecs are flushed after every
+//! chunk (lz4 compresses each chunk as its own block), so a frame always inflates to exactly
+//! the bytes that were read for it. Chunk offsets therefore stay in original-file space and a
+//! transfer can restart a fresh stream from any offset.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+/// Bytes read from each of the start, middle and end of a file to judge its compressibility.
+pub const SAMPLE_WINDOW_BYTES: usize = 256 * 1024;
+
+/// Largest chunk of original data carried by one frame. Keeps incompressible chunks well
+/// inside [`crate::protocol::MAX_FRAME_BYTES`] and bounds what a single frame may inflate to.
+pub const MAX_CHUNK_BYTES: usize = 128 * 1024;
+
+/// Samples compressing worse than this are sent uncompressed by the automatic choice.
+pub const INCOMPRESSIBLE_RATIO: f32 = 0.9;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression applied to a file stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    None,
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    /// Codecs this build can decode, in order of preference. Advertised in the hello.
+    pub const SUPPORTED: [Codec; 4] = [Codec::Zstd, Codec::Lz4, Codec::Gzip, Codec::None];
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Codec::None => "none",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Picks the codec for a file from `sample`, returning it with the estimated
+/// compressed/original ratio.
+///
+/// A `forced` codec is used as long as the peer can decode it. Otherwise the first codec the
+/// peer supports from [`Codec::SUPPORTED`] is tried, falling back to none when the sample
+/// barely compresses.
+pub fn choose_codec(sample: &[u8], forced: Option<Codec>, peer: &[Codec]) -> Result<(Codec, f32)> {
+    if let Some(codec) = forced {
+        if codec != Codec::None && !peer.contains(&codec) {
+            bail!("peer does not support the {codec} codec");
+        }
+        return Ok((codec, estimate_ratio(codec, sample)?));
+    }
+    let Some(codec) = Codec::SUPPORTED
+        .into_iter()
+        .find(|codec| *codec != Codec::None && peer.contains(codec))
+    else {
+        return Ok((Codec::None, 1.0));
+    };
+    let ratio = estimate_ratio(codec, sample)?;
+    if ratio > INCOMPRESSIBLE_RATIO {
+        return Ok((Codec::None, 1.0));
+    }
+    Ok((codec, ratio))
+}
+
+/// Compressed/original size ratio of `sample` under `codec`.
+pub fn estimate_ratio(codec: Codec, sample: &[u8]) -> Result<f32> {
+    if sample.is_empty() || codec == Codec::None {
+        return Ok(1.0);
+    }
+    let compressed = ChunkEncoder::new(codec)?.encode(sample)?;
+    Ok(compressed.len() as f32 / sample.len() as f32)
+}
+
+/// Reads windows from the start, middle and end of the file at `path`, so headers or trailers
+/// alone do not decide how compressible it is.
+pub fn sample_file(path: &Path) -> Result<Vec<u8>> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    let window = SAMPLE_WINDOW_BYTES as u64;
+    let windows = if size <= window * 3 {
+        vec![(0, size)]
+    } else {
+        vec![
+            (0, window),
+            (size / 2 - window / 2, window),
+            (size - window, window),
+        ]
+    };
+    let mut sample = Vec::new();
+    for (start, len) in windows {
+        file.seek(SeekFrom::Start(start))?;
+        (&mut file)
+            .take(len)
+            .read_to_end(&mut sample)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+    }
+    Ok(sample)
+}
+
+/// Compresses the chunks of one outgoing stream.
+pub(crate) enum ChunkEncoder {
+    None,
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Lz4,
+}
+
+impl ChunkEncoder {
+    pub(crate) fn new(codec: Codec) -> Result<Self> {
+        Ok(match codec {
+            Codec::None => Self::None,
+            Codec::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Codec::Zstd => Self::Zstd(
+                zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)
+                    .context("failed to start zstd encoder")?,
+            ),
+            Codec::Lz4 => Self::Lz4,
+        })
+    }
+
+    /// Compresses `chunk` and returns everything needed to inflate it on the other side.
+    pub(crate) fn encode(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(chunk.to_vec()),
+            Self::Gzip(encoder) => flush_through(encoder, chunk),
+            Self::Zstd(encoder) => flush_through(encoder, chunk),
+            Self::Lz4 => Ok(lz4_flex::block::compress_prepend_size(chunk)),
+        }
+    }
+}
+
+fn flush_through<W: Write + BufferedOutput>(encoder: &mut W, chunk: &[u8]) -> Result<Vec<u8>> {
+    encoder
+        .write_all(chunk)
+        .and_then(|_| encoder.flush())
+        .context("failed to compress chunk")?;
+    Ok(encoder.take_output())
+}
+
+/// Stream codecs that write into an in-memory buffer drained after every chunk.
+trait BufferedOutput {
+    fn take_output(&mut self) -> Vec<u8>;
+}
+
+impl BufferedOutput for GzEncoder<Vec<u8>> {
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+}
+
+impl BufferedOutput for zstd::stream::write::Encoder<'static, Vec<u8>> {
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+}
+
+impl BufferedOutput for GzDecoder<BoundedBuffer> {
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.get_mut().data)
+    }
+}
+
+impl BufferedOutput for zstd::stream::write::Decoder<'static, BoundedBuffer> {
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.get_mut().data)
+    }
+}
+
+/// Inflates the chunks of one incoming stream, holding at most one chunk in memory.
+pub(crate) enum ChunkDecoder {
+    None,
+    Gzip(GzDecoder<BoundedBuffer>),
+    Zstd(zstd::stream::write::Decoder<'static, BoundedBuffer>),
+    Lz4,
+}
+
+impl ChunkDecoder {
+    pub(crate) fn new(codec: Codec) -> Result<Self> {
+        Ok(match codec {
+            Codec::None => Self::None,
+            Codec::Gzip => Self::Gzip(GzDecoder::new(BoundedBuffer::default())),
+            Codec::Zstd => Self::Zstd(
+                zstd::stream::write::Decoder::new(BoundedBuffer::default())
+                    .context("failed to start zstd decoder")?,
+            ),
+            Codec::Lz4 => Self::Lz4,
+        })
+    }
+
+    /// Inflates `chunk`, failing if it would produce more than `limit` bytes (and never more
+    /// than [`MAX_CHUNK_BYTES`]).
+    pub(crate) fn decode(&mut self, chunk: &[u8], limit: u64) -> Result<Vec<u8>> {
+        let limit = usize::try_from(limit)
+            .unwrap_or(usize::MAX)
+            .min(MAX_CHUNK_BYTES);
+        match self {
+            Self::None => {
+                if chunk.len() > limit {
+                    bail!("chunk is larger than the announced file size");
+                }
+                Ok(chunk.to_vec())
+            }
+            Self::Gzip(decoder) => {
+                decoder.get_mut().limit = limit;
+                flush_through(decoder, chunk).context("failed to decompress chunk")
+            }
+            Self::Zstd(decoder) => {
+                decoder.get_mut().limit = limit;
+                flush_through(decoder, chunk).context("failed to decompress chunk")
+            }
+            Self::Lz4 => {
+                let Some(size) = chunk.get(..4) else {
+                    bail!("truncated lz4 chunk");
+                };
+                let size = u32::from_le_bytes(size.try_into().expect("four bytes")) as usize;
+                if size > limit {
+                    bail!("chunk inflates past the announced file size");
+                }
+                lz4_flex::block::decompress_size_prepended(chunk)
+                    .context("failed to decompress chunk")
+            }
+        }
+    }
+}
+
+/// Output sink that refuses to grow past `limit`.
+#[derive(Default)]
+pub(crate) struct BoundedBuffer {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for BoundedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.data.len() + buf.len() > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk inflates past the announced file size",
+            ));
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_inflate_one_by_one_with_every_codec() {
+        let text = b"dezap streams files chunk by chunk. ".repeat(2000);
+        for codec in Codec::SUPPORTED {
+            let mut encoder = ChunkEncoder::new(codec).unwrap();
+            let mut decoder = ChunkDecoder::new(codec).unwrap();
+            let mut restored = Vec::new();
+            for chunk in text.chunks(4096) {
+                let frame = encoder.encode(chunk).unwrap();
+                let inflated = decoder.decode(&frame, chunk.len() as u64).unwrap();
+                assert_eq!(inflated, chunk, "{codec}");
+                restored.extend_from_slice(&inflated);
+            }
+            assert_eq!(restored, text, "{codec}");
+        }
+    }
+
+    #[test]
+    fn oversized_chunks_are_rejected() {
+        let zeros = vec![0u8; 1024 * 1024];
+        for codec in Codec::SUPPORTED {
+            let frame = ChunkEncoder::new(codec).unwrap().encode(&zeros).unwrap();
+            let mut decoder = ChunkDecoder::new(codec).unwrap();
+            assert!(decoder.decode(&frame, 4096).is_err(), "{codec}");
+        }
+    }
+
+    #[test]
+    fn incompressible_samples_skip_compression() {
+        let text = b"plain text compresses well ".repeat(4000);
+        let (codec, ratio) = choose_codec(&text, None, &Codec::SUPPORTED).unwrap();
+        assert_eq!(codec, Codec::Zstd);
+        assert!(ratio < 0.1);
+
+        let mut noise = vec![0u8; 64 * 1024];
+        let mut state = 0x2545_f491_u32;
+        for byte in noise.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+        let (codec, _) = choose_codec(&noise, None, &Codec::SUPPORTED).unwrap();
+        assert_eq!(codec, Codec::None);
+
+        let (codec, _) = choose_codec(&noise, Some(Codec::Gzip), &[Codec::Gzip]).unwrap();
+        assert_eq!(codec, Codec::Gzip);
+        assert!(choose_codec(&text, Some(Codec::Lz4), &[Codec::Gzip]).is_err());
+        let (codec, _) = choose_codec(&text, None, &[Codec::Gzip, Codec::None]).unwrap();
+        assert_eq!(codec, Codec::Gzip);
+    }
+}
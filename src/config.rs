@@ -1,43 +1,34 @@
 use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use config::{Config, Environment, File};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 
+use crate::compression::Codec;
+use crate::protocol::PresenceState;
+
 /// Application configuration merged from defaults, config files, and CLI overrides.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct AppConfig {
     pub listen: ListenConfig,
     pub peer: PeerConfig,
+    pub reconnect: ReconnectConfig,
+    pub presence: PresenceConfig,
     pub identity: IdentityConfig,
     pub paths: PathsConfig,
     pub limits: LimitsConfig,
+    pub transfer: TransferConfig,
     pub tls: TlsConfig,
     pub ui: UiConfig,
     pub logging: LoggingConfig,
     pub discovery: DiscoveryConfig,
 }
 
-impl Default for AppConfig {
-    fn default() -> Self {
-        Self {
-            listen: ListenConfig::default(),
-            peer: PeerConfig::default(),
-            identity: IdentityConfig::default(),
-            paths: PathsConfig::default(),
-            limits: LimitsConfig::default(),
-            tls: TlsConfig::default(),
-            ui: UiConfig::default(),
-            logging: LoggingConfig::default(),
-            discovery: DiscoveryConfig::default(),
-        }
-    }
-}
-
 impl AppConfig {
     /// Loads configuration using defaults, optionally merging explicit files.
     pub fn load(explicit_path: Option<&Path>) -> Result<Self> {
@@ -62,6 +53,7 @@ impl AppConfig {
             .context("failed to deserialize configuration")?;
 
         cfg.paths.normalize()?;
+        cfg.transfer.normalize();
         Ok(cfg)
     }
 
@@ -88,15 +80,71 @@ impl Default for ListenConfig {
 }
 
 /// Default peer configuration.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct PeerConfig {
     pub default_peer: Option<SocketAddr>,
 }
 
-impl Default for PeerConfig {
+/// Redialing of outgoing connections that dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub enabled: bool,
+    /// Wait before the first attempt; it doubles with every failed one.
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Share of the wait added or taken off at random, from 0.0 to 1.0, so peers that
+    /// dropped together do not redial in lockstep.
+    pub jitter: f64,
+    /// Attempts before giving up; 0 keeps trying.
+    pub max_attempts: u32,
+}
+
+impl ReconnectConfig {
+    /// Wait before attempt `attempt` (starting at 1), with `spread` from -1.0 to 1.0 picking
+    /// where it falls within the jitter.
+    pub fn delay(&self, attempt: u32, spread: f64) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(32);
+        let base = self
+            .initial_delay_ms
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay_ms.max(self.initial_delay_ms));
+        let jitter = self.jitter.clamp(0.0, 1.0) * spread.clamp(-1.0, 1.0);
+        Duration::from_millis((base as f64 * (1.0 + jitter)) as u64)
+    }
+}
+
+impl Default for ReconnectConfig {
     fn default() -> Self {
-        Self { default_peer: None }
+        Self {
+            enabled: false,
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
+}
+
+/// Availability announced to peers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// State announced on connect until changed.
+    pub state: PresenceState,
+    pub status: Option<String>,
+    /// Seconds between repeated announcements; 0 only announces changes.
+    pub heartbeat_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            state: PresenceState::Online,
+            status: None,
+            heartbeat_secs: 30,
+        }
     }
 }
 
@@ -194,14 +242,142 @@ pub struct LimitsConfig {
     pub max_message_bytes: usize,
     pub max_file_bytes: u64,
     pub chunk_size_bytes: usize,
+    /// Free space that must remain on the download disk once an accepted file is stored.
+    pub reserve_disk_bytes: u64,
+    /// Upload speed cap shared by all transfers, in bytes per second on the wire; 0 is unlimited.
+    pub upload_bytes_per_sec: u64,
+    /// Download speed cap shared by all transfers; 0 is unlimited.
+    pub download_bytes_per_sec: u64,
+    /// Upload speed cap for each transfer on its own; 0 is unlimited.
+    pub transfer_upload_bytes_per_sec: u64,
+    /// Download speed cap for each transfer on its own; 0 is unlimited.
+    pub transfer_download_bytes_per_sec: u64,
+    /// How long a file offer waits for an answer before it expires; 0 waits forever.
+    pub offer_ttl_secs: u64,
+    /// Unfinished downloads untouched for longer than this are deleted at startup; 0 keeps them.
+    pub partial_ttl_secs: u64,
 }
 
 impl Default for LimitsConfig {
     fn default() -> Self {
         Self {
             max_message_bytes: 16 * 1024,
-            max_file_bytes: 1 * 1024 * 1024 * 1024,
+            max_file_bytes: 1024 * 1024 * 1024,
             chunk_size_bytes: 64 * 1024,
+            reserve_disk_bytes: 64 * 1024 * 1024,
+            upload_bytes_per_sec: 0,
+            download_bytes_per_sec: 0,
+            transfer_upload_bytes_per_sec: 0,
+            transfer_download_bytes_per_sec: 0,
+            offer_ttl_secs: 10 * 60,
+            partial_ttl_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// File transfer settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    pub codec: CodecChoice,
+    pub on_collision: CollisionPolicy,
+    /// Streams a large file is split over; 1 sends every file over a single stream.
+    pub streams: usize,
+    /// Offers accepted without asking; the first matching rule wins.
+    pub auto_accept: Vec<AutoAcceptRule>,
+}
+
+impl TransferConfig {
+    /// Expands tilde paths in the auto-accept destinations.
+    pub fn normalize(&mut self) {
+        for rule in &mut self.auto_accept {
+            if let Some(destination) = &mut rule.destination {
+                *destination = PathsConfig::expand_path(destination);
+            }
+        }
+    }
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            codec: CodecChoice::default(),
+            on_collision: CollisionPolicy::default(),
+            streams: 4,
+            auto_accept: Vec::new(),
+        }
+    }
+}
+
+/// Offers from one saved peer that are accepted without asking.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoAcceptRule {
+    /// Hex identity key of the peer, or the name of a saved peer whose key was verified
+    /// with the safety number.
+    pub peer: String,
+    /// Largest transfer accepted; 0 leaves only `limits.max_file_bytes`.
+    pub max_bytes: u64,
+    /// Extensions every file must end with, e.g. `zip` or `tar.gz`; empty allows any file.
+    pub extensions: Vec<String>,
+    /// Directory accepted files are saved in instead of the download directory.
+    pub destination: Option<PathBuf>,
+}
+
+impl AutoAcceptRule {
+    /// Whether a transfer of `size` bytes carrying `paths` stays within the rule.
+    pub fn allows<'a>(&self, size: u64, mut paths: impl Iterator<Item = &'a str>) -> bool {
+        if self.max_bytes != 0 && size > self.max_bytes {
+            return false;
+        }
+        if self.extensions.is_empty() {
+            return true;
+        }
+        paths.all(|path| {
+            let path = path.to_lowercase();
+            self.extensions.iter().any(|extension| {
+                let extension = extension.trim_start_matches('.').to_lowercase();
+                path.strip_suffix(extension.as_str())
+                    .is_some_and(|stem| stem.ends_with('.') && !stem.ends_with("/."))
+            })
+        })
+    }
+}
+
+/// What to do when a received file would land on an existing path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Keep both by saving as `name (1).ext`, `name (2).ext`, ...
+    #[default]
+    Rename,
+    Overwrite,
+    /// Ask before accepting; falls back to renaming if the path appears later.
+    Prompt,
+}
+
+/// Codec selection for outgoing files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecChoice {
+    /// Sample each file and skip compression when it does not pay off.
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl CodecChoice {
+    /// Codec to use regardless of the file contents, if one was forced.
+    pub fn forced(self) -> Option<Codec> {
+        match self {
+            CodecChoice::Auto => None,
+            CodecChoice::None => Some(Codec::None),
+            CodecChoice::Gzip => Some(Codec::Gzip),
+            CodecChoice::Zstd => Some(Codec::Zstd),
+            CodecChoice::Lz4 => Some(Codec::Lz4),
         }
     }
 }
@@ -239,6 +415,10 @@ impl Default for TlsConfig {
 pub struct UiConfig {
     pub show_timestamps: bool,
     pub accent: String,
+    /// Tell peers when their messages were shown in the chat.
+    pub read_receipts: bool,
+    /// Tell peers while a message to them is being typed.
+    pub typing_indicators: bool,
 }
 
 impl Default for UiConfig {
@@ -246,6 +426,8 @@ impl Default for UiConfig {
         Self {
             show_timestamps: true,
             accent: "crimson".to_string(),
+            read_receipts: true,
+            typing_indicators: true,
         }
     }
 }
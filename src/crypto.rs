@@ -0,0 +1,499 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use parking_lot::Mutex;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::protocol::{self, CipherFrame, FileFrame, HelloMessage, WireMessage};
+use crate::ratchet::Ratchet;
+
+/// File (next to `history.key`) holding the long-term identity seed.
+pub const IDENTITY_KEY_FILE: &str = "identity.key";
+
+const HELLO_CONTEXT: &[u8] = b"dezap-hello-v1";
+const SESSION_CONTEXT: &[u8] = b"dezap-session-v1";
+const SAFETY_CONTEXT: &[u8] = b"dezap-safety-v1";
+const FILE_SECRET_INFO: &[u8] = b"dezap-files-v1";
+const FILE_KEY_INFO: &[u8] = b"dezap-file-key";
+
+/// Long-term Ed25519 identity used to sign handshake transcripts.
+pub struct Identity {
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// Loads the identity seed from `dir`, generating and persisting one when missing.
+    ///
+    /// A file that exists but is malformed is an error rather than a reason to replace it:
+    /// a new key would break every pin peers hold for this identity.
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let key_path = dir.join(IDENTITY_KEY_FILE);
+        if key_path.exists() {
+            let data = fs::read(&key_path)
+                .with_context(|| format!("failed to read identity key {}", key_path.display()))?;
+            if data.len() < 32 {
+                bail!(
+                    "identity key {} is malformed ({} bytes, expected 32)",
+                    key_path.display(),
+                    data.len()
+                );
+            }
+            let mut seed = [0u8; 32];
+            seed.copy_from_slice(&data[..32]);
+            return Ok(Self {
+                signing: SigningKey::from_bytes(&seed),
+            });
+        }
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        fs::write(&key_path, seed).context("failed to write identity key")?;
+        restrict_permissions(&key_path);
+        Ok(Self {
+            signing: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Public half of the identity keypair.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    /// Signs the transcript of an outgoing hello.
+    pub fn sign_hello(&self, username: &str, ephemeral_key: &[u8; 32]) -> Vec<u8> {
+        let transcript = hello_transcript(username, &self.public_key(), ephemeral_key);
+        self.signing.sign(&transcript).to_bytes().to_vec()
+    }
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o600));
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) {}
+
+/// Bytes covered by the hello signature: context, username, identity key and ephemeral key.
+fn hello_transcript(username: &str, identity_key: &[u8; 32], ephemeral_key: &[u8; 32]) -> Vec<u8> {
+    let mut transcript = Vec::with_capacity(HELLO_CONTEXT.len() + username.len() + 72);
+    transcript.extend_from_slice(HELLO_CONTEXT);
+    transcript.extend_from_slice(&(username.len() as u32).to_be_bytes());
+    transcript.extend_from_slice(username.as_bytes());
+    transcript.extend_from_slice(identity_key);
+    transcript.extend_from_slice(ephemeral_key);
+    transcript
+}
+
+/// Checks that `hello` was signed by the identity key it carries.
+pub fn verify_hello(hello: &HelloMessage) -> Result<()> {
+    let verifying = VerifyingKey::from_bytes(&hello.identity_key)
+        .map_err(|_| anyhow!("invalid identity key"))?;
+    let signature = Signature::from_slice(&hello.signature)
+        .map_err(|_| anyhow!("malformed handshake signature"))?;
+    let transcript = hello_transcript(&hello.username, &hello.identity_key, &hello.public_key);
+    verifying
+        .verify_strict(&transcript, &signature)
+        .map_err(|_| anyhow!("handshake signature does not match identity key"))
+}
+
+/// Short, human-comparable rendering of an identity key.
+pub fn short_fingerprint(key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key);
+    digest[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Numeric safety number for a pair of identity keys, identical on both sides.
+///
+/// Rendered as six groups of five digits so it can be read aloud or compared on screen.
+pub fn safety_number(local: &[u8; 32], remote: &[u8; 32]) -> String {
+    let (first, second) = if local <= remote {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+    let mut hasher = Sha512::new();
+    hasher.update(SAFETY_CONTEXT);
+    hasher.update(first);
+    hasher.update(second);
+    let digest = hasher.finalize();
+    digest[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Hex encoding used to persist identity keys in `peers.json` and file digests.
+pub fn key_to_hex(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Parses a key produced by [`key_to_hex`].
+pub fn key_from_hex(raw: &str) -> Option<[u8; 32]> {
+    if raw.len() != 64 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (idx, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(raw.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Per-connection key agreement state.
+pub(crate) struct CryptoCtx {
+    inner: Mutex<CryptoState>,
+}
+
+struct CryptoState {
+    secret: StaticSecret,
+    public: PublicKey,
+    ratchet: Option<Ratchet>,
+    file_secret: Option<[u8; 32]>,
+    remote_identity: Option<[u8; 32]>,
+}
+
+impl CryptoCtx {
+    pub(crate) fn new() -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        Self {
+            inner: Mutex::new(CryptoState {
+                secret,
+                public,
+                ratchet: None,
+                file_secret: None,
+                remote_identity: None,
+            }),
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> [u8; 32] {
+        self.inner.lock().public.to_bytes()
+    }
+
+    /// Derives the session key from a verified remote hello and starts the message ratchet.
+    /// Returns `false` when already derived.
+    pub(crate) fn accept_remote(
+        &self,
+        local_identity: &[u8; 32],
+        hello: &HelloMessage,
+    ) -> Result<bool> {
+        let mut inner = self.inner.lock();
+        if inner.ratchet.is_some() {
+            return Ok(false);
+        }
+        if hello.identity_key == *local_identity && hello.public_key == inner.public.to_bytes() {
+            bail!("peer echoed our own handshake");
+        }
+        let remote = PublicKey::from(hello.public_key);
+        let dh = inner.secret.diffie_hellman(&remote);
+        let local_public = inner.public.to_bytes();
+        let session = session_key(
+            dh.as_bytes(),
+            (local_identity, &local_public),
+            (&hello.identity_key, &hello.public_key),
+        )?;
+        inner.ratchet = Some(Ratchet::new(&session, &inner.secret, &hello.public_key)?);
+        inner.file_secret = Some(expand_key(&session, FILE_SECRET_INFO)?);
+        inner.remote_identity = Some(hello.identity_key);
+        Ok(true)
+    }
+
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<CipherFrame> {
+        self.inner
+            .lock()
+            .ratchet
+            .as_mut()
+            .ok_or_else(|| anyhow!("secure channel not established yet"))?
+            .seal(plaintext)
+    }
+
+    pub(crate) fn open(&self, frame: &CipherFrame) -> Result<Vec<u8>> {
+        self.inner
+            .lock()
+            .ratchet
+            .as_mut()
+            .ok_or_else(|| anyhow!("secure channel not established yet"))?
+            .open(frame)
+    }
+
+    /// Cipher for one stream of file `id`. Every stream gets its own key, so restarting the
+    /// offsets of a segment on a new stream never repeats a nonce under the same key.
+    pub(crate) fn file_cipher(&self, id: u64, segment: u32, attempt: u32) -> Result<FileCipher> {
+        let secret = self
+            .inner
+            .lock()
+            .file_secret
+            .ok_or_else(|| anyhow!("secure channel not established yet"))?;
+        let mut info = FILE_KEY_INFO.to_vec();
+        info.extend_from_slice(&id.to_be_bytes());
+        info.extend_from_slice(&segment.to_be_bytes());
+        info.extend_from_slice(&attempt.to_be_bytes());
+        let key = expand_key(&secret, &info)?;
+        Ok(FileCipher {
+            cipher: ChaCha20Poly1305::new(GenericArray::from_slice(&key)),
+            id,
+            segment,
+            attempt,
+        })
+    }
+
+    /// Identity key the remote side authenticated with, once the handshake completed.
+    pub(crate) fn remote_identity(&self) -> Option<[u8; 32]> {
+        self.inner.lock().remote_identity
+    }
+}
+
+/// Seals and opens the frames of a single file stream.
+pub(crate) struct FileCipher {
+    cipher: ChaCha20Poly1305,
+    id: u64,
+    segment: u32,
+    attempt: u32,
+}
+
+impl FileCipher {
+    pub(crate) fn seal(&self, offset: u64, message: &WireMessage) -> Result<WireMessage> {
+        let plaintext = protocol::encode_message(message)?;
+        let nonce = self.nonce(offset);
+        let body = self
+            .cipher
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &self.aad(offset),
+                },
+            )
+            .map_err(|_| anyhow!("failed to encrypt file frame"))?;
+        Ok(WireMessage::FileFrame(FileFrame {
+            id: self.id,
+            segment: self.segment,
+            attempt: self.attempt,
+            offset,
+            nonce,
+            body,
+        }))
+    }
+
+    /// Opens a frame, rejecting frames for another file, another stream or another offset.
+    pub(crate) fn open(&self, frame: &FileFrame, expected_offset: u64) -> Result<WireMessage> {
+        if frame.segment != self.segment || frame.attempt != self.attempt {
+            bail!(
+                "file frame belongs to another stream (segment {}, attempt {})",
+                frame.segment,
+                frame.attempt
+            );
+        }
+        if frame.id != self.id || frame.offset != expected_offset {
+            bail!(
+                "unexpected file frame (id {}, offset {}; expected offset {expected_offset})",
+                frame.id,
+                frame.offset
+            );
+        }
+        if frame.nonce != self.nonce(frame.offset) {
+            bail!("file frame nonce does not match its offset");
+        }
+        let plaintext = self
+            .cipher
+            .decrypt(
+                GenericArray::from_slice(&frame.nonce),
+                Payload {
+                    msg: &frame.body,
+                    aad: &self.aad(frame.offset),
+                },
+            )
+            .map_err(|_| anyhow!("file frame failed authentication"))?;
+        protocol::decode_message(&plaintext)
+    }
+
+    fn nonce(&self, offset: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&offset.to_be_bytes());
+        nonce
+    }
+
+    fn aad(&self, offset: u64) -> [u8; 16] {
+        let mut aad = [0u8; 16];
+        aad[..8].copy_from_slice(&self.id.to_be_bytes());
+        aad[8..].copy_from_slice(&offset.to_be_bytes());
+        aad
+    }
+}
+
+fn expand_key(secret: &[u8; 32], info: &[u8]) -> Result<[u8; 32]> {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(info, &mut okm)
+        .map_err(|_| anyhow!("failed to expand key"))?;
+    Ok(okm)
+}
+
+/// Binds the DH output to both identities and both ephemeral keys, so a substituted key on
+/// either side yields a different session key.
+fn session_key(
+    dh: &[u8; 32],
+    local: (&[u8; 32], &[u8; 32]),
+    remote: (&[u8; 32], &[u8; 32]),
+) -> Result<[u8; 32]> {
+    let (first, second) = if local.0 <= remote.0 {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+    let mut info = Vec::with_capacity(SESSION_CONTEXT.len() + 128);
+    info.extend_from_slice(SESSION_CONTEXT);
+    for key in [first.0, first.1, second.0, second.1] {
+        info.extend_from_slice(key);
+    }
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(None, dh)
+        .expand(&info, &mut okm)
+        .map_err(|_| anyhow!("failed to expand session key"))?;
+    Ok(okm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello_for(identity: &Identity, ctx: &CryptoCtx, username: &str) -> HelloMessage {
+        let public_key = ctx.public_key();
+        HelloMessage {
+            username: username.into(),
+            password: None,
+            public_key,
+            identity_key: identity.public_key(),
+            signature: identity.sign_hello(username, &public_key),
+            codecs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn handshake_derives_matching_keys_and_rejects_tampering() {
+        let (alice_dir, bob_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let alice = Identity::load_or_create(alice_dir.path()).unwrap();
+        let bob = Identity::load_or_create(bob_dir.path()).unwrap();
+        let (alice_ctx, bob_ctx) = (CryptoCtx::new(), CryptoCtx::new());
+        let alice_hello = hello_for(&alice, &alice_ctx, "alice");
+        let bob_hello = hello_for(&bob, &bob_ctx, "bob");
+
+        verify_hello(&alice_hello).unwrap();
+        verify_hello(&bob_hello).unwrap();
+        alice_ctx
+            .accept_remote(&alice.public_key(), &bob_hello)
+            .unwrap();
+        bob_ctx
+            .accept_remote(&bob.public_key(), &alice_hello)
+            .unwrap();
+        let frame = alice_ctx.seal(b"hi bob").unwrap();
+        assert_eq!(bob_ctx.open(&frame).unwrap(), b"hi bob");
+        let sender = alice_ctx.file_cipher(9, 2, 0).unwrap();
+        let chunk = WireMessage::FileChunk(protocol::FileChunk {
+            id: 9,
+            offset: 0,
+            bytes: vec![1, 2, 3],
+            last: true,
+        });
+        let WireMessage::FileFrame(sealed) = sender.seal(0, &chunk).unwrap() else {
+            unreachable!()
+        };
+        let receiver = bob_ctx
+            .file_cipher(9, sealed.segment, sealed.attempt)
+            .unwrap();
+        assert_eq!(receiver.open(&sealed, 0).unwrap(), chunk);
+        assert!(receiver.open(&sealed, 3).is_err(), "reordered frame");
+        let mut moved = sealed.clone();
+        moved.offset = 3;
+        moved.nonce = receiver.nonce(3);
+        assert!(receiver.open(&moved, 3).is_err(), "offset is authenticated");
+        let mut relabelled = sealed.clone();
+        relabelled.attempt = 1;
+        assert!(receiver.open(&relabelled, 0).is_err(), "stream is bound");
+        let mut flipped = sealed;
+        flipped.body[0] ^= 1;
+        assert!(receiver.open(&flipped, 0).is_err(), "tampered body");
+
+        let mut forged = bob_hello.clone();
+        forged.public_key = CryptoCtx::new().public_key();
+        assert!(verify_hello(&forged).is_err());
+        let mut renamed = bob_hello;
+        renamed.username = "mallory".into();
+        assert!(verify_hello(&renamed).is_err());
+    }
+
+    #[test]
+    fn file_streams_never_share_a_key_and_nonce() {
+        let (alice_dir, bob_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let alice = Identity::load_or_create(alice_dir.path()).unwrap();
+        let bob = Identity::load_or_create(bob_dir.path()).unwrap();
+        let (alice_ctx, bob_ctx) = (CryptoCtx::new(), CryptoCtx::new());
+        alice_ctx
+            .accept_remote(&alice.public_key(), &hello_for(&bob, &bob_ctx, "bob"))
+            .unwrap();
+        let meta = WireMessage::FileMeta(protocol::FileMetadata {
+            id: 9,
+            name: "same.bin".into(),
+            original_size: 10,
+            codec: crate::compression::Codec::None,
+            segment: 0,
+        });
+        // Two segments, then the first one resumed on a new stream: the frames all use the
+        // meta offset, so identical bodies would mean a shared key and nonce.
+        let mut bodies = Vec::new();
+        for (segment, attempt) in [(0, 0), (1, 1), (0, 2)] {
+            let cipher = alice_ctx.file_cipher(9, segment, attempt).unwrap();
+            let WireMessage::FileFrame(frame) =
+                cipher.seal(protocol::FILE_META_OFFSET, &meta).unwrap()
+            else {
+                unreachable!()
+            };
+            assert!(!bodies.contains(&frame.body));
+            bodies.push(frame.body);
+        }
+    }
+    #[test]
+    fn identity_persists_and_hex_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Identity::load_or_create(dir.path()).unwrap().public_key();
+        let second = Identity::load_or_create(dir.path()).unwrap().public_key();
+        assert_eq!(first, second);
+        assert_eq!(key_from_hex(&key_to_hex(&first)), Some(first));
+        assert_eq!(key_from_hex("zz"), None);
+
+        let key_path = dir.path().join(IDENTITY_KEY_FILE);
+        fs::write(&key_path, [7u8; 5]).unwrap();
+        let err = Identity::load_or_create(dir.path()).err().unwrap();
+        assert!(err.to_string().contains(&key_path.display().to_string()));
+        assert_eq!(fs::read(&key_path).unwrap(), [7u8; 5]);
+    }
+
+    #[test]
+    fn safety_number_is_symmetric() {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let number = safety_number(&a, &b);
+        assert_eq!(number, safety_number(&b, &a));
+        assert_eq!(number.len(), 6 * 5 + 5);
+        assert!(number.chars().all(|ch| ch.is_ascii_digit() || ch == ' '));
+        assert_ne!(number, safety_number(&a, &[3u8; 32]));
+    }
+}
@@ -1,10 +1,16 @@
 #![deny(rust_2018_idioms)]
 
+pub mod bandwidth;
 pub mod cli;
+pub mod compression;
 pub mod config;
+pub mod crypto;
 pub mod logging;
+pub mod manifest;
 pub mod net;
 pub mod protocol;
+pub mod ratchet;
+pub mod reorder;
 pub mod service;
 
 #[cfg(feature = "tui")]
@@ -16,7 +16,7 @@ pub fn init(config: &LoggingConfig, verbosity: u8) -> Result<()> {
 
 /// Initializes logging without writing to the terminal (useful for the TUI).
 pub fn init_quiet(config: &LoggingConfig, verbosity: u8) -> Result<()> {
-    init_with_writer(config, verbosity, || io::sink())
+    init_with_writer(config, verbosity, io::sink)
 }
 
 fn init_with_writer<W>(config: &LoggingConfig, verbosity: u8, writer: W) -> Result<()>
@@ -0,0 +1,694 @@
+//! Multi-file transfers.
+//!
+//! A transfer is a manifest of files sent back to back as one stream of bytes. Offsets,
+//! segments and their digests all work on that concatenation; the receiver stages every entry
+//! in its own file and only moves the tree into place once every digest matches.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::config::CollisionPolicy;
+use crate::protocol::ManifestEntry;
+
+/// Upper bound on files in one transfer.
+pub const MAX_ENTRIES: usize = 10_000;
+/// Longest file name, in bytes, most filesystems accept.
+const MAX_NAME_BYTES: usize = 255;
+/// Device names Windows reserves regardless of extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether the manifest describes a single file rather than a tree.
+pub fn is_single_file(entries: &[ManifestEntry]) -> bool {
+    entries.len() == 1 && !entries[0].path.contains('/')
+}
+
+/// Turns a manifest path into a relative path, rejecting anything that could leave the
+/// destination directory.
+pub fn relative_path(raw: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in raw.split('/') {
+        let unsafe_component = component.is_empty()
+            || component == "."
+            || component == ".."
+            || component.contains(['\\', '\0'])
+            || (cfg!(windows) && component.contains(':'));
+        if unsafe_component {
+            bail!("unsafe path '{raw}' in transfer manifest");
+        }
+        path.push(component);
+    }
+    Ok(path)
+}
+
+/// Turns a sender-supplied name into one file name that is safe to create on any platform:
+/// separators and characters Windows forbids become `_`, control characters are dropped,
+/// trailing dots and spaces are trimmed and reserved device names get a `_` prefix.
+pub fn sanitize_name(raw: &str) -> String {
+    let mut name: String = raw
+        .chars()
+        .filter(|ch| !ch.is_control())
+        .map(|ch| match ch {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            ch => ch,
+        })
+        .collect();
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_NAME_BYTES {
+        let mut end = MAX_NAME_BYTES;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    if name.is_empty() {
+        name.push('_');
+    }
+    name
+}
+
+/// Checks an incoming manifest before it is offered to the user and returns it with every
+/// path component passed through [`sanitize_name`]. Paths that try to leave the destination
+/// are rejected outright rather than rewritten.
+pub fn validate(entries: &[ManifestEntry], total: u64) -> Result<Vec<ManifestEntry>> {
+    if entries.is_empty() {
+        bail!("transfer manifest is empty");
+    }
+    if entries.len() > MAX_ENTRIES {
+        bail!("transfer manifest lists more than {MAX_ENTRIES} files");
+    }
+    let mut sum = 0u64;
+    let mut sanitized = Vec::with_capacity(entries.len());
+    for entry in entries {
+        relative_path(&entry.path)?;
+        sum = sum
+            .checked_add(entry.size)
+            .ok_or_else(|| anyhow!("transfer manifest sizes overflow"))?;
+        let path: Vec<_> = entry.path.split('/').map(sanitize_name).collect();
+        sanitized.push(ManifestEntry {
+            path: path.join("/"),
+            size: entry.size,
+        });
+    }
+    // Sanitizing can fold distinct names together, so look for clashes afterwards.
+    let mut paths = HashSet::new();
+    for entry in &sanitized {
+        if !paths.insert(entry.path.as_str()) {
+            bail!("'{}' appears twice in the transfer manifest", entry.path);
+        }
+    }
+    for entry in &sanitized {
+        for (idx, _) in entry.path.match_indices('/') {
+            if paths.contains(&entry.path[..idx]) {
+                bail!("'{}' is both a file and a directory", &entry.path[..idx]);
+            }
+        }
+    }
+    if sum != total {
+        bail!("transfer manifest does not add up to the announced size");
+    }
+    Ok(sanitized)
+}
+
+/// A local file and the manifest entry it is sent as.
+pub(crate) struct Source {
+    pub(crate) path: PathBuf,
+    pub(crate) entry: ManifestEntry,
+}
+
+/// Walks `paths` in manifest order. Files keep their name, directories are walked in name
+/// order with their own name as the first path component. Symlinks and special files found
+/// inside directories are skipped.
+pub(crate) fn collect(paths: &[PathBuf]) -> Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    for path in paths {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("unable to read {}", path.display()))?;
+        let name = fs::canonicalize(path)
+            .ok()
+            .and_then(|path| path.file_name().map(|name| name.to_owned()))
+            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+            .into_string()
+            .map_err(|_| anyhow!("{} is not valid UTF-8", path.display()))?;
+        if metadata.is_file() {
+            sources.push(Source {
+                path: path.clone(),
+                entry: ManifestEntry {
+                    path: name,
+                    size: metadata.len(),
+                },
+            });
+        } else if metadata.is_dir() {
+            walk(path, &name, &mut sources)?;
+        } else {
+            bail!("{} is not a file or directory", path.display());
+        }
+    }
+    if sources.is_empty() {
+        bail!("nothing to send: no files found");
+    }
+    if sources.len() > MAX_ENTRIES {
+        bail!(
+            "too many files ({}); the limit is {MAX_ENTRIES}",
+            sources.len()
+        );
+    }
+    let mut seen = HashSet::new();
+    for source in &sources {
+        if !seen.insert(source.entry.path.as_str()) {
+            bail!("'{}' appears twice in the transfer", source.entry.path);
+        }
+    }
+    Ok(sources)
+}
+
+fn walk(dir: &Path, prefix: &str, sources: &mut Vec<Source>) -> Result<()> {
+    let mut children = fs::read_dir(dir)
+        .with_context(|| format!("unable to list {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("unable to list {}", dir.display()))?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let file_type = child.file_type()?;
+        let name = child
+            .file_name()
+            .into_string()
+            .map_err(|_| anyhow!("{} is not valid UTF-8", child.path().display()))?;
+        let relative = format!("{prefix}/{name}");
+        if file_type.is_dir() {
+            walk(&child.path(), &relative, sources)?;
+        } else if file_type.is_file() {
+            sources.push(Source {
+                path: child.path(),
+                entry: ManifestEntry {
+                    path: relative,
+                    size: child.metadata()?.len(),
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Feeds bytes `start..end` of the concatenated `files` into `hasher`, failing if a file is
+/// shorter than its manifest size.
+pub(crate) fn hash_range(
+    files: &[(PathBuf, u64)],
+    start: u64,
+    end: u64,
+    hasher: &mut Sha256,
+) -> Result<()> {
+    let mut position = 0u64;
+    for (path, size) in files {
+        if position >= end {
+            break;
+        }
+        let file_end = position + size;
+        let (from, to) = (start.max(position), end.min(file_end));
+        position = file_end;
+        if from >= to {
+            continue;
+        }
+        let take = to - from;
+        let mut file =
+            fs::File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+        file.seek(SeekFrom::Start(from - (file_end - size)))
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        let mut reader = file.take(take);
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut hashed = 0u64;
+        loop {
+            let read = reader
+                .read(&mut buffer)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            hashed += read as u64;
+        }
+        if hashed != take {
+            bail!("{} is shorter than expected", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of the concatenation already present in staged `files`: whole files up to the first
+/// incomplete one, plus what that one holds.
+pub(crate) fn staged_len(files: &[(PathBuf, u64)]) -> u64 {
+    let mut total = 0;
+    for (path, size) in files {
+        let len = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        total += len.min(*size);
+        if len < *size {
+            break;
+        }
+    }
+    total
+}
+
+/// Existing paths a transfer would land on: `target` itself for a single file, or the
+/// top-level names of a tree inside `target`.
+pub fn collisions(entries: &[ManifestEntry], target: &Path) -> Vec<PathBuf> {
+    if is_single_file(entries) {
+        return exists(target)
+            .then(|| target.to_path_buf())
+            .into_iter()
+            .collect();
+    }
+    let mut tops: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| entry.path.split('/').next())
+        .collect();
+    tops.dedup();
+    tops.into_iter()
+        .map(|top| target.join(top))
+        .filter(|path| exists(path))
+        .collect()
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// First of `path`, `stem (1).ext`, `stem (2).ext`, ... that does not exist yet.
+fn unique_path(path: &Path) -> Result<PathBuf> {
+    if !exists(path) {
+        return Ok(path.to_path_buf());
+    }
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 1..=MAX_ENTRIES {
+        let candidate = path.with_file_name(format!("{stem} ({n}){extension}"));
+        if !exists(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    bail!("no free name left for {}", path.display())
+}
+
+/// Moves verified staged files to their destination: `target` itself for a single file, or
+/// the manifest paths below `target` for a tree. With [`CollisionPolicy::Overwrite`] existing
+/// files are replaced; otherwise a clashing file, or the top-level directory of a clashing
+/// tree, is saved under a new name. Returns where a single file ended up, or `target`.
+pub(crate) fn place(
+    staged: &[PathBuf],
+    entries: &[ManifestEntry],
+    target: &Path,
+    policy: CollisionPolicy,
+) -> Result<PathBuf> {
+    let overwrite = policy == CollisionPolicy::Overwrite;
+    if is_single_file(entries) {
+        let destination = if overwrite {
+            target.to_path_buf()
+        } else {
+            unique_path(target)?
+        };
+        move_file(&staged[0], &destination)?;
+        return Ok(destination);
+    }
+    fs::create_dir_all(target).with_context(|| format!("failed to create {}", target.display()))?;
+    let root = fs::canonicalize(target)?;
+    let mut renamed: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for (staged, entry) in staged.iter().zip(entries) {
+        let mut relative = relative_path(&entry.path)?;
+        if !overwrite {
+            let mut components = relative.components();
+            let top = components.next().expect("paths are never empty");
+            let top = PathBuf::from(top.as_os_str());
+            let top = match renamed.get(&top) {
+                Some(existing) => existing.clone(),
+                None => {
+                    let fresh = unique_path(&root.join(&top))?;
+                    let fresh = fresh.strip_prefix(&root)?.to_path_buf();
+                    renamed.insert(top, fresh.clone());
+                    fresh
+                }
+            };
+            let rest = components.as_path();
+            relative = if rest.as_os_str().is_empty() {
+                top
+            } else {
+                top.join(rest)
+            };
+        }
+        let destination = root.join(relative);
+        let parent = destination
+            .parent()
+            .ok_or_else(|| anyhow!("'{}' has no parent directory", entry.path))?;
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+        if !fs::canonicalize(parent)?.starts_with(&root) {
+            bail!("'{}' escapes the destination directory", entry.path);
+        }
+        move_file(staged, &destination)?;
+    }
+    Ok(target.to_path_buf())
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to).with_context(|| format!("failed to create {}", to.display()))?;
+    }
+    Ok(())
+}
+
+/// Finds the entry holding `offset` and the position inside it.
+fn locate(files: &[(PathBuf, u64)], offset: u64) -> (usize, u64) {
+    let mut index = 0;
+    let mut skip = offset;
+    while index < files.len() && skip >= files[index].1 {
+        skip -= files[index].1;
+        index += 1;
+    }
+    (index, skip)
+}
+
+/// Reads the concatenated source files from an offset.
+pub(crate) struct EntryReader {
+    files: Vec<(PathBuf, u64)>,
+    index: usize,
+    file: Option<tokio::fs::File>,
+    left: u64,
+}
+
+impl EntryReader {
+    pub(crate) async fn open(files: Vec<(PathBuf, u64)>, offset: u64) -> Result<Self> {
+        let (index, skip) = locate(&files, offset);
+        let mut reader = Self {
+            files,
+            index,
+            file: None,
+            left: 0,
+        };
+        if let Some((path, size)) = reader.files.get(index) {
+            let mut file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("unable to open {}", path.display()))?;
+            file.seek(std::io::SeekFrom::Start(skip)).await?;
+            reader.left = size - skip;
+            reader.file = Some(file);
+        }
+        Ok(reader)
+    }
+
+    /// Fills `buf`, moving on to the following entries as each one is exhausted. Returns
+    /// less than `buf.len()` only at the end of the last entry.
+    pub(crate) async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if let Some(file) = self.file.as_mut() {
+                if self.left > 0 {
+                    let want =
+                        (buf.len() - filled).min(usize::try_from(self.left).unwrap_or(usize::MAX));
+                    let read = file.read(&mut buf[filled..filled + want]).await?;
+                    if read == 0 {
+                        bail!(
+                            "{} shrank while it was being sent",
+                            self.files[self.index].0.display()
+                        );
+                    }
+                    self.left -= read as u64;
+                    filled += read;
+                    continue;
+                }
+                self.file = None;
+                self.index += 1;
+            }
+            let Some((path, size)) = self.files.get(self.index) else {
+                break;
+            };
+            self.file = Some(
+                tokio::fs::File::open(path)
+                    .await
+                    .with_context(|| format!("unable to open {}", path.display()))?,
+            );
+            self.left = *size;
+        }
+        Ok(filled)
+    }
+}
+
+/// Writes the concatenated stream into the staged files of an incoming transfer. Writers
+/// may start at any offset, so several segments can be written side by side.
+pub(crate) struct EntryWriter {
+    files: Vec<(PathBuf, u64)>,
+    index: usize,
+    file: Option<tokio::fs::File>,
+    left: u64,
+    /// Position inside the first file to write to.
+    skip: u64,
+}
+
+impl EntryWriter {
+    pub(crate) fn new(files: Vec<(PathBuf, u64)>, offset: u64) -> Self {
+        let (index, skip) = locate(&files, offset);
+        let left = files.get(index).map(|(_, size)| size - skip).unwrap_or(0);
+        Self {
+            files,
+            index,
+            file: None,
+            left,
+            skip,
+        }
+    }
+
+    pub(crate) async fn write(&mut self, mut bytes: &[u8]) -> Result<()> {
+        while !bytes.is_empty() {
+            if self.left == 0 {
+                if let Some(mut file) = self.file.take() {
+                    file.flush().await?;
+                }
+                self.index += 1;
+                let Some((_, size)) = self.files.get(self.index) else {
+                    bail!("file stream carries more data than its manifest");
+                };
+                self.left = *size;
+                continue;
+            }
+            if self.file.is_none() {
+                let path = &self.files[self.index].0;
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("unable to open {}", path.display()))?;
+                file.seek(SeekFrom::Start(std::mem::take(&mut self.skip)))
+                    .await?;
+                self.file = Some(file);
+            }
+            let file = self.file.as_mut().expect("file opened above");
+            let take = bytes
+                .len()
+                .min(usize::try_from(self.left).unwrap_or(usize::MAX));
+            file.write_all(&bytes[..take]).await?;
+            self.left -= take as u64;
+            bytes = &bytes[take..];
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64) -> ManifestEntry {
+        ManifestEntry {
+            path: path.into(),
+            size,
+        }
+    }
+
+    #[test]
+    fn unsafe_manifests_are_rejected() {
+        assert!(validate(&[entry("a.txt", 1), entry("dir/b.txt", 2)], 3).is_ok());
+        for bad in ["../etc/passwd", "/abs", "a//b", "a/./b", "a\\..\\b", ""] {
+            assert!(validate(&[entry(bad, 1)], 1).is_err(), "{bad:?}");
+        }
+        assert!(validate(&[entry("a", 1), entry("a", 1)], 2).is_err());
+        assert!(validate(&[entry("a", 1), entry("a/b", 1)], 2).is_err());
+        assert!(validate(&[entry("a", 1)], 2).is_err());
+        // Names that only clash once sanitized.
+        assert!(validate(&[entry("a:b", 1), entry("a_b", 1)], 2).is_err());
+    }
+
+    #[test]
+    fn hostile_names_are_sanitized() {
+        for (raw, clean) in [
+            ("report.pdf", "report.pdf"),
+            ("../../.bashrc", ".._.._.bashrc"),
+            ("/etc/passwd", "_etc_passwd"),
+            ("C:\\Windows\\evil.exe", "C__Windows_evil.exe"),
+            ("bell\u{7}\nname.txt", "bellname.txt"),
+            ("trailing. . ", "trailing"),
+            ("..", "_"),
+            ("", "_"),
+            ("CON", "_CON"),
+            ("nul.txt", "_nul.txt"),
+            ("lpt1 .log", "_lpt1 .log"),
+            ("console.log", "console.log"),
+        ] {
+            assert_eq!(sanitize_name(raw), clean, "{raw:?}");
+        }
+        let long = "é".repeat(200);
+        let clean = sanitize_name(&long);
+        assert!(clean.len() <= MAX_NAME_BYTES && clean.chars().all(|ch| ch == 'é'));
+
+        let entries = validate(&[entry("dir/CON", 1), entry("dir/a\tb", 2)], 3).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["dir/_CON", "dir/ab"]);
+    }
+
+    #[test]
+    fn collisions_follow_the_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("notes.txt");
+        fs::write(&target, b"old").unwrap();
+        let single = [entry("notes.txt", 3)];
+        assert_eq!(collisions(&single, &target), std::slice::from_ref(&target));
+
+        let staged = dir.path().join("staged");
+        fs::write(&staged, b"new").unwrap();
+        let staged = [staged];
+        let placed = place(&staged, &single, &target, CollisionPolicy::Rename).unwrap();
+        assert_eq!(placed, dir.path().join("notes (1).txt"));
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(fs::read(&placed).unwrap(), b"new");
+
+        fs::write(&staged[0], b"newer").unwrap();
+        let placed = place(&staged, &single, &target, CollisionPolicy::Overwrite).unwrap();
+        assert_eq!(placed, target);
+        assert_eq!(fs::read(&target).unwrap(), b"newer");
+
+        let downloads = dir.path().join("downloads");
+        fs::create_dir_all(downloads.join("project")).unwrap();
+        fs::write(downloads.join("project/a"), b"old").unwrap();
+        fs::write(downloads.join("top.txt"), b"old").unwrap();
+        let tree = [
+            entry("project/a", 1),
+            entry("project/b", 1),
+            entry("top.txt", 1),
+        ];
+        assert_eq!(
+            collisions(&tree, &downloads),
+            [downloads.join("project"), downloads.join("top.txt")]
+        );
+        let staged: Vec<_> = ["1", "2", "3"]
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                fs::write(&path, name).unwrap();
+                path
+            })
+            .collect();
+        place(&staged, &tree, &downloads, CollisionPolicy::Rename).unwrap();
+        assert_eq!(fs::read(downloads.join("project/a")).unwrap(), b"old");
+        assert_eq!(fs::read(downloads.join("project (1)/a")).unwrap(), b"1");
+        assert_eq!(fs::read(downloads.join("project (1)/b")).unwrap(), b"2");
+        assert_eq!(fs::read(downloads.join("top (1).txt")).unwrap(), b"3");
+    }
+
+    #[tokio::test]
+    async fn trees_round_trip_through_staging() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("src/empty")).unwrap();
+        fs::write(root.join("README"), b"readme").unwrap();
+        fs::write(root.join("src/main.rs"), b"fn main() {}").unwrap();
+        fs::write(root.join("src/zero"), b"").unwrap();
+
+        let sources = collect(std::slice::from_ref(&root)).unwrap();
+        let paths: Vec<_> = sources.iter().map(|s| s.entry.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["project/README", "project/src/main.rs", "project/src/zero"]
+        );
+        let entries: Vec<_> = sources.iter().map(|s| s.entry.clone()).collect();
+        let files: Vec<_> = sources
+            .iter()
+            .map(|s| (s.path.clone(), s.entry.size))
+            .collect();
+
+        let staging = dir.path().join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        let staged: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| (staging.join(idx.to_string()), entry.size))
+            .collect();
+        for (path, _) in &staged {
+            fs::write(path, b"").unwrap();
+        }
+        // Interrupt after 3 bytes, then resume from what was staged.
+        let mut reader = EntryReader::open(files.clone(), 0).await.unwrap();
+        let mut buffer = [0u8; 3];
+        let read = reader.read(&mut buffer).await.unwrap();
+        let mut writer = EntryWriter::new(staged.clone(), 0);
+        writer.write(&buffer[..read]).await.unwrap();
+        writer.flush().await.unwrap();
+        let offset = staged_len(&staged);
+        assert_eq!(offset, 3);
+        let mut reader = EntryReader::open(files.clone(), offset).await.unwrap();
+        let mut writer = EntryWriter::new(staged.clone(), offset);
+        let mut buffer = [0u8; 5];
+        loop {
+            let read = reader.read(&mut buffer).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            writer.write(&buffer[..read]).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+
+        let (mut sent, mut received) = (Sha256::new(), Sha256::new());
+        hash_range(&files, 0, u64::MAX, &mut sent).unwrap();
+        hash_range(&staged, 0, u64::MAX, &mut received).unwrap();
+        assert_eq!(sent.finalize(), received.finalize());
+
+        let target = dir.path().join("downloads");
+        let staged_paths: Vec<_> = staged.into_iter().map(|(path, _)| path).collect();
+        place(&staged_paths, &entries, &target, CollisionPolicy::Rename).unwrap();
+        assert_eq!(
+            fs::read(target.join("project/src/main.rs")).unwrap(),
+            b"fn main() {}"
+        );
+        assert_eq!(fs::read(target.join("project/README")).unwrap(), b"readme");
+        assert!(target.join("project/src/zero").exists());
+    }
+}
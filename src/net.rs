@@ -20,6 +20,10 @@ use tokio::task::JoinHandle;
 
 use crate::config::{DiscoveryConfig, TlsConfig};
 
+/// Unidirectional streams a peer may have open towards us at once. Every message and every
+/// segment of a file transfer travels on its own stream.
+pub const MAX_CONCURRENT_UNI_STREAMS: u32 = 256;
+
 /// Active server endpoint, including client configuration for outbound connections.
 pub struct ServerContext {
     pub endpoint: Endpoint,
@@ -189,7 +193,7 @@ fn parse_discovery_reply(payload: &[u8], ip: IpAddr) -> Option<SocketAddr> {
 fn default_transport_config() -> Arc<quinn::TransportConfig> {
     let mut transport_config = quinn::TransportConfig::default();
     transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(10)));
-    transport_config.max_concurrent_uni_streams(quinn::VarInt::from_u32(256));
+    transport_config.max_concurrent_uni_streams(MAX_CONCURRENT_UNI_STREAMS.into());
     transport_config.max_concurrent_bidi_streams(quinn::VarInt::from_u32(32));
     Arc::new(transport_config)
 }
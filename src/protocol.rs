@@ -4,6 +4,8 @@ use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::compression::Codec;
+
 /// Maximum frame payload supported by the framing helpers.
 pub const MAX_FRAME_BYTES: usize = 256 * 1024;
 
@@ -16,15 +18,48 @@ pub enum WireMessage {
     Ack(Ack),
     Control(ControlMessage),
     Ciphertext(CipherFrame),
+    /// Encrypted `FileMeta`/`FileChunk`/`FileDigest` on a file-transfer stream.
+    FileFrame(FileFrame),
+    FileDigest(FileDigest),
+    /// `Ack` sealed with the session ratchet like chat; plain `Ack`s are refused.
+    SealedAck(CipherFrame),
+    /// `ControlMessage` sealed with the session ratchet. Presence and typing notices are only
+    /// accepted this way.
+    SealedControl(CipherFrame),
 }
 
 /// Text chat payload.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TextMessage {
     pub id: u64,
+    /// Position among the messages sent on this connection, starting at 0.
+    pub seq: u64,
     pub author: String,
     pub body: String,
     pub timestamp: i64,
+    /// Set when the message changes an earlier one instead of standing on its own.
+    pub amends: Option<Amendment>,
+    /// Id of the earlier message this one answers.
+    pub reply_to: Option<u64>,
+}
+
+/// Change to an earlier chat message, named by its id. The body of the carrying message is
+/// the new text for `Edit` and the emoji for `React`; `Delete` leaves it empty.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Amendment {
+    pub id: u64,
+    pub kind: AmendmentKind,
+}
+
+/// Amendment kinds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AmendmentKind {
+    /// Replaces the text of a message the sender wrote.
+    Edit,
+    /// Retracts a message the sender wrote.
+    Delete,
+    /// Sets the sender's reaction to any message of the conversation; an empty body clears it.
+    React,
 }
 
 /// Metadata describing an incoming file stream.
@@ -32,11 +67,15 @@ pub struct TextMessage {
 pub struct FileMetadata {
     pub id: u64,
     pub name: String,
-    pub compressed_size: u64,
     pub original_size: u64,
+    /// Codec the chunks of this stream are compressed with.
+    pub codec: Codec,
+    /// Segment of the transfer the stream carries, as an index into the offer's segments.
+    pub segment: u32,
 }
 
-/// File chunk message.
+/// File chunk message. `offset` is the position of the chunk in the original file and
+/// `bytes` holds its compressed form.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct FileChunk {
     pub id: u64,
@@ -45,6 +84,15 @@ pub struct FileChunk {
     pub last: bool,
 }
 
+/// Trailer closing a file stream, computed while the file was read.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileDigest {
+    pub id: u64,
+    /// SHA-256 of the whole segment the stream carries, including any part sent before a
+    /// resume.
+    pub sha256: [u8; 32],
+}
+
 /// ACK/control payloads.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Ack {
@@ -55,8 +103,12 @@ pub struct Ack {
 /// ACK kinds.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum AckKind {
+    /// The chat message with this id arrived.
     Received,
+    /// The file transfer with this id was stored and verified.
     Completed,
+    /// The chat message with this id was shown to the user.
+    Read,
 }
 
 /// Control messages for future extensions.
@@ -68,6 +120,12 @@ pub enum ControlMessage {
     FileOffer(FileOffer),
     FileAccept(FileAccept),
     FileReject(FileReject),
+    FileResume(FileResume),
+    FileControl(FileControl),
+    /// Availability of the sender, repeated periodically as a heartbeat.
+    Presence(Presence),
+    /// The sender is composing a message (`true`), or stopped without sending it.
+    Typing(bool),
 }
 
 /// Hello handshake contents.
@@ -75,7 +133,14 @@ pub enum ControlMessage {
 pub struct HelloMessage {
     pub username: String,
     pub password: Option<String>,
+    /// Per-connection X25519 key.
     pub public_key: [u8; 32],
+    /// Long-term Ed25519 identity of the sender.
+    pub identity_key: [u8; 32],
+    /// Signature by `identity_key` over the username and both public keys.
+    pub signature: Vec<u8>,
+    /// Compression codecs the sender can decode, most preferred first.
+    pub codecs: Vec<Codec>,
 }
 
 /// File offer handshake.
@@ -84,7 +149,23 @@ pub struct FileOffer {
     pub id: u64,
     pub name: String,
     pub original_size: u64,
-    pub compressed_size: u64,
+    /// Codec chosen for the transfer.
+    pub codec: Codec,
+    /// Compressed/original size ratio estimated from samples of the file.
+    pub estimated_ratio: f32,
+    /// Files carried by the transfer, in stream order. Their sizes add up to `original_size`.
+    pub entries: Vec<ManifestEntry>,
+    /// The concatenated files are sent in segments of this many bytes (the last one may be
+    /// shorter), each on its own stream and several at a time.
+    pub segment_bytes: u64,
+}
+
+/// One file of a transfer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry {
+    /// Relative path with `/` separators. A single file is just its name.
+    pub path: String,
+    pub size: u64,
 }
 
 /// Acceptance of a pending file transfer.
@@ -93,20 +174,133 @@ pub struct FileAccept {
     pub id: u64,
 }
 
+/// Request to restart an accepted transfer after a reconnect, listing what is still missing.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileResume {
+    pub id: u64,
+    pub segments: Vec<SegmentResume>,
+}
+
+/// Segment to send again, starting at `offset` within the whole transfer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SegmentResume {
+    pub index: u32,
+    pub offset: u64,
+}
+
+/// Pause, resume or cancellation of a transfer by either side.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileControl {
+    pub id: u64,
+    pub action: TransferAction,
+}
+
+/// Actions carried by [`FileControl`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransferAction {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// Availability announced by a peer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Presence {
+    pub state: PresenceState,
+    /// Free-form status text, e.g. "in a meeting".
+    pub status: Option<String>,
+}
+
+/// States carried by [`Presence`].
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceState {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+impl std::fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PresenceState::Online => "online",
+            PresenceState::Away => "away",
+            PresenceState::Busy => "busy",
+        })
+    }
+}
+
+impl std::str::FromStr for PresenceState {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "online" => Ok(PresenceState::Online),
+            "away" => Ok(PresenceState::Away),
+            "busy" => Ok(PresenceState::Busy),
+            other => bail!("unknown presence '{other}' (expected online, away or busy)"),
+        }
+    }
+}
+
 /// Rejection of a pending file transfer.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct FileReject {
     pub id: u64,
     pub reason: Option<String>,
+    /// The offer went unanswered for too long.
+    pub expired: bool,
 }
 
 /// Encrypted payload envelope.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct CipherFrame {
+    pub header: RatchetHeader,
     pub nonce: [u8; 12],
     pub body: Vec<u8>,
 }
 
+/// Application error code a sender resets a file stream with when the transfer is cancelled.
+pub const STREAM_CANCELLED: u32 = 1;
+
+/// Error code a receiver stops the remaining streams of a transfer with after giving up on
+/// it; the reason follows in a `FileReject`.
+pub const STREAM_ABORTED: u32 = 2;
+
+/// Offset used by the sealed `FileMeta` frame that opens a file stream.
+pub const FILE_META_OFFSET: u64 = u64::MAX;
+
+/// Offset used by the sealed `FileDigest` frame that closes a file stream.
+pub const FILE_DIGEST_OFFSET: u64 = u64::MAX - 1;
+
+/// Sealed file-stream frame. Each stream has its own key, derived from `id`, `segment` and
+/// `attempt`; the nonce is `offset` and `id`/`offset` are authenticated, so frames cannot be
+/// moved within or across files.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileFrame {
+    pub id: u64,
+    /// Segment the stream carries.
+    pub segment: u32,
+    /// Stream number among all the streams the sender opened for this file, so a resumed
+    /// segment never reuses the key of an earlier stream.
+    pub attempt: u32,
+    pub offset: u64,
+    pub nonce: [u8; 12],
+    pub body: Vec<u8>,
+}
+
+/// Double-ratchet header, authenticated as associated data.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RatchetHeader {
+    /// Sender's current ratchet public key.
+    pub dh: [u8; 32],
+    /// Messages sent on the sender's previous chain.
+    pub previous: u32,
+    /// Index of this message in the current chain.
+    pub index: u32,
+}
+
 /// Serializes a [`WireMessage`] into bytes.
 pub fn encode_message(message: &WireMessage) -> Result<Vec<u8>> {
     bincode::serde::encode_to_vec(message, bincode::config::standard())
@@ -188,9 +382,12 @@ mod tests {
     async fn frame_round_trip() {
         let message = WireMessage::Text(TextMessage {
             id: 42,
+            seq: 0,
             author: "tester".into(),
             body: "hello".into(),
             timestamp: utc_timestamp(),
+            amends: None,
+            reply_to: None,
         });
 
         let mut buffer = Vec::new();
@@ -205,8 +402,9 @@ mod tests {
         let meta = WireMessage::FileMeta(FileMetadata {
             id: 7,
             name: "file.bin".into(),
-            compressed_size: 96,
             original_size: 128,
+            codec: Codec::Zstd,
+            segment: 0,
         });
 
         let bytes = encode_message(&meta).unwrap();
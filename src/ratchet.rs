@@ -0,0 +1,328 @@
+//! Double ratchet used to encrypt chat messages.
+//!
+//! Every message is sealed with its own key taken from a symmetric chain, and each time the
+//! conversation changes direction a fresh X25519 ratchet key is mixed into the root chain.
+//! Message keys are discarded after use, so a leaked state cannot decrypt earlier traffic.
+
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::protocol::{CipherFrame, RatchetHeader};
+
+/// Upper bound on message keys skipped in a single chain, and on those kept around overall;
+/// past it the oldest skipped keys are forgotten.
+pub const MAX_SKIP: u32 = 1000;
+
+const ROOT_INFO: &[u8] = b"dezap-ratchet-root";
+const INITIAL_CHAIN_INFO: &[u8] = b"dezap-ratchet-initial";
+const MESSAGE_INFO: &[u8] = b"dezap-ratchet-message";
+const CHAIN_INFO: &[u8] = b"dezap-ratchet-chain";
+
+/// Sender ratchet key and index of a message.
+type MessageId = ([u8; 32], u32);
+
+/// Ratchet state for one connection.
+#[derive(Clone)]
+pub struct Ratchet {
+    dh_self: StaticSecret,
+    dh_remote: Option<[u8; 32]>,
+    root: [u8; 32],
+    send_chain: [u8; 32],
+    recv_chain: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    previous_count: u32,
+    /// Keys of messages that have not arrived yet, oldest first.
+    skipped: VecDeque<(MessageId, [u8; 32])>,
+}
+
+impl Ratchet {
+    /// Builds the ratchet from the handshake session key and both handshake keys.
+    ///
+    /// The side with the lower ephemeral key starts with a fresh ratchet key, the other reuses
+    /// its handshake key; both get a sending chain right away so either may speak first.
+    pub fn new(
+        session_key: &[u8; 32],
+        local_secret: &StaticSecret,
+        remote_public: &[u8; 32],
+    ) -> Result<Self> {
+        let local_public = PublicKey::from(local_secret).to_bytes();
+        let initial_chain = expand(session_key, INITIAL_CHAIN_INFO)?;
+        if local_public < *remote_public {
+            let dh_self = StaticSecret::new(OsRng);
+            let shared = dh_self.diffie_hellman(&PublicKey::from(*remote_public));
+            let (root, send_chain) = kdf_root(session_key, shared.as_bytes())?;
+            Ok(Self {
+                dh_self,
+                dh_remote: Some(*remote_public),
+                root,
+                send_chain,
+                recv_chain: Some(initial_chain),
+                send_count: 0,
+                recv_count: 0,
+                previous_count: 0,
+                skipped: VecDeque::new(),
+            })
+        } else {
+            Ok(Self {
+                dh_self: local_secret.clone(),
+                dh_remote: None,
+                root: *session_key,
+                send_chain: initial_chain,
+                recv_chain: None,
+                send_count: 0,
+                recv_count: 0,
+                previous_count: 0,
+                skipped: VecDeque::new(),
+            })
+        }
+    }
+
+    /// Encrypts `plaintext` with the next sending key.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<CipherFrame> {
+        let (next_chain, message_key) = kdf_chain(&self.send_chain)?;
+        let header = RatchetHeader {
+            dh: PublicKey::from(&self.dh_self).to_bytes(),
+            previous: self.previous_count,
+            index: self.send_count,
+        };
+        self.send_chain = next_chain;
+        self.send_count += 1;
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let body = seal_with(&message_key, &nonce, &header, plaintext)?;
+        Ok(CipherFrame {
+            header,
+            nonce,
+            body,
+        })
+    }
+
+    /// Decrypts `frame`, advancing the state only if authentication succeeds.
+    pub fn open(&mut self, frame: &CipherFrame) -> Result<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.open_in_place(frame)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn open_in_place(&mut self, frame: &CipherFrame) -> Result<Vec<u8>> {
+        let header = &frame.header;
+        let id = (header.dh, header.index);
+        if let Some(position) = self.skipped.iter().position(|(skipped, _)| *skipped == id) {
+            let (_, key) = self.skipped.remove(position).expect("position is in range");
+            return open_with(&key, frame);
+        }
+        if self.dh_remote != Some(header.dh) {
+            self.skip_until(header.previous)?;
+            self.step(header.dh)?;
+        }
+        self.skip_until(header.index)?;
+        let chain = self
+            .recv_chain
+            .ok_or_else(|| anyhow!("no receiving chain"))?;
+        let (next_chain, message_key) = kdf_chain(&chain)?;
+        self.recv_chain = Some(next_chain);
+        self.recv_count += 1;
+        open_with(&message_key, frame)
+    }
+
+    /// Stores keys for messages of the current receiving chain that have not arrived yet,
+    /// dropping the oldest ones once more than `MAX_SKIP` are held.
+    fn skip_until(&mut self, until: u32) -> Result<()> {
+        let (Some(mut chain), Some(remote)) = (self.recv_chain, self.dh_remote) else {
+            return Ok(());
+        };
+        if until.saturating_sub(self.recv_count) > MAX_SKIP {
+            bail!("too many skipped messages");
+        }
+        while self.recv_count < until {
+            let (next_chain, message_key) = kdf_chain(&chain)?;
+            self.skipped
+                .push_back(((remote, self.recv_count), message_key));
+            chain = next_chain;
+            self.recv_count += 1;
+        }
+        self.recv_chain = Some(chain);
+        while self.skipped.len() > MAX_SKIP as usize {
+            self.skipped.pop_front();
+        }
+        Ok(())
+    }
+
+    /// DH ratchet step on a new remote key: derive the receiving chain, then a fresh sending one.
+    fn step(&mut self, remote: [u8; 32]) -> Result<()> {
+        let remote_key = PublicKey::from(remote);
+        let shared = self.dh_self.diffie_hellman(&remote_key);
+        let (root, recv_chain) = kdf_root(&self.root, shared.as_bytes())?;
+        self.dh_self = StaticSecret::new(OsRng);
+        let shared = self.dh_self.diffie_hellman(&remote_key);
+        let (root, send_chain) = kdf_root(&root, shared.as_bytes())?;
+        self.previous_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+        self.dh_remote = Some(remote);
+        self.root = root;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = send_chain;
+        Ok(())
+    }
+}
+
+fn kdf_root(root: &[u8; 32], dh: &[u8; 32]) -> Result<([u8; 32], [u8; 32])> {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root), dh)
+        .expand(ROOT_INFO, &mut okm)
+        .map_err(|_| anyhow!("failed to expand root key"))?;
+    let mut next_root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    next_root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    Ok((next_root, chain))
+}
+
+fn kdf_chain(chain: &[u8; 32]) -> Result<([u8; 32], [u8; 32])> {
+    Ok((expand(chain, CHAIN_INFO)?, expand(chain, MESSAGE_INFO)?))
+}
+
+fn expand(key: &[u8; 32], info: &[u8]) -> Result<[u8; 32]> {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::from_prk(key)
+        .map_err(|_| anyhow!("invalid chain key"))?
+        .expand(info, &mut okm)
+        .map_err(|_| anyhow!("failed to expand chain key"))?;
+    Ok(okm)
+}
+
+fn header_aad(header: &RatchetHeader) -> Result<Vec<u8>> {
+    bincode::serde::encode_to_vec(header, bincode::config::standard())
+        .context("failed to encode ratchet header")
+}
+
+fn seal_with(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    header: &RatchetHeader,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let aad = header_aad(header)?;
+    cipher
+        .encrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| anyhow!("failed to encrypt payload"))
+}
+
+fn open_with(key: &[u8; 32], frame: &CipherFrame) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let aad = header_aad(&frame.header)?;
+    cipher
+        .decrypt(
+            GenericArray::from_slice(&frame.nonce),
+            Payload {
+                msg: &frame.body,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| anyhow!("failed to decrypt payload"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Ratchet, Ratchet) {
+        let session = [7u8; 32];
+        let a = StaticSecret::new(OsRng);
+        let b = StaticSecret::new(OsRng);
+        let a_pub = PublicKey::from(&a).to_bytes();
+        let b_pub = PublicKey::from(&b).to_bytes();
+        (
+            Ratchet::new(&session, &a, &b_pub).unwrap(),
+            Ratchet::new(&session, &b, &a_pub).unwrap(),
+        )
+    }
+
+    #[test]
+    fn either_side_can_speak_first_and_replies_ratchet() {
+        for swap in [false, true] {
+            let (mut a, mut b) = pair();
+            if swap {
+                std::mem::swap(&mut a, &mut b);
+            }
+            let first = a.seal(b"one").unwrap();
+            assert_eq!(b.open(&first).unwrap(), b"one");
+            let reply = b.seal(b"two").unwrap();
+            assert_ne!(reply.header.dh, first.header.dh);
+            assert_eq!(a.open(&reply).unwrap(), b"two");
+            let third = a.seal(b"three").unwrap();
+            assert_eq!(b.open(&third).unwrap(), b"three");
+        }
+    }
+
+    #[test]
+    fn out_of_order_and_concurrent_messages_decrypt() {
+        let (mut a, mut b) = pair();
+        let a1 = a.seal(b"a1").unwrap();
+        let a2 = a.seal(b"a2").unwrap();
+        let b1 = b.seal(b"b1").unwrap();
+        assert_eq!(b.open(&a2).unwrap(), b"a2");
+        let b2 = b.seal(b"b2").unwrap();
+        assert_eq!(a.open(&b2).unwrap(), b"b2");
+        let a3 = a.seal(b"a3").unwrap();
+        assert_eq!(b.open(&a3).unwrap(), b"a3");
+        assert_eq!(b.open(&a1).unwrap(), b"a1");
+        assert_eq!(a.open(&b1).unwrap(), b"b1");
+    }
+
+    #[test]
+    fn replayed_or_tampered_frames_are_rejected_without_state_damage() {
+        let (mut a, mut b) = pair();
+        let frame = a.seal(b"hello").unwrap();
+        let mut tampered = frame.clone();
+        tampered.header.index += 1;
+        assert!(b.open(&tampered).is_err());
+        assert_eq!(b.open(&frame).unwrap(), b"hello");
+        assert!(b.open(&frame).is_err(), "message keys are single use");
+        let next = a.seal(b"again").unwrap();
+        assert_eq!(b.open(&next).unwrap(), b"again");
+    }
+
+    #[test]
+    fn skip_bound_is_enforced() {
+        let (mut a, mut b) = pair();
+        let mut frame = a.seal(b"x").unwrap();
+        frame.header.index = MAX_SKIP + 1;
+        assert!(b.open(&frame).is_err());
+    }
+
+    #[test]
+    fn losses_across_chains_evict_the_oldest_skipped_keys() {
+        let (mut a, mut b) = pair();
+        let mut late = Vec::new();
+        // Four chains in a row lose all but their last message, 1596 losses in total.
+        for _ in 0..4 {
+            let frames: Vec<_> = (0..400).map(|_| a.seal(b"lost").unwrap()).collect();
+            assert_eq!(b.open(&frames[399]).unwrap(), b"lost");
+            late.push(frames[0].clone());
+            let reply = b.seal(b"reply").unwrap();
+            assert_eq!(a.open(&reply).unwrap(), b"reply");
+        }
+        let next = a.seal(b"still here").unwrap();
+        assert_eq!(b.open(&next).unwrap(), b"still here");
+        assert_eq!(b.open(&late[3]).unwrap(), b"lost");
+        assert!(b.open(&late[0]).is_err(), "oldest keys were evicted");
+    }
+}
@@ -0,0 +1,210 @@
+//! Puts chat messages back in the order they were sent.
+//!
+//! Every message travels on its own QUIC stream, so two messages sent back to back can
+//! arrive the other way round. Senders number their messages per connection and
+//! [`ReorderBuffer`] holds on to messages that overtook an earlier one for a short while,
+//! giving the missing one a chance to catch up before moving on without it.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::protocol::TextMessage;
+
+/// Message ids remembered to drop messages delivered twice.
+const SEEN_IDS: usize = 1024;
+
+/// Reorder buffer for the chat messages of one connection.
+pub struct ReorderBuffer {
+    hold: Duration,
+    /// Sequence number expected next.
+    next: u64,
+    /// Messages waiting for an earlier one, with the time they arrived.
+    held: BTreeMap<u64, (Instant, TextMessage)>,
+    seen: SeenIds,
+}
+
+impl ReorderBuffer {
+    /// Creates a buffer that waits at most `hold` for a missing message.
+    pub fn new(hold: Duration) -> Self {
+        Self {
+            hold,
+            next: 0,
+            held: BTreeMap::new(),
+            seen: SeenIds::default(),
+        }
+    }
+
+    /// Takes a received message and returns the messages that can now be shown, in order.
+    ///
+    /// Duplicates are dropped. A message whose turn has already passed, because the buffer
+    /// gave up waiting for it, is returned right away.
+    pub fn push(&mut self, message: TextMessage, now: Instant) -> Vec<TextMessage> {
+        if self.seen.contains(&message.id) || self.held.contains_key(&message.seq) {
+            return Vec::new();
+        }
+        if message.seq < self.next {
+            self.seen.insert(message.id);
+            return vec![message];
+        }
+        self.held.insert(message.seq, (now, message));
+        self.release()
+    }
+
+    /// When the oldest held message stops waiting for the ones before it.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held
+            .values()
+            .map(|(arrived, _)| *arrived + self.hold)
+            .min()
+    }
+
+    /// Gives up on missing messages that held ones waited `hold` for and returns what
+    /// follows them.
+    pub fn expire(&mut self, now: Instant) -> Vec<TextMessage> {
+        let expired = self
+            .held
+            .iter()
+            .filter(|(_, (arrived, _))| *arrived + self.hold <= now)
+            .map(|(seq, _)| *seq)
+            .max();
+        let mut ready = Vec::new();
+        if let Some(last) = expired {
+            while let Some(entry) = self.held.first_entry() {
+                if *entry.key() > last {
+                    break;
+                }
+                let (_, message) = entry.remove();
+                self.seen.insert(message.id);
+                ready.push(message);
+            }
+            self.next = last + 1;
+        }
+        ready.extend(self.release());
+        ready
+    }
+
+    /// Returns every held message, for when the connection goes away.
+    pub fn drain(&mut self) -> Vec<TextMessage> {
+        let held = std::mem::take(&mut self.held);
+        held.into_values().map(|(_, message)| message).collect()
+    }
+
+    /// Takes the messages that follow on from `next` without a gap.
+    fn release(&mut self) -> Vec<TextMessage> {
+        let mut ready = Vec::new();
+        while let Some((_, message)) = self.held.remove(&self.next) {
+            self.next += 1;
+            self.seen.insert(message.id);
+            ready.push(message);
+        }
+        ready
+    }
+}
+
+/// The last [`SEEN_IDS`] message ids, oldest forgotten first.
+#[derive(Default)]
+pub struct SeenIds {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl SeenIds {
+    pub fn contains(&self, id: &u64) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Remembers `id` and returns whether it was new.
+    pub fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        if self.order.len() == SEEN_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOLD: Duration = Duration::from_millis(250);
+
+    fn message(seq: u64) -> TextMessage {
+        TextMessage {
+            id: 1000 + seq,
+            seq,
+            author: "tester".into(),
+            body: format!("message {seq}"),
+            timestamp: 0,
+            amends: None,
+            reply_to: None,
+        }
+    }
+
+    fn seqs(messages: Vec<TextMessage>) -> Vec<u64> {
+        messages.into_iter().map(|message| message.seq).collect()
+    }
+
+    #[test]
+    fn shuffled_burst_comes_out_in_order() {
+        let mut buffer = ReorderBuffer::new(HOLD);
+        let now = Instant::now();
+        let mut out = Vec::new();
+        for seq in [3, 0, 4, 1, 7, 2, 6, 5, 9, 8] {
+            out.extend(seqs(buffer.push(message(seq), now)));
+        }
+        assert_eq!(out, (0..10).collect::<Vec<_>>());
+        assert_eq!(buffer.deadline(), None);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut buffer = ReorderBuffer::new(HOLD);
+        let now = Instant::now();
+        assert_eq!(seqs(buffer.push(message(0), now)), vec![0]);
+        assert!(buffer.push(message(0), now).is_empty());
+        assert!(buffer.push(message(2), now).is_empty());
+        assert!(buffer.push(message(2), now).is_empty());
+        // The same message resent under a new sequence number is still a duplicate.
+        let mut resent = message(0);
+        resent.seq = 1;
+        assert!(buffer.push(resent, now).is_empty());
+        assert_eq!(seqs(buffer.push(message(1), now)), vec![1, 2]);
+    }
+
+    #[test]
+    fn seen_ids_forget_the_oldest_first() {
+        let mut seen = SeenIds::default();
+        for id in 0..SEEN_IDS as u64 {
+            assert!(seen.insert(id));
+        }
+        assert!(!seen.insert(0));
+        assert!(seen.insert(SEEN_IDS as u64));
+        assert!(!seen.contains(&0));
+        assert!(seen.contains(&1));
+    }
+
+    #[test]
+    fn gaps_are_skipped_after_the_hold_window() {
+        let mut buffer = ReorderBuffer::new(HOLD);
+        let start = Instant::now();
+        assert!(buffer.push(message(1), start).is_empty());
+        let later = start + Duration::from_millis(100);
+        assert!(buffer.push(message(2), later).is_empty());
+        assert!(buffer.push(message(4), later).is_empty());
+        assert_eq!(buffer.deadline(), Some(start + HOLD));
+        assert!(buffer.expire(start + HOLD / 2).is_empty());
+        // Gives up on 0 but keeps waiting for 3, which 4 has not waited long enough for.
+        assert_eq!(seqs(buffer.expire(start + HOLD)), vec![1, 2]);
+        assert_eq!(buffer.deadline(), Some(later + HOLD));
+        // A straggler is still shown, just late.
+        assert_eq!(seqs(buffer.push(message(0), later + HOLD)), vec![0]);
+        assert_eq!(seqs(buffer.expire(later + HOLD)), vec![4]);
+        assert_eq!(seqs(buffer.push(message(5), later + HOLD)), vec![5]);
+    }
+}
@@ -1,36 +1,66 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::io::Write;
 use std::net::Ipv4Addr;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, bail, Context, Result};
 use chacha20poly1305::aead::{generic_array::GenericArray, Aead, KeyInit};
 use chacha20poly1305::ChaCha20Poly1305;
-use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::mpsc;
-use tokio::task::{spawn_blocking, JoinHandle};
-use x25519_dalek::{PublicKey, StaticSecret};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::{spawn_blocking, JoinHandle, JoinSet};
 
+use crate::bandwidth::{self, RateLimiter, Throughput};
 use crate::cli::{ListenCommand, SendCommand, SendFileCommand};
-use crate::config::{AppConfig, LimitsConfig};
+use crate::compression::{self, ChunkDecoder, ChunkEncoder, Codec};
+use crate::config::{AppConfig, AutoAcceptRule, CollisionPolicy, LimitsConfig};
+use crate::crypto::{self, CryptoCtx, FileCipher, Identity};
+use crate::manifest::{self, EntryReader, EntryWriter};
 use crate::net;
 use crate::protocol::{
-    self, CipherFrame, ControlMessage, FileAccept, FileChunk, FileMetadata, FileOffer, FileReject,
-    HelloMessage, TextMessage, WireMessage,
+    self, Ack, AckKind, Amendment, AmendmentKind, CipherFrame, ControlMessage, FileAccept,
+    FileChunk, FileControl, FileDigest, FileFrame, FileMetadata, FileOffer, FileReject, FileResume,
+    HelloMessage, ManifestEntry, Presence, PresenceState, SegmentResume, TextMessage,
+    TransferAction, WireMessage, FILE_DIGEST_OFFSET, FILE_META_OFFSET, STREAM_ABORTED,
+    STREAM_CANCELLED,
 };
+use crate::reorder::{ReorderBuffer, SeenIds};
 use parking_lot::Mutex;
-use tempfile::NamedTempFile;
 
 const COMMAND_BUFFER: usize = 64;
 const EVENT_BUFFER: usize = 256;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest presence status text kept from a peer.
+const MAX_STATUS_CHARS: usize = 80;
+/// Directory under `download_dir` holding unfinished downloads.
+const PARTIAL_DIR: &str = ".dezap-partial";
+/// Files smaller than two segments of this size go over a single stream.
+const MIN_SEGMENT_BYTES: u64 = 8 * 1024 * 1024;
+/// Most segments a received offer may be split into.
+const MAX_SEGMENTS: u64 = 1024;
+/// How much of a segment is received between two records of its progress on disk.
+const SEGMENT_CHECKPOINT_BYTES: u64 = 8 * 1024 * 1024;
+/// Extra time a sender gives an unanswered offer, so the recipient's own expiry, which
+/// explains itself with a `FileReject`, usually gets there first.
+const OFFER_EXPIRY_GRACE: Duration = Duration::from_secs(30);
+/// How often accepted outgoing transfers are checked for having gone idle.
+const IDLE_TRANSFER_SWEEP: Duration = Duration::from_secs(60);
+/// How long a chat message that overtook an earlier one waits for it before being shown.
+const REORDER_HOLD: Duration = Duration::from_millis(250);
 
 /// High-level command channel to the async runtime.
 pub struct DezapService {
@@ -78,12 +108,30 @@ pub enum ServiceCommand {
         addr: std::net::SocketAddr,
         password: Option<String>,
     },
-    Disconnect,
+    Disconnect {
+        peer: Option<std::net::SocketAddr>,
+    },
     SendText {
+        peer: std::net::SocketAddr,
+        text: String,
+        /// Id of the earlier message this one answers.
+        reply_to: Option<u64>,
+    },
+    /// Edits, retracts or reacts to an earlier message; `text` is the new text or the emoji.
+    AmendMessage {
+        peer: std::net::SocketAddr,
+        amendment: Amendment,
         text: String,
     },
+    /// Tells `peer` its messages `ids` were shown to the user.
+    MarkRead {
+        peer: std::net::SocketAddr,
+        ids: Vec<u64>,
+    },
+    /// Offers files and directories as one transfer.
     SendFile {
-        path: PathBuf,
+        peer: std::net::SocketAddr,
+        paths: Vec<PathBuf>,
     },
     Discover,
     SetUsername {
@@ -93,15 +141,80 @@ pub enum ServiceCommand {
         target: Option<Ipv4Addr>,
     },
     AcceptFile {
+        peer: std::net::SocketAddr,
         id: u64,
         path: PathBuf,
+        /// Overrides `[transfer] on_collision` for this offer.
+        on_collision: Option<CollisionPolicy>,
     },
     DeclineFile {
+        peer: std::net::SocketAddr,
+        id: u64,
+    },
+    /// Stops an accepted or offered transfer on both sides and discards its partial download.
+    CancelTransfer {
+        peer: std::net::SocketAddr,
+        id: u64,
+    },
+    /// Holds a transfer until either side resumes it.
+    PauseTransfer {
+        peer: std::net::SocketAddr,
+        id: u64,
+    },
+    ResumeTransfer {
+        peer: std::net::SocketAddr,
         id: u64,
     },
     SetHistoryEnabled {
         enabled: bool,
     },
+    /// Forgets the pinned identity key of a saved peer so the next handshake re-pins it.
+    ResetPeerIdentity {
+        addr: std::net::SocketAddr,
+    },
+    /// Asks for the safety number shared with `peer`.
+    ShowSafetyNumber {
+        peer: std::net::SocketAddr,
+    },
+    /// Marks the pinned identity of a saved peer as verified out of band (or not).
+    SetPeerVerified {
+        addr: std::net::SocketAddr,
+        verified: bool,
+    },
+    /// Tells `peer` whether a message to it is being typed. Dropped when `peer` is offline.
+    SetTyping {
+        peer: std::net::SocketAddr,
+        typing: bool,
+    },
+    /// Announces a new availability to every connected peer.
+    SetPresence {
+        state: PresenceState,
+        status: Option<String>,
+    },
+}
+
+impl ServiceCommand {
+    /// Peer targeted by the command, if it concerns a single connection.
+    pub fn target_peer(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            ServiceCommand::Connect { addr, .. }
+            | ServiceCommand::ResetPeerIdentity { addr }
+            | ServiceCommand::SetPeerVerified { addr, .. } => Some(*addr),
+            ServiceCommand::Disconnect { peer } => *peer,
+            ServiceCommand::SendText { peer, .. }
+            | ServiceCommand::AmendMessage { peer, .. }
+            | ServiceCommand::MarkRead { peer, .. }
+            | ServiceCommand::SetTyping { peer, .. }
+            | ServiceCommand::SendFile { peer, .. }
+            | ServiceCommand::AcceptFile { peer, .. }
+            | ServiceCommand::DeclineFile { peer, .. }
+            | ServiceCommand::CancelTransfer { peer, .. }
+            | ServiceCommand::PauseTransfer { peer, .. }
+            | ServiceCommand::ResumeTransfer { peer, .. }
+            | ServiceCommand::ShowSafetyNumber { peer } => Some(*peer),
+            _ => None,
+        }
+    }
 }
 
 /// Events emitted by the service to inform the UI/CLI.
@@ -119,41 +232,250 @@ pub enum ServiceEvent {
         password_protected: bool,
     },
     ListenerStopped,
-    Disconnected,
+    Disconnected {
+        peer: std::net::SocketAddr,
+    },
     MessageReceived {
         peer: std::net::SocketAddr,
+        id: u64,
         author: String,
         text: String,
+        /// Id of the earlier message this one answers.
+        reply_to: Option<u64>,
     },
     MessageSent {
+        peer: std::net::SocketAddr,
+        id: u64,
+        author: String,
+        text: String,
+        reply_to: Option<u64>,
+    },
+    /// Message `id` is waiting in the outbox until `peer` is reachable again.
+    MessageQueued {
+        peer: std::net::SocketAddr,
+        id: u64,
+        author: String,
+        text: String,
+        reply_to: Option<u64>,
+    },
+    /// The outgoing connection to `peer` dropped and attempt `attempt` to redial it is
+    /// scheduled.
+    Reconnecting {
+        peer: std::net::SocketAddr,
+        attempt: u32,
+    },
+    /// Queued messages `ids` went out after `peer` came back.
+    OutboxFlushed {
+        peer: std::net::SocketAddr,
+        ids: Vec<u64>,
+    },
+    /// A message was edited, retracted or reacted to, by `peer` or by us (`outgoing`).
+    MessageAmended {
+        peer: std::net::SocketAddr,
         author: String,
+        amendment: Amendment,
         text: String,
+        outgoing: bool,
+    },
+    /// `peer` acknowledged receiving message `id`.
+    MessageDelivered {
+        peer: std::net::SocketAddr,
+        id: u64,
+    },
+    /// `peer` showed message `id` to its user.
+    MessageRead {
+        peer: std::net::SocketAddr,
+        id: u64,
     },
     PeerProfile {
         peer: std::net::SocketAddr,
         username: String,
     },
+    /// `peer` announced its availability, either as a change or as a heartbeat.
+    PeerPresence {
+        peer: std::net::SocketAddr,
+        state: PresenceState,
+        status: Option<String>,
+        /// Unix timestamp of the announcement.
+        last_seen: i64,
+    },
+    /// `peer` started or stopped typing a message.
+    PeerTyping {
+        peer: std::net::SocketAddr,
+        typing: bool,
+    },
     FileTransfer(FileTransferProgress),
     Discovery(DiscoveryEvent),
     SavedPeers(Vec<SavedPeer>),
     FileOffer(FileOfferNotice),
+    /// Offer `id` matched an `auto_accept` rule and is being accepted into `path`.
+    FileAutoAccepted {
+        peer: std::net::SocketAddr,
+        id: u64,
+        name: String,
+        path: PathBuf,
+    },
+    /// A saved peer presented a different identity key than the pinned one; the connection was refused.
+    IdentityKeyChanged {
+        peer: std::net::SocketAddr,
+        /// Address of the saved peer holding the pin, as `ResetPeerIdentity` takes it.
+        saved: std::net::SocketAddr,
+        name: String,
+        expected: String,
+        received: String,
+    },
+    /// A received file did not match the digest announced by the sender and was discarded.
+    FileIntegrityFailed {
+        peer: std::net::SocketAddr,
+        id: u64,
+        name: String,
+    },
+    /// A transfer was stopped for good and its partial download, if any, discarded.
+    TransferAborted {
+        peer: std::net::SocketAddr,
+        id: u64,
+        name: String,
+        reason: AbortReason,
+    },
+    /// Offer `id` went unanswered for `[limits] offer_ttl_secs` and was withdrawn.
+    OfferExpired {
+        peer: std::net::SocketAddr,
+        id: u64,
+        name: String,
+        direction: TransferDirection,
+    },
+    /// Transfer `id` was paused or resumed, here or by the peer.
+    TransferPaused {
+        peer: std::net::SocketAddr,
+        id: u64,
+        paused: bool,
+    },
+    /// Accepting offer `id` would replace `existing` and the collision policy asks first.
+    /// The offer stays pending until it is accepted again with an explicit policy or declined.
+    FileCollision {
+        peer: std::net::SocketAddr,
+        id: u64,
+        name: String,
+        /// Destination the offer was accepted into.
+        path: PathBuf,
+        existing: Vec<PathBuf>,
+    },
+    /// Safety number for comparing identity keys with `peer` out of band.
+    SafetyNumber {
+        peer: std::net::SocketAddr,
+        name: String,
+        number: String,
+        verified: bool,
+    },
     Error {
+        peer: Option<std::net::SocketAddr>,
         message: String,
     },
 }
 
+impl ServiceEvent {
+    /// Peer the event concerns, if any.
+    pub fn peer(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            ServiceEvent::Connected { peer, .. }
+            | ServiceEvent::Connecting { peer }
+            | ServiceEvent::Reconnecting { peer, .. }
+            | ServiceEvent::Disconnected { peer }
+            | ServiceEvent::MessageReceived { peer, .. }
+            | ServiceEvent::MessageSent { peer, .. }
+            | ServiceEvent::MessageQueued { peer, .. }
+            | ServiceEvent::OutboxFlushed { peer, .. }
+            | ServiceEvent::MessageAmended { peer, .. }
+            | ServiceEvent::MessageDelivered { peer, .. }
+            | ServiceEvent::MessageRead { peer, .. }
+            | ServiceEvent::PeerProfile { peer, .. }
+            | ServiceEvent::PeerPresence { peer, .. }
+            | ServiceEvent::PeerTyping { peer, .. }
+            | ServiceEvent::IdentityKeyChanged { peer, .. }
+            | ServiceEvent::FileIntegrityFailed { peer, .. }
+            | ServiceEvent::FileCollision { peer, .. }
+            | ServiceEvent::FileAutoAccepted { peer, .. }
+            | ServiceEvent::OfferExpired { peer, .. }
+            | ServiceEvent::TransferAborted { peer, .. }
+            | ServiceEvent::TransferPaused { peer, .. }
+            | ServiceEvent::SafetyNumber { peer, .. } => Some(*peer),
+            ServiceEvent::FileTransfer(progress) => Some(progress.peer),
+            ServiceEvent::FileOffer(offer) => Some(offer.peer),
+            ServiceEvent::Error { peer, .. } => *peer,
+            ServiceEvent::Listening { .. }
+            | ServiceEvent::ListenerStopped
+            | ServiceEvent::Discovery(_)
+            | ServiceEvent::SavedPeers(_) => None,
+        }
+    }
+}
+
 /// Transfer progress payload.
 #[derive(Debug, Clone)]
 pub struct FileTransferProgress {
+    pub peer: std::net::SocketAddr,
     pub id: u64,
     pub name: String,
     pub transferred: u64,
     pub total: u64,
     pub direction: TransferDirection,
     pub path: Option<PathBuf>,
+    /// File of a multi-file transfer the progress is currently in; `None` for single files.
+    pub entry: Option<EntryProgress>,
+    /// Smoothed speed in bytes of the original data per second, once it can be estimated.
+    pub bytes_per_sec: Option<u64>,
+    /// Time left at the current speed.
+    pub eta: Option<Duration>,
     pub completed: bool,
 }
 
+/// Progress within one file of a multi-file transfer.
+#[derive(Debug, Clone)]
+pub struct EntryProgress {
+    pub index: usize,
+    pub count: usize,
+    pub path: String,
+    pub transferred: u64,
+    pub size: u64,
+}
+
+/// Why a transfer was refused or stopped instead of being kept for resuming.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbortReason {
+    /// The announced size is above `[limits] max_file_bytes`.
+    TooLarge { size: u64, limit: u64 },
+    /// Storing the file would leave less than `[limits] reserve_disk_bytes` free.
+    DiskFull { needed: u64, available: u64 },
+    /// The stream could not be inflated or inflated past the announced size.
+    Corrupt(String),
+    /// One side cancelled the transfer.
+    Cancelled { by_peer: bool },
+    /// An accepted transfer made no progress for `[limits] partial_ttl_secs` and the sender
+    /// let it go.
+    Idle { secs: u64 },
+}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbortReason::TooLarge { size, limit } => write!(
+                f,
+                "file is {size} bytes, above the {limit} byte limit of the recipient"
+            ),
+            AbortReason::DiskFull { needed, available } => write!(
+                f,
+                "recipient needs {needed} bytes of free disk space but has {available}"
+            ),
+            AbortReason::Corrupt(detail) => write!(f, "file stream is corrupt: {detail}"),
+            AbortReason::Cancelled { by_peer: true } => write!(f, "cancelled by the peer"),
+            AbortReason::Cancelled { by_peer: false } => write!(f, "cancelled"),
+            AbortReason::Idle { secs } => write!(f, "no progress for {secs} seconds"),
+        }
+    }
+}
+
+impl std::error::Error for AbortReason {}
+
 /// Transfer direction.
 #[derive(Debug, Clone, Copy)]
 pub enum TransferDirection {
@@ -170,8 +492,19 @@ pub enum DiscoveryEvent {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SavedPeer {
+    /// Where the peer was last dialed; only a hint for dialing it again, since a peer that
+    /// connects to us does so from a port of its own.
     pub addr: std::net::SocketAddr,
     pub name: String,
+    /// Hex-encoded identity key pinned on first contact.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_key: Option<String>,
+    /// Whether the pinned identity key was confirmed with the safety number.
+    #[serde(default)]
+    pub verified: bool,
+    /// Unix timestamp of the last handshake, heartbeat or disconnect seen from the peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
 }
 
 #[derive(Debug, Clone)]
@@ -179,10 +512,27 @@ pub struct FileOfferNotice {
     pub id: u64,
     pub name: String,
     pub original_size: u64,
-    pub compressed_size: u64,
+    /// Codec the sender picked for the transfer.
+    pub codec: Codec,
+    /// Compressed/original size ratio estimated by the sender.
+    pub estimated_ratio: f32,
+    /// Files in the transfer, already checked to stay inside the destination.
+    pub entries: Vec<ManifestEntry>,
+    /// Size of the segments the sender splits the transfer into.
+    pub segment_bytes: u64,
+    /// When the offer is declined on its own unless answered first.
+    pub expires_at: Option<Instant>,
     pub peer: std::net::SocketAddr,
 }
 
+impl FileOfferNotice {
+    /// Whether the offer is a single file saved under a file path, as opposed to a tree
+    /// recreated below a destination directory.
+    pub fn is_single_file(&self) -> bool {
+        manifest::is_single_file(&self.entries)
+    }
+}
+
 /// Runs the service runtime loop.
 async fn runtime_loop(
     config: AppConfig,
@@ -195,44 +545,96 @@ async fn runtime_loop(
         Err(err) => {
             let _ = event_tx
                 .send(ServiceEvent::Error {
+                    peer: None,
                     message: format!("failed to initialize history store: {err:#}"),
                 })
                 .await;
             return;
         }
     };
+    let identity = match Identity::load_or_create(&config.paths.history_dir) {
+        Ok(identity) => Arc::new(identity),
+        Err(err) => {
+            let _ = event_tx
+                .send(ServiceEvent::Error {
+                    peer: None,
+                    message: format!("failed to load identity key: {err:#}"),
+                })
+                .await;
+            return;
+        }
+    };
     let peers_store = match SavedPeersStore::new(config.paths.peers_file.clone()) {
         Ok(store) => Arc::new(store),
         Err(err) => {
             let _ = event_tx
                 .send(ServiceEvent::Error {
+                    peer: None,
                     message: format!("failed to load peers: {err:#}"),
                 })
                 .await;
             return;
         }
     };
+    let outbox = Arc::new(OutboxStore::new(&history));
     let mut state = ServiceState::new(
         config,
         event_tx.clone(),
         internal_tx.clone(),
         history,
+        outbox.clone(),
+        identity,
         peers_store.clone(),
     );
-    let _ = event_tx
-        .send(ServiceEvent::SavedPeers(peers_store.list()))
-        .await;
+    let partial_ttl = state.config.limits.partial_ttl_secs;
+    let max_age = (partial_ttl > 0).then(|| Duration::from_secs(partial_ttl));
+    let swept = state.partials.sweep(max_age);
+    if swept > 0 {
+        tracing::info!(swept, "removed leftover partial downloads");
+    }
+    let saved = peers_store.list();
+    let _ = event_tx.send(ServiceEvent::SavedPeers(saved.clone())).await;
+    // Messages left in the outbox by an earlier run are still waiting for their peer.
+    for peer in saved {
+        for message in outbox.list(peer.addr) {
+            if message.amends.is_some() {
+                continue;
+            }
+            let _ = event_tx
+                .send(ServiceEvent::MessageQueued {
+                    peer: peer.addr,
+                    id: message.id,
+                    author: message.author,
+                    text: message.body,
+                    reply_to: message.reply_to,
+                })
+                .await;
+        }
+    }
 
+    let heartbeat_secs = state.config.presence.heartbeat_secs;
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(heartbeat_secs.max(1)));
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // A TTL shorter than the sweep interval is checked as often as it lasts.
+    let mut idle_sweep =
+        tokio::time::interval(IDLE_TRANSFER_SWEEP.min(Duration::from_secs(partial_ttl.max(1))));
+    idle_sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     loop {
         tokio::select! {
+            _ = heartbeat.tick(), if heartbeat_secs > 0 => state.announce_presence(),
+            _ = idle_sweep.tick(), if partial_ttl > 0 => {
+                state.expire_idle_transfers(Duration::from_secs(partial_ttl)).await;
+            }
             Some(cmd) = cmd_rx.recv() => {
+                let peer = cmd.target_peer();
                 if let Err(err) = state.handle_command(cmd).await {
-                    let _ = event_tx.send(ServiceEvent::Error { message: format!("{err:#}") }).await;
+                    let _ = event_tx.send(ServiceEvent::Error { peer, message: format!("{err:#}") }).await;
                 }
             }
             Some(signal) = internal_rx.recv() => {
+                let peer = signal.peer();
                 if let Err(err) = state.handle_internal(signal).await {
-                    let _ = event_tx.send(ServiceEvent::Error { message: format!("{err:#}") }).await;
+                    let _ = event_tx.send(ServiceEvent::Error { peer, message: format!("{err:#}") }).await;
                 }
             }
             else => break,
@@ -248,16 +650,36 @@ struct ServiceState {
     internal_tx: mpsc::Sender<InternalSignal>,
     listener: Option<ListenerState>,
     client: Option<ClientState>,
-    connection: Option<ConnectionState>,
+    connections: HashMap<std::net::SocketAddr, ConnectionState>,
+    /// Password of each connection we dialed, kept to redial it if it drops.
+    dialed: HashMap<std::net::SocketAddr, Option<String>>,
+    /// Pending redial of a dropped connection, per peer.
+    reconnects: HashMap<std::net::SocketAddr, JoinHandle<()>>,
+    /// Password of the last attempt to dial each peer, reused when discovery dials it.
+    dial_passwords: HashMap<std::net::SocketAddr, Option<String>>,
+    /// Peers that refused the password we last dialed them with; discovery leaves them
+    /// alone until they are dialed by hand.
+    denied: HashSet<std::net::SocketAddr>,
     username: String,
     listener_password: Option<String>,
     discovery_override: Option<Ipv4Addr>,
     history: Arc<HistoryWriter>,
+    outbox: Arc<OutboxStore>,
+    identity: Arc<Identity>,
     peers: Arc<SavedPeersStore>,
     pending_transfers: Arc<Mutex<HashMap<u64, PreparedTransfer>>>,
     incoming_offers: Arc<Mutex<HashMap<u64, FileOfferNotice>>>,
-    incoming_transfers: Arc<Mutex<HashMap<u64, IncomingTransfer>>>,
+    incoming_transfers: Arc<Mutex<HashMap<u64, Arc<IncomingTransfer>>>>,
+    transfer_controls: Arc<Mutex<HashMap<u64, TransferControl>>>,
+    partials: Arc<PartialStore>,
+    upload_limit: Arc<RateLimiter>,
+    download_limit: Arc<RateLimiter>,
     history_enabled: Arc<AtomicBool>,
+    /// Availability announced to peers.
+    presence: Arc<Mutex<Presence>>,
+    /// Chat messages already shown from each identity key. Outboxes resend whatever was
+    /// not acknowledged, so this spans connections.
+    delivered: Arc<Mutex<HashMap<[u8; 32], SeenIds>>>,
 }
 
 impl ServiceState {
@@ -266,6 +688,8 @@ impl ServiceState {
         event_tx: mpsc::Sender<ServiceEvent>,
         internal_tx: mpsc::Sender<InternalSignal>,
         history: Arc<HistoryWriter>,
+        outbox: Arc<OutboxStore>,
+        identity: Arc<Identity>,
         peers: Arc<SavedPeersStore>,
     ) -> Self {
         let username = config.identity.username.clone();
@@ -273,23 +697,43 @@ impl ServiceState {
         let pending_transfers = Arc::new(Mutex::new(HashMap::new()));
         let incoming_offers = Arc::new(Mutex::new(HashMap::new()));
         let incoming_transfers = Arc::new(Mutex::new(HashMap::new()));
+        let transfer_controls = Arc::new(Mutex::new(HashMap::new()));
+        let partials = Arc::new(PartialStore::new(&config.paths.download_dir));
+        let upload_limit = Arc::new(RateLimiter::new(config.limits.upload_bytes_per_sec));
+        let download_limit = Arc::new(RateLimiter::new(config.limits.download_bytes_per_sec));
         let history_enabled = Arc::new(AtomicBool::new(true));
+        let presence = Arc::new(Mutex::new(Presence {
+            state: config.presence.state,
+            status: config.presence.status.clone(),
+        }));
         Self {
             config,
             event_tx,
             internal_tx,
             listener: None,
             client: None,
-            connection: None,
+            connections: HashMap::new(),
+            dialed: HashMap::new(),
+            reconnects: HashMap::new(),
+            dial_passwords: HashMap::new(),
+            denied: HashSet::new(),
             username,
             listener_password,
             discovery_override: None,
             history,
+            outbox,
+            identity,
             peers,
             pending_transfers,
             incoming_offers,
             incoming_transfers,
+            transfer_controls,
+            partials,
+            upload_limit,
+            download_limit,
             history_enabled,
+            presence,
+            delivered: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -298,9 +742,21 @@ impl ServiceState {
             ServiceCommand::Listen { addr, password } => self.start_listener(addr, password).await,
             ServiceCommand::StopListening => self.stop_listener().await,
             ServiceCommand::Connect { addr, password } => self.connect(addr, password).await,
-            ServiceCommand::Disconnect => self.disconnect().await,
-            ServiceCommand::SendText { text } => self.send_text(text).await,
-            ServiceCommand::SendFile { path } => self.send_file(path).await,
+            ServiceCommand::Disconnect { peer } => match peer {
+                Some(peer) => self.disconnect(peer).await,
+                None => self.disconnect_all().await,
+            },
+            ServiceCommand::SendText {
+                peer,
+                text,
+                reply_to,
+            } => self.send_text(peer, text, reply_to).await,
+            ServiceCommand::AmendMessage {
+                peer,
+                amendment,
+                text,
+            } => self.amend_message(peer, amendment, text).await,
+            ServiceCommand::SendFile { peer, paths } => self.send_file(peer, paths).await,
             ServiceCommand::Discover => self.run_discovery().await,
             ServiceCommand::SetUsername { username } => {
                 self.username = username;
@@ -310,12 +766,69 @@ impl ServiceState {
                 self.discovery_override = target;
                 Ok(())
             }
-            ServiceCommand::AcceptFile { id, path } => self.accept_file(id, path).await,
-            ServiceCommand::DeclineFile { id } => self.decline_file(id).await,
+            ServiceCommand::AcceptFile {
+                peer,
+                id,
+                path,
+                on_collision,
+            } => self.accept_file(peer, id, path, on_collision).await,
+            ServiceCommand::DeclineFile { peer, id } => self.decline_file(peer, id).await,
+            ServiceCommand::CancelTransfer { peer, id } => self.cancel_transfer(peer, id).await,
+            ServiceCommand::PauseTransfer { peer, id } => self.pause_transfer(peer, id, true).await,
+            ServiceCommand::ResumeTransfer { peer, id } => {
+                self.pause_transfer(peer, id, false).await
+            }
             ServiceCommand::SetHistoryEnabled { enabled } => {
                 self.history_enabled.store(enabled, Ordering::Relaxed);
                 Ok(())
             }
+            ServiceCommand::ResetPeerIdentity { addr } => {
+                let list = self.peers.reset_identity(addr)?;
+                self.event_tx
+                    .send(ServiceEvent::SavedPeers(list))
+                    .await
+                    .ok();
+                Ok(())
+            }
+            ServiceCommand::ShowSafetyNumber { peer } => self.show_safety_number(peer).await,
+            ServiceCommand::MarkRead { peer, ids } => {
+                let state = self.connection_for(peer)?;
+                let connection = state.connection.clone();
+                let meta = state.meta.clone();
+                // Sent off the command loop so a slow peer does not hold up other commands.
+                tokio::spawn(async move {
+                    for id in ids {
+                        if let Err(err) = acknowledge(&connection, &meta, id, AckKind::Read).await {
+                            tracing::warn!(?peer, "failed to send read receipt: {err:#}");
+                        }
+                    }
+                });
+                Ok(())
+            }
+            ServiceCommand::SetPeerVerified { addr, verified } => {
+                let list = self.peers.set_verified(addr, verified)?;
+                self.event_tx
+                    .send(ServiceEvent::SavedPeers(list))
+                    .await
+                    .ok();
+                Ok(())
+            }
+            ServiceCommand::SetTyping { peer, typing } => {
+                if let Some(state) = self.connections.get(&peer) {
+                    let connection = state.connection.clone();
+                    let meta = state.meta.clone();
+                    tokio::spawn(async move {
+                        let message = ControlMessage::Typing(typing);
+                        send_sealed_control(&connection, &meta, message).await.ok();
+                    });
+                }
+                Ok(())
+            }
+            ServiceCommand::SetPresence { state, status } => {
+                *self.presence.lock() = Presence { state, status };
+                self.announce_presence();
+                Ok(())
+            }
         }
     }
 
@@ -323,21 +836,110 @@ impl ServiceState {
         match signal {
             InternalSignal::Inbound(connection, peer) => {
                 let required = self.listener_password.clone();
-                self.attach_connection(connection, peer, None, required)
+                self.attach_connection(connection, peer, false, None, required)
                     .await
             }
-            InternalSignal::ConnectionClosed(peer) => {
-                if let Some(state) = &self.connection {
-                    if state.peer == peer {
-                        self.connection = None;
-                        self.event_tx.send(ServiceEvent::Disconnected).await.ok();
+            InternalSignal::ConnectionClosed {
+                peer,
+                stable_id,
+                dropped,
+            } => {
+                let current = self
+                    .connections
+                    .get(&peer)
+                    .map(|state| state.connection.stable_id() == stable_id)
+                    .unwrap_or(false);
+                if current {
+                    let state = self.connections.remove(&peer).expect("current connection");
+                    self.event_tx
+                        .send(ServiceEvent::Disconnected { peer })
+                        .await
+                        .ok();
+                    self.mark_last_seen(peer, &state.meta).await;
+                    if dropped && self.config.reconnect.enabled && self.dialed.contains_key(&peer) {
+                        return self.schedule_reconnect(peer, 1).await;
+                    }
+                    self.dialed.remove(&peer);
+                }
+                Ok(())
+            }
+            InternalSignal::Redialed {
+                peer,
+                attempt,
+                connection,
+            } => {
+                self.reconnects.remove(&peer);
+                let password = match self.dialed.get(&peer) {
+                    Some(password) if !self.connections.contains_key(&peer) => password.clone(),
+                    // Redialing was stopped, or the peer came back on its own.
+                    _ => {
+                        if let Ok(connection) = connection {
+                            connection.close(0u32.into(), b"already connected");
+                        }
+                        return Ok(());
+                    }
+                };
+                match connection {
+                    Ok(connection) => {
+                        self.attach_connection(connection, peer, true, password.clone(), None)
+                            .await?;
+                        self.dialed.insert(peer, password);
+                        Ok(())
+                    }
+                    Err(err) => {
+                        tracing::debug!(?peer, attempt, "reconnect failed: {err:#}");
+                        self.schedule_reconnect(peer, attempt + 1).await
                     }
                 }
+            }
+            InternalSignal::AutoAccept {
+                peer,
+                id,
+                destination,
+            } => self.auto_accept(peer, id, destination).await,
+            InternalSignal::Denied { peer } => {
+                self.denied.insert(peer);
                 Ok(())
             }
         }
     }
 
+    /// Accepts an offer matched by an auto-accept rule without asking.
+    async fn auto_accept(
+        &mut self,
+        peer: std::net::SocketAddr,
+        id: u64,
+        destination: Option<PathBuf>,
+    ) -> Result<()> {
+        let Some(name) = self
+            .incoming_offers
+            .lock()
+            .get(&id)
+            .filter(|offer| offer.peer == peer)
+            .map(|offer| offer.name.clone())
+        else {
+            return Ok(());
+        };
+        let path = destination.unwrap_or_else(|| self.config.paths.download_dir.clone());
+        fs::create_dir_all(&path)
+            .with_context(|| format!("failed to create directory {}", path.display()))?;
+        self.event_tx
+            .send(ServiceEvent::FileAutoAccepted {
+                peer,
+                id,
+                name,
+                path: path.clone(),
+            })
+            .await
+            .ok();
+        // Nobody is around to answer a collision prompt.
+        let on_collision = match self.config.transfer.on_collision {
+            CollisionPolicy::Prompt => CollisionPolicy::Rename,
+            policy => policy,
+        };
+        self.accept_file(peer, id, path, Some(on_collision)).await
+    }
+
     async fn start_listener(
         &mut self,
         addr: std::net::SocketAddr,
@@ -355,24 +957,21 @@ impl ServiceState {
         let endpoint = server.endpoint.clone();
         let internal = self.internal_tx.clone();
         let incoming_task = tokio::spawn(async move {
-            loop {
-                match endpoint.accept().await {
-                    Some(incoming) => match incoming.await {
-                        Ok(connection) => {
-                            let peer = connection.remote_address();
-                            if internal
-                                .send(InternalSignal::Inbound(connection, peer))
-                                .await
-                                .is_err()
-                            {
-                                break;
-                            }
+            while let Some(incoming) = endpoint.accept().await {
+                match incoming.await {
+                    Ok(connection) => {
+                        let peer = connection.remote_address();
+                        if internal
+                            .send(InternalSignal::Inbound(connection, peer))
+                            .await
+                            .is_err()
+                        {
+                            break;
                         }
-                        Err(err) => {
-                            tracing::warn!(%err, "incoming connection failed");
-                        }
-                    },
-                    None => break,
+                    }
+                    Err(err) => {
+                        tracing::warn!(%err, "incoming connection failed");
+                    }
                 }
             }
         });
@@ -415,152 +1014,492 @@ impl ServiceState {
             .send(ServiceEvent::Connecting { peer: addr })
             .await
             .ok();
-        self.disconnect().await?;
+        self.stop_reconnecting(addr);
+        self.dial_passwords.insert(addr, password.clone());
+        self.denied.remove(&addr);
         let server_name = self.config.tls.server_name().to_string();
         let client = self.client_endpoint()?;
         let connection =
             net::connect(client.endpoint, &client.client_config, addr, &server_name).await?;
-        self.attach_connection(connection, addr, password, None)
-            .await
-    }
-
-    async fn disconnect(&mut self) -> Result<()> {
-        if let Some(connection) = self.connection.take() {
-            connection
-                .connection
-                .close(0u32.into(), b"manual disconnect");
-            connection.reader.abort();
-            self.event_tx.send(ServiceEvent::Disconnected).await.ok();
-        }
-        Ok(())
-    }
-
-    async fn send_text(&mut self, text: String) -> Result<()> {
-        let state = self
-            .connection
-            .as_ref()
-            .ok_or_else(|| anyhow!("no active connection"))?;
-        let connection = state.connection.clone();
-        let meta = state.meta.clone();
-        send_text_message(
-            &connection,
-            state.peer,
-            &self.username,
-            text.clone(),
-            &self.config.limits,
-            &self.event_tx,
-            meta,
-            self.history.clone(),
-            self.history_enabled.clone(),
-        )
-        .await?;
-        persist_chat(
-            self.config.paths.chat_log.clone(),
-            format!("{} (you): {}", self.username, text),
-        )
-        .await?;
+        self.attach_connection(connection, addr, true, password.clone(), None)
+            .await?;
+        self.dialed.insert(addr, password);
         Ok(())
     }
 
-    async fn send_file(&mut self, path: PathBuf) -> Result<()> {
-        let state = self
-            .connection
-            .as_ref()
-            .ok_or_else(|| anyhow!("no active connection"))?;
-        let prepared = prepare_transfer(path.clone(), &self.config.limits).await?;
-        let offer = prepared.offer.clone();
-        {
-            let mut pending = self.pending_transfers.lock();
-            pending.insert(offer.id, prepared);
+    /// Redials `peer` after the backoff for `attempt`, unless `[reconnect] max_attempts`
+    /// were already made. Dialing runs in the background and reports back with
+    /// `InternalSignal::Redialed`.
+    async fn schedule_reconnect(&mut self, peer: std::net::SocketAddr, attempt: u32) -> Result<()> {
+        let policy = &self.config.reconnect;
+        if policy.max_attempts != 0 && attempt > policy.max_attempts {
+            self.dialed.remove(&peer);
+            bail!(
+                "gave up reconnecting to {peer} after {} attempts",
+                policy.max_attempts
+            );
+        }
+        let delay = policy.delay(attempt, rand::random::<f64>() * 2.0 - 1.0);
+        let server_name = self.config.tls.server_name().to_string();
+        let client = self.client_endpoint()?;
+        let endpoint = client.endpoint.clone();
+        let client_config = client.client_config;
+        let internal = self.internal_tx.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let connection = net::connect(&endpoint, &client_config, peer, &server_name).await;
+            internal
+                .send(InternalSignal::Redialed {
+                    peer,
+                    attempt,
+                    connection,
+                })
+                .await
+                .ok();
+        });
+        if let Some(previous) = self.reconnects.insert(peer, task) {
+            previous.abort();
         }
-        send_control_message(
-            &state.connection,
-            ControlMessage::FileOffer(FileOffer {
-                id: offer.id,
-                name: offer.name.clone(),
-                original_size: offer.original_size,
-                compressed_size: offer.compressed_size,
-            }),
-        )
-        .await?;
         self.event_tx
-            .send(ServiceEvent::FileTransfer(FileTransferProgress {
-                id: offer.id,
-                name: offer.name,
-                transferred: 0,
-                total: offer.compressed_size,
-                direction: TransferDirection::Outgoing,
-                path: Some(path),
-                completed: false,
-            }))
+            .send(ServiceEvent::Reconnecting { peer, attempt })
             .await
             .ok();
         Ok(())
     }
 
-    async fn run_discovery(&mut self) -> Result<()> {
-        let peers = net::discover_peers(&self.config.discovery, self.discovery_override).await?;
-        if peers.is_empty() {
+    /// Forgets a dialed connection so it is not redialed, and cancels a pending redial.
+    fn stop_reconnecting(&mut self, peer: std::net::SocketAddr) {
+        self.dialed.remove(&peer);
+        if let Some(task) = self.reconnects.remove(&peer) {
+            task.abort();
+        }
+    }
+
+    async fn disconnect(&mut self, peer: std::net::SocketAddr) -> Result<()> {
+        self.stop_reconnecting(peer);
+        if let Some(connection) = self.connections.remove(&peer) {
+            connection
+                .connection
+                .close(0u32.into(), b"manual disconnect");
+            connection.reader.abort();
             self.event_tx
-                .send(ServiceEvent::Discovery(DiscoveryEvent::Completed))
+                .send(ServiceEvent::Disconnected { peer })
                 .await
                 .ok();
-        } else {
-            for peer in peers {
+            self.mark_last_seen(peer, &connection.meta).await;
+        }
+        Ok(())
+    }
+
+    /// Stamps a saved peer as seen now, as its connection is going away.
+    async fn mark_last_seen(&self, peer: std::net::SocketAddr, meta: &ConnectionMeta) {
+        let Some(key) = meta.remote_identity() else {
+            return;
+        };
+        match self.peers.seen(&key, protocol::utc_timestamp()) {
+            Ok(Some(list)) => {
                 self.event_tx
-                    .send(ServiceEvent::Discovery(DiscoveryEvent::PeerFound(peer)))
+                    .send(ServiceEvent::SavedPeers(list))
                     .await
                     .ok();
             }
+            Ok(None) => {}
+            Err(err) => tracing::warn!(?peer, "failed to record last seen: {err:#}"),
+        }
+    }
+
+    /// Sends the current presence to every connected peer; also serves as the heartbeat.
+    fn announce_presence(&self) {
+        let presence = self.presence.lock().clone();
+        for (peer, state) in &self.connections {
+            // Presence is sealed; the handshake announces it to a peer once it completes.
+            if state.meta.remote_identity().is_none() {
+                continue;
+            }
+            let connection = state.connection.clone();
+            let meta = state.meta.clone();
+            let presence = presence.clone();
+            let peer = *peer;
+            tokio::spawn(async move {
+                let message = ControlMessage::Presence(presence);
+                if let Err(err) = send_sealed_control(&connection, &meta, message).await {
+                    tracing::debug!(?peer, "failed to send presence: {err:#}");
+                }
+            });
+        }
+    }
+
+    async fn disconnect_all(&mut self) -> Result<()> {
+        let mut peers: Vec<_> = self.connections.keys().copied().collect();
+        peers.extend(self.reconnects.keys().copied());
+        for peer in peers {
+            self.disconnect(peer).await?;
+        }
+        Ok(())
+    }
+
+    fn connection_for(&self, peer: std::net::SocketAddr) -> Result<&ConnectionState> {
+        self.connections
+            .get(&peer)
+            .ok_or_else(|| anyhow!("no active connection to {peer}"))
+    }
+
+    async fn show_safety_number(&mut self, peer: std::net::SocketAddr) -> Result<()> {
+        let saved = self.peers.find(peer);
+        let live = self.connections.get(&peer).map(|state| &state.meta);
+        let remote = live
+            .and_then(|meta| meta.remote_identity())
+            .or_else(|| self.peers.pinned_identity(peer))
+            .ok_or_else(|| anyhow!("no identity key known for {peer} yet"))?;
+        let name = live
+            .map(|meta| meta.name())
+            .or_else(|| saved.as_ref().map(|entry| entry.name.clone()))
+            .unwrap_or_else(|| peer.to_string());
+        let verified = saved.is_some_and(|entry| {
+            entry.verified && entry.identity_key == Some(crypto::key_to_hex(&remote))
+        });
+        self.event_tx
+            .send(ServiceEvent::SafetyNumber {
+                peer,
+                name,
+                number: crypto::safety_number(&self.identity.public_key(), &remote),
+                verified,
+            })
+            .await
+            .ok();
+        Ok(())
+    }
+
+    async fn send_text(
+        &mut self,
+        peer: std::net::SocketAddr,
+        text: String,
+        reply_to: Option<u64>,
+    ) -> Result<()> {
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            bail!("empty messages are ignored");
+        }
+        self.send_chat(peer, trimmed.to_string(), None, reply_to)
+            .await?;
+        persist_chat(
+            self.config.paths.chat_log.clone(),
+            format!("{} (you): {}", self.username, text),
+        )
+        .await
+    }
+
+    async fn amend_message(
+        &mut self,
+        peer: std::net::SocketAddr,
+        amendment: Amendment,
+        text: String,
+    ) -> Result<()> {
+        let body = match amendment.kind {
+            AmendmentKind::Edit if text.trim().is_empty() => {
+                bail!("an edit cannot empty a message; delete it instead")
+            }
+            AmendmentKind::Edit | AmendmentKind::React => text.trim().to_string(),
+            AmendmentKind::Delete => String::new(),
+        };
+        self.send_chat(peer, body, Some(amendment), None).await
+    }
+
+    /// Sends a chat message, or an amendment to one, through the outbox of `peer`.
+    async fn send_chat(
+        &mut self,
+        peer: std::net::SocketAddr,
+        body: String,
+        amends: Option<Amendment>,
+        reply_to: Option<u64>,
+    ) -> Result<()> {
+        let live = self
+            .connections
+            .get(&peer)
+            .map(|state| (state.connection.clone(), state.meta.clone()));
+        if live.is_none() && self.peers.find(peer).is_none() {
+            bail!("no active connection to {peer}");
+        }
+        if body.len() > self.config.limits.max_message_bytes {
+            bail!("message length exceeds configured limit");
+        }
+        let message = QueuedMessage {
+            id: rand::random(),
+            author: self.username.clone(),
+            body,
+            timestamp: protocol::utc_timestamp(),
+            amends,
+            reply_to,
+        };
+        // Stays in the outbox until the peer acknowledges it, so a message lost with the
+        // connection goes out again after the next handshake.
+        self.outbox.push(peer, &message)?;
+        // Sealing needs the session key, so before the handshake is done the message just
+        // waits for the outbox flush that follows it. The write itself runs off the command
+        // loop so a slow peer cannot hold up other commands.
+        let sent = match live {
+            Some((connection, meta)) if meta.remote_identity().is_some() => {
+                match seal_queued(&meta, &message) {
+                    Ok(wire) => {
+                        tokio::spawn(async move {
+                            if let Err(err) = send_wire_message(&connection, wire).await {
+                                tracing::warn!(?peer, "keeping message in the outbox: {err:#}");
+                            }
+                        });
+                        true
+                    }
+                    Err(err) => {
+                        tracing::warn!(?peer, "keeping message in the outbox: {err:#}");
+                        false
+                    }
+                }
+            }
+            _ => false,
+        };
+        let event = if let Some(amendment) = amends {
+            ServiceEvent::MessageAmended {
+                peer,
+                author: message.author.clone(),
+                amendment,
+                text: message.body.clone(),
+                outgoing: true,
+            }
+        } else if sent {
+            ServiceEvent::MessageSent {
+                peer,
+                id: message.id,
+                author: message.author.clone(),
+                text: message.body.clone(),
+                reply_to,
+            }
+        } else {
+            ServiceEvent::MessageQueued {
+                peer,
+                id: message.id,
+                author: message.author.clone(),
+                text: message.body.clone(),
+                reply_to,
+            }
+        };
+        self.event_tx.send(event).await.ok();
+        if self.history_enabled.load(Ordering::Relaxed) {
+            self.history
+                .record(
+                    peer,
+                    HistoryEntry {
+                        timestamp: message.timestamp,
+                        outgoing: true,
+                        author: message.author,
+                        text: message.body,
+                        id: message.id,
+                        amends,
+                        reply_to,
+                    },
+                )
+                .ok();
+        }
+        Ok(())
+    }
+
+    async fn send_file(&mut self, peer: std::net::SocketAddr, paths: Vec<PathBuf>) -> Result<()> {
+        let state = self.connection_for(peer)?;
+        let connection = state.connection.clone();
+        let meta = state.meta.clone();
+        let limits = self.config.limits.clone();
+        let forced = self.config.transfer.codec.forced();
+        let streams = self.config.transfer.streams;
+        let pending = self.pending_transfers.clone();
+        let controls = self.transfer_controls.clone();
+        let event_tx = self.event_tx.clone();
+        // Waiting for the handshake and sampling the files would hold up every other command.
+        tokio::spawn(async move {
+            let offered: Result<()> = async {
+                // The codec depends on what the peer announced in its hello.
+                meta.wait_established().await?;
+                let prepared =
+                    prepare_transfer(paths, &limits, forced, meta.codecs(), streams).await?;
+                let offer = prepared.offer.clone();
+                let path = prepared.original_path.clone();
+                pending.lock().insert(offer.id, prepared);
+                controls
+                    .lock()
+                    .insert(offer.id, TransferControl::new(peer, offer.name.clone()));
+                send_control_message(&connection, ControlMessage::FileOffer(offer.clone())).await?;
+                if let Some(ttl) = offer_ttl(&limits) {
+                    spawn_offer_withdrawal(
+                        connection.clone(),
+                        pending.clone(),
+                        controls.clone(),
+                        event_tx.clone(),
+                        peer,
+                        offer.id,
+                        ttl + OFFER_EXPIRY_GRACE,
+                    );
+                }
+                event_tx
+                    .send(ServiceEvent::FileTransfer(FileTransferProgress {
+                        peer,
+                        id: offer.id,
+                        name: offer.name,
+                        transferred: 0,
+                        total: offer.original_size,
+                        direction: TransferDirection::Outgoing,
+                        path: Some(path),
+                        entry: None,
+                        bytes_per_sec: None,
+                        eta: None,
+                        completed: false,
+                    }))
+                    .await
+                    .ok();
+                Ok(())
+            }
+            .await;
+            if let Err(err) = offered {
+                event_tx
+                    .send(ServiceEvent::Error {
+                        peer: Some(peer),
+                        message: format!("{err:#}"),
+                    })
+                    .await
+                    .ok();
+            }
+        });
+        Ok(())
+    }
+
+    async fn run_discovery(&mut self) -> Result<()> {
+        let peers = net::discover_peers(&self.config.discovery, self.discovery_override).await?;
+        for peer in &peers {
             self.event_tx
-                .send(ServiceEvent::Discovery(DiscoveryEvent::Completed))
+                .send(ServiceEvent::Discovery(DiscoveryEvent::PeerFound(*peer)))
                 .await
                 .ok();
         }
+        self.event_tx
+            .send(ServiceEvent::Discovery(DiscoveryEvent::Completed))
+            .await
+            .ok();
+        // A rediscovered peer with queued messages is dialed so the outbox can be flushed,
+        // with the password it was last dialed with.
+        for peer in peers {
+            if self.connections.contains_key(&peer)
+                || self.denied.contains(&peer)
+                || self.outbox.list(peer).is_empty()
+            {
+                continue;
+            }
+            let password = self.dial_passwords.get(&peer).cloned().flatten();
+            if let Err(err) = self.connect(peer, password).await {
+                self.event_tx
+                    .send(ServiceEvent::Error {
+                        peer: Some(peer),
+                        message: format!("failed to reach {peer} for queued messages: {err:#}"),
+                    })
+                    .await
+                    .ok();
+            }
+        }
         Ok(())
     }
 
-    async fn accept_file(&mut self, id: u64, requested: PathBuf) -> Result<()> {
-        let state = self
-            .connection
-            .as_ref()
-            .ok_or_else(|| anyhow!("no active connection"))?;
+    async fn accept_file(
+        &mut self,
+        peer: std::net::SocketAddr,
+        id: u64,
+        requested: PathBuf,
+        on_collision: Option<CollisionPolicy>,
+    ) -> Result<()> {
+        let state = self.connection_for(peer)?;
+        let remote_identity = state
+            .meta
+            .remote_identity()
+            .ok_or_else(|| anyhow!("secure channel with {peer} not established yet"))?;
         let offer = {
             let mut pending = self.incoming_offers.lock();
-            pending.remove(&id)
+            match pending.get(&id) {
+                Some(offer) if offer.peer == peer => pending.remove(&id),
+                _ => None,
+            }
         }
-        .ok_or_else(|| anyhow!("no pending offer for id {id}"))?;
+        .ok_or_else(|| anyhow!("no pending offer for id {id} from {peer}"))?;
+        // A single file may be saved under a directory; trees are always recreated inside
+        // the chosen directory.
         let mut target = requested;
         let hint_dir = target
             .to_string_lossy()
             .ends_with(std::path::MAIN_SEPARATOR);
-        if hint_dir || target.is_dir() {
+        if offer.is_single_file() && (hint_dir || target.is_dir()) {
             target = target.join(&offer.name);
         }
-        if let Some(parent) = target.parent() {
-            tokio::fs::create_dir_all(parent).await.ok();
+        let on_collision = on_collision.unwrap_or(self.config.transfer.on_collision);
+        if on_collision == CollisionPolicy::Prompt {
+            let existing = manifest::collisions(&offer.entries, &target);
+            if !existing.is_empty() {
+                let name = offer.name.clone();
+                self.incoming_offers.lock().insert(id, offer);
+                self.event_tx
+                    .send(ServiceEvent::FileCollision {
+                        peer,
+                        id,
+                        name,
+                        path: target,
+                        existing,
+                    })
+                    .await
+                    .ok();
+                return Ok(());
+            }
         }
-        let temp_path = spawn_blocking(|| -> Result<PathBuf> {
-            let path = NamedTempFile::new()
-                .context("failed to create temp file")?
-                .into_temp_path()
-                .keep()
-                .context("failed to persist temp receive file")?;
-            Ok(path)
-        })
-        .await??;
-
-        {
-            let mut incoming = self.incoming_transfers.lock();
-            incoming.insert(
+        if let Err(reason) = ensure_disk_space(
+            [self.partials.dir.as_path(), target.as_path()],
+            offer.original_size,
+            self.config.limits.reserve_disk_bytes,
+        ) {
+            abort_download(
+                &state.connection,
+                &self.partials,
+                &self.event_tx,
+                peer,
                 id,
-                IncomingTransfer {
-                    target_path: target.clone(),
-                    temp_path,
-                    original_name: offer.name.clone(),
-                },
-            );
+                offer.name,
+                reason,
+            )
+            .await;
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await.ok();
         }
+        let partial = PartialState {
+            id,
+            name: offer.name.clone(),
+            target_path: target.clone(),
+            original_size: offer.original_size,
+            entries: offer.entries.clone(),
+            on_collision,
+            peer_identity: crypto::key_to_hex(&remote_identity),
+            segment_bytes: offer.segment_bytes,
+        };
+        let staged = self.partials.create(&partial)?;
+        let segments = segment_ranges(offer.original_size, offer.segment_bytes)
+            .into_iter()
+            .map(|range| SegmentState::Missing {
+                offset: range.start,
+            })
+            .collect();
+        let transfer = IncomingTransfer::new(
+            &partial,
+            staged,
+            segments,
+            self.config.limits.transfer_download_bytes_per_sec,
+        );
+        self.incoming_transfers
+            .lock()
+            .insert(id, Arc::new(transfer));
+        self.transfer_controls
+            .lock()
+            .insert(id, TransferControl::new(peer, offer.name.clone()));
         send_control_message(
             &state.connection,
             ControlMessage::FileAccept(FileAccept { id }),
@@ -568,12 +1507,16 @@ impl ServiceState {
         .await?;
         self.event_tx
             .send(ServiceEvent::FileTransfer(FileTransferProgress {
+                peer,
                 id,
                 name: offer.name.clone(),
                 transferred: 0,
-                total: offer.compressed_size,
+                total: offer.original_size,
                 direction: TransferDirection::Incoming,
                 path: Some(target.clone()),
+                entry: None,
+                bytes_per_sec: None,
+                eta: None,
                 completed: false,
             }))
             .await
@@ -581,14 +1524,14 @@ impl ServiceState {
         Ok(())
     }
 
-    async fn decline_file(&mut self, id: u64) -> Result<()> {
-        let state = self
-            .connection
-            .as_ref()
-            .ok_or_else(|| anyhow!("no active connection"))?;
+    async fn decline_file(&mut self, peer: std::net::SocketAddr, id: u64) -> Result<()> {
+        let state = self.connection_for(peer)?;
         let existed = {
             let mut pending = self.incoming_offers.lock();
-            pending.remove(&id)
+            match pending.get(&id) {
+                Some(offer) if offer.peer == peer => pending.remove(&id),
+                _ => None,
+            }
         };
         if existed.is_some() {
             send_control_message(
@@ -596,6 +1539,7 @@ impl ServiceState {
                 ControlMessage::FileReject(FileReject {
                     id,
                     reason: Some("Recipient declined".into()),
+                    expired: false,
                 }),
             )
             .await?;
@@ -603,29 +1547,149 @@ impl ServiceState {
         Ok(())
     }
 
+    async fn cancel_transfer(&mut self, peer: std::net::SocketAddr, id: u64) -> Result<()> {
+        let control = {
+            let mut controls = self.transfer_controls.lock();
+            match controls.get(&id) {
+                Some(control) if control.peer == peer => controls.remove(&id),
+                _ => None,
+            }
+        }
+        .ok_or_else(|| anyhow!("no transfer {id} with {peer} in progress"))?;
+        // Stops the task moving the transfer, if any, before its files go away.
+        control.cancel();
+        self.pending_transfers.lock().remove(&id);
+        self.incoming_transfers.lock().remove(&id);
+        self.partials.remove(id);
+        if let Some(state) = self.connections.get(&peer) {
+            send_control_message(
+                &state.connection,
+                ControlMessage::FileControl(FileControl {
+                    id,
+                    action: TransferAction::Cancel,
+                }),
+            )
+            .await
+            .ok();
+        }
+        self.event_tx
+            .send(ServiceEvent::TransferAborted {
+                peer,
+                id,
+                name: control.name,
+                reason: AbortReason::Cancelled { by_peer: false },
+            })
+            .await
+            .ok();
+        Ok(())
+    }
+
+    /// Lets go of accepted outgoing transfers that have not moved for `ttl`, so a receiver
+    /// that never comes back does not keep them in memory for the life of the process.
+    async fn expire_idle_transfers(&mut self, ttl: Duration) {
+        for transfer in take_idle_transfers(&self.pending_transfers, ttl) {
+            let id = transfer.offer.id;
+            let Some(control) = self.transfer_controls.lock().remove(&id) else {
+                continue;
+            };
+            control.cancel();
+            let peer = control.peer;
+            if let Some(state) = self.connections.get(&peer) {
+                send_control_message(
+                    &state.connection,
+                    ControlMessage::FileControl(FileControl {
+                        id,
+                        action: TransferAction::Cancel,
+                    }),
+                )
+                .await
+                .ok();
+            }
+            self.event_tx
+                .send(ServiceEvent::TransferAborted {
+                    peer,
+                    id,
+                    name: transfer.offer.name,
+                    reason: AbortReason::Idle {
+                        secs: ttl.as_secs(),
+                    },
+                })
+                .await
+                .ok();
+        }
+    }
+
+    async fn pause_transfer(
+        &mut self,
+        peer: std::net::SocketAddr,
+        id: u64,
+        paused: bool,
+    ) -> Result<()> {
+        let control = self
+            .transfer_controls
+            .lock()
+            .get(&id)
+            .filter(|control| control.peer == peer)
+            .cloned()
+            .ok_or_else(|| anyhow!("no transfer {id} with {peer} in progress"))?;
+        let state = self.connection_for(peer)?;
+        if !control.set_paused(paused) {
+            return Ok(());
+        }
+        // Only the sender holds its stream back; a paused receiver asks it to.
+        let action = if paused {
+            TransferAction::Pause
+        } else {
+            TransferAction::Resume
+        };
+        send_control_message(
+            &state.connection,
+            ControlMessage::FileControl(FileControl { id, action }),
+        )
+        .await?;
+        self.event_tx
+            .send(ServiceEvent::TransferPaused { peer, id, paused })
+            .await
+            .ok();
+        Ok(())
+    }
+
     async fn attach_connection(
         &mut self,
         connection: quinn::Connection,
         peer: std::net::SocketAddr,
+        dialed: bool,
         outgoing_password: Option<String>,
         required_password: Option<String>,
     ) -> Result<()> {
-        self.disconnect().await?;
+        self.disconnect(peer).await?;
         let event_tx = self.event_tx.clone();
         let chat_log = self.config.paths.chat_log.clone();
         let internal = self.internal_tx.clone();
         let reader_connection = connection.clone();
-        let meta = ConnectionMeta::new("???");
+        let closed_connection = connection.clone();
+        let stable_id = connection.stable_id();
+        let meta = ConnectionMeta::new("???", self.identity.clone());
         let peer_ctx = PeerContext {
             required_password: required_password.clone(),
+            dialed,
             meta: meta.clone(),
             history: self.history.clone(),
             history_enabled: self.history_enabled.clone(),
+            outbox: self.outbox.clone(),
             peers: self.peers.clone(),
             pending_transfers: self.pending_transfers.clone(),
             incoming_offers: self.incoming_offers.clone(),
             incoming_transfers: self.incoming_transfers.clone(),
+            transfer_controls: self.transfer_controls.clone(),
+            partials: self.partials.clone(),
+            upload_limit: self.upload_limit.clone(),
+            download_limit: self.download_limit.clone(),
             limits: self.config.limits.clone(),
+            auto_accept: self.config.transfer.auto_accept.clone().into(),
+            presence: self.presence.clone(),
+            delivered: self.delivered.clone(),
+            internal_tx: self.internal_tx.clone(),
         };
         let reader = tokio::spawn(async move {
             if let Err(err) =
@@ -633,19 +1697,34 @@ impl ServiceState {
             {
                 let _ = event_tx
                     .send(ServiceEvent::Error {
+                        peer: Some(peer),
                         message: format!("connection reader error: {err:#}"),
                     })
                     .await;
             }
-            let _ = internal.send(InternalSignal::ConnectionClosed(peer)).await;
+            // Only a connection lost to the network is worth redialing; one closed on purpose
+            // by either side is not.
+            let dropped = matches!(
+                closed_connection.close_reason(),
+                Some(quinn::ConnectionError::TimedOut | quinn::ConnectionError::Reset)
+            );
+            let _ = internal
+                .send(InternalSignal::ConnectionClosed {
+                    peer,
+                    stable_id,
+                    dropped,
+                })
+                .await;
         });
 
-        self.connection = Some(ConnectionState {
+        self.connections.insert(
             peer,
-            connection: connection.clone(),
-            reader,
-            meta: meta.clone(),
-        });
+            ConnectionState {
+                connection: connection.clone(),
+                reader,
+                meta: meta.clone(),
+            },
+        );
         self.event_tx
             .send(ServiceEvent::Connected {
                 peer,
@@ -653,14 +1732,9 @@ impl ServiceState {
             })
             .await
             .ok();
-        send_hello(
-            &connection,
-            &self.username,
-            outgoing_password,
-            meta.public_key(),
-        )
-        .await
-        .ok();
+        send_hello(&connection, meta.hello(&self.username, outgoing_password))
+            .await
+            .ok();
         Ok(())
     }
 
@@ -688,7 +1762,7 @@ impl ServiceState {
     }
 
     async fn shutdown(&mut self) {
-        self.disconnect().await.ok();
+        self.disconnect_all().await.ok();
         self.stop_listener().await.ok();
     }
 }
@@ -711,7 +1785,6 @@ struct ClientState {
 }
 
 struct ConnectionState {
-    peer: std::net::SocketAddr,
     connection: quinn::Connection,
     reader: JoinHandle<()>,
     meta: ConnectionMeta,
@@ -719,20 +1792,65 @@ struct ConnectionState {
 
 enum InternalSignal {
     Inbound(quinn::Connection, std::net::SocketAddr),
-    ConnectionClosed(std::net::SocketAddr),
+    ConnectionClosed {
+        peer: std::net::SocketAddr,
+        stable_id: usize,
+        /// The connection was lost rather than closed by either side.
+        dropped: bool,
+    },
+    /// A scheduled redial of `peer` finished.
+    Redialed {
+        peer: std::net::SocketAddr,
+        attempt: u32,
+        connection: Result<quinn::Connection>,
+    },
+    /// Offer `id` matched an auto-accept rule; `None` saves it in the download directory.
+    AutoAccept {
+        peer: std::net::SocketAddr,
+        id: u64,
+        destination: Option<PathBuf>,
+    },
+    /// `peer` refused our hello, usually over its password.
+    Denied {
+        peer: std::net::SocketAddr,
+    },
+}
+
+impl InternalSignal {
+    fn peer(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            InternalSignal::Inbound(_, peer)
+            | InternalSignal::ConnectionClosed { peer, .. }
+            | InternalSignal::Redialed { peer, .. }
+            | InternalSignal::AutoAccept { peer, .. }
+            | InternalSignal::Denied { peer } => Some(*peer),
+        }
+    }
 }
 
 #[derive(Clone)]
 struct ConnectionMeta {
     name: Arc<Mutex<String>>,
     crypto: Arc<CryptoCtx>,
+    identity: Arc<Identity>,
+    established: Arc<Notify>,
+    codecs: Arc<Mutex<Vec<Codec>>>,
+    /// Sequence number of the next chat message we send.
+    next_seq: Arc<AtomicU64>,
+    /// Set by the peer's first hello; any later one is refused.
+    greeted: Arc<AtomicBool>,
 }
 
 impl ConnectionMeta {
-    fn new(initial: &str) -> Self {
+    fn new(initial: &str, identity: Arc<Identity>) -> Self {
         Self {
             name: Arc::new(Mutex::new(initial.to_string())),
             crypto: Arc::new(CryptoCtx::new()),
+            identity,
+            established: Arc::new(Notify::new()),
+            codecs: Arc::new(Mutex::new(Vec::new())),
+            next_seq: Arc::new(AtomicU64::new(0)),
+            greeted: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -744,78 +1862,95 @@ impl ConnectionMeta {
         self.name.lock().clone()
     }
 
-    fn public_key(&self) -> [u8; 32] {
-        self.crypto.public_key()
+    /// Builds our signed hello for this connection.
+    fn hello(&self, username: &str, password: Option<String>) -> HelloMessage {
+        let public_key = self.crypto.public_key();
+        HelloMessage {
+            username: username.to_string(),
+            password,
+            public_key,
+            identity_key: self.identity.public_key(),
+            signature: self.identity.sign_hello(username, &public_key),
+            codecs: Codec::SUPPORTED.to_vec(),
+        }
+    }
+
+    fn set_codecs(&self, codecs: Vec<Codec>) {
+        *self.codecs.lock() = codecs;
+    }
+
+    /// Codecs the peer announced it can decode.
+    fn codecs(&self) -> Vec<Codec> {
+        self.codecs.lock().clone()
     }
 
-    fn derive(&self, remote: &[u8]) -> Result<bool> {
-        self.crypto.accept_remote(remote)
+    fn derive(&self, hello: &HelloMessage) -> Result<bool> {
+        let derived = self
+            .crypto
+            .accept_remote(&self.identity.public_key(), hello)?;
+        self.established.notify_waiters();
+        Ok(derived)
     }
 
-    fn shared_key(&self) -> Option<[u8; 32]> {
-        self.crypto.shared_key()
+    /// Waits until the remote hello has been processed. Streams are handled concurrently, so a
+    /// request can arrive before the handshake that authorises it.
+    async fn wait_established(&self) -> Result<()> {
+        let wait = async {
+            loop {
+                let notified = self.established.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if self.remote_identity().is_some() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, wait)
+            .await
+            .map_err(|_| anyhow!("secure channel not established yet"))
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<CipherFrame> {
+        self.crypto.seal(plaintext)
+    }
+
+    fn open(&self, frame: &CipherFrame) -> Result<Vec<u8>> {
+        self.crypto.open(frame)
+    }
+
+    fn file_cipher(&self, id: u64, segment: u32, attempt: u32) -> Result<FileCipher> {
+        self.crypto.file_cipher(id, segment, attempt)
+    }
+
+    fn remote_identity(&self) -> Option<[u8; 32]> {
+        self.crypto.remote_identity()
     }
 }
 
 #[derive(Clone)]
 struct PeerContext {
     required_password: Option<String>,
+    /// We dialed the connection, so `peer` is an address the peer listens on.
+    dialed: bool,
     meta: ConnectionMeta,
     history: Arc<HistoryWriter>,
     history_enabled: Arc<AtomicBool>,
+    outbox: Arc<OutboxStore>,
     peers: Arc<SavedPeersStore>,
     pending_transfers: Arc<Mutex<HashMap<u64, PreparedTransfer>>>,
     incoming_offers: Arc<Mutex<HashMap<u64, FileOfferNotice>>>,
-    incoming_transfers: Arc<Mutex<HashMap<u64, IncomingTransfer>>>,
+    incoming_transfers: Arc<Mutex<HashMap<u64, Arc<IncomingTransfer>>>>,
+    transfer_controls: Arc<Mutex<HashMap<u64, TransferControl>>>,
+    partials: Arc<PartialStore>,
+    /// Caps shared by every transfer of every connection.
+    upload_limit: Arc<RateLimiter>,
+    download_limit: Arc<RateLimiter>,
     limits: LimitsConfig,
-}
-
-struct CryptoCtx {
-    inner: Mutex<CryptoState>,
-}
-
-struct CryptoState {
-    secret: StaticSecret,
-    public: PublicKey,
-    shared: Option<[u8; 32]>,
-}
-
-impl CryptoCtx {
-    fn new() -> Self {
-        let secret = StaticSecret::new(OsRng);
-        let public = PublicKey::from(&secret);
-        Self {
-            inner: Mutex::new(CryptoState {
-                secret,
-                public,
-                shared: None,
-            }),
-        }
-    }
-
-    fn public_key(&self) -> [u8; 32] {
-        self.inner.lock().public.to_bytes()
-    }
-
-    fn accept_remote(&self, remote: &[u8]) -> Result<bool> {
-        let mut inner = self.inner.lock();
-        if inner.shared.is_some() {
-            return Ok(false);
-        }
-        if remote.len() != 32 {
-            bail!("invalid remote public key");
-        }
-        let mut buf = [0u8; 32];
-        buf.copy_from_slice(remote);
-        let remote = PublicKey::from(buf);
-        let shared = inner.secret.diffie_hellman(&remote);
-        inner.shared = Some(shared.to_bytes());
-        Ok(true)
-    }
-
-    fn shared_key(&self) -> Option<[u8; 32]> {
-        self.inner.lock().shared
-    }
+    auto_accept: Arc<[AutoAcceptRule]>,
+    presence: Arc<Mutex<Presence>>,
+    delivered: Arc<Mutex<HashMap<[u8; 32], SeenIds>>>,
+    internal_tx: mpsc::Sender<InternalSignal>,
 }
 
 /// Runs a headless listener in CLI mode.
@@ -861,27 +1996,44 @@ pub async fn run_cli_message(config: &AppConfig, cmd: SendCommand) -> Result<()>
             password: None,
         })
         .await?;
-    let mut sent = false;
+    let mut requested = false;
+    let mut sent = None;
+    let mut acked = Vec::new();
+    let mut delivered = false;
     while let Some(event) = service.next_event().await {
         match event {
-            ServiceEvent::Connected { .. } => {
+            // Chat is sealed with the session key, so wait for the handshake.
+            ServiceEvent::PeerProfile { peer, .. } => {
                 service
                     .send(ServiceCommand::SendText {
+                        peer,
                         text: cmd.text.clone(),
+                        reply_to: None,
                     })
                     .await?;
+                requested = true;
             }
-            ServiceEvent::MessageSent { .. } => {
-                sent = true;
-                service.send(ServiceCommand::Disconnect).await.ok();
-                break;
+            ServiceEvent::MessageSent { id, .. } => sent = Some(id),
+            // Messages queued by earlier runs are announced before connecting.
+            ServiceEvent::MessageQueued { .. } if requested => {
+                bail!("message could not be sent; it stays queued for the next connection")
             }
-            ServiceEvent::Error { message } => bail!(message),
-            ServiceEvent::Disconnected => break,
+            ServiceEvent::MessageDelivered { id, .. } => acked.push(id),
+            ServiceEvent::Error { message, .. } => bail!(message),
+            ServiceEvent::Disconnected { .. } => break,
             _ => {}
         }
+        // Only exit once the peer confirmed it has the message.
+        if sent.is_some_and(|id| acked.contains(&id)) {
+            delivered = true;
+            service
+                .send(ServiceCommand::Disconnect { peer: None })
+                .await
+                .ok();
+            break;
+        }
     }
-    if !sent {
+    if !delivered {
         bail!("message delivery incomplete");
     }
     Ok(())
@@ -899,20 +2051,26 @@ pub async fn run_cli_file_send(config: &AppConfig, cmd: SendFileCommand) -> Resu
     let mut completed = false;
     while let Some(event) = service.next_event().await {
         match event {
-            ServiceEvent::Connected { .. } => {
+            ServiceEvent::Connected { peer, .. } => {
                 service
                     .send(ServiceCommand::SendFile {
-                        path: cmd.path.clone(),
+                        peer,
+                        paths: cmd.paths.clone(),
                     })
                     .await?;
             }
             ServiceEvent::FileTransfer(progress) if progress.completed => {
                 completed = true;
-                service.send(ServiceCommand::Disconnect).await.ok();
+                service
+                    .send(ServiceCommand::Disconnect {
+                        peer: Some(progress.peer),
+                    })
+                    .await
+                    .ok();
                 break;
             }
-            ServiceEvent::Error { message } => bail!(message),
-            ServiceEvent::Disconnected => break,
+            ServiceEvent::Error { message, .. } => bail!(message),
+            ServiceEvent::Disconnected { .. } => break,
             _ => {}
         }
     }
@@ -922,119 +2080,120 @@ pub async fn run_cli_file_send(config: &AppConfig, cmd: SendFileCommand) -> Resu
     Ok(())
 }
 
-async fn send_text_message(
-    connection: &quinn::Connection,
-    peer: std::net::SocketAddr,
-    author: &str,
-    text: String,
-    limits: &LimitsConfig,
-    event_tx: &mpsc::Sender<ServiceEvent>,
-    meta: ConnectionMeta,
-    history: Arc<HistoryWriter>,
-    history_enabled: Arc<AtomicBool>,
-) -> Result<()> {
-    let trimmed = text.trim();
-    if trimmed.is_empty() {
-        bail!("empty messages are ignored");
-    }
-    if trimmed.len() > limits.max_message_bytes {
-        bail!("message length exceeds configured limit");
-    }
-    let text_payload = TextMessage {
-        id: rand::random(),
-        author: author.to_string(),
-        body: trimmed.to_string(),
-        timestamp: protocol::utc_timestamp(),
-    };
-    let encrypted = encrypt_text(&meta, &text_payload)?;
+async fn send_hello(connection: &quinn::Connection, hello: HelloMessage) -> Result<()> {
     let mut stream = connection
         .open_uni()
         .await
-        .context("failed opening unidirectional stream")?;
-    protocol::write_message(&mut stream, &encrypted).await?;
+        .context("failed to open control stream")?;
+    let message = WireMessage::Control(ControlMessage::Hello(hello));
+    protocol::write_message(&mut stream, &message).await?;
     let _ = stream.finish();
-    event_tx
-        .send(ServiceEvent::MessageSent {
-            author: author.to_string(),
-            text: trimmed.to_string(),
-        })
-        .await
-        .ok();
-    if history_enabled.load(Ordering::Relaxed) {
-        history
-            .record(
-                peer,
-                HistoryEntry {
-                    timestamp: protocol::utc_timestamp(),
-                    outgoing: true,
-                    author: author.to_string(),
-                    text: trimmed.to_string(),
-                },
-            )
-            .ok();
-    }
     Ok(())
 }
 
-async fn send_hello(
+async fn send_control_message(
+    connection: &quinn::Connection,
+    message: ControlMessage,
+) -> Result<()> {
+    send_wire_message(connection, WireMessage::Control(message)).await
+}
+
+/// Sends a control message sealed with the session ratchet, for those that must not be
+/// forged on the path.
+async fn send_sealed_control(
     connection: &quinn::Connection,
-    username: &str,
-    password: Option<String>,
-    public_key: [u8; 32],
+    meta: &ConnectionMeta,
+    message: ControlMessage,
 ) -> Result<()> {
+    let plaintext = bincode::serde::encode_to_vec(&message, bincode::config::standard())
+        .context("failed to encode control message")?;
+    send_wire_message(
+        connection,
+        WireMessage::SealedControl(meta.seal(&plaintext)?),
+    )
+    .await
+}
+
+async fn send_wire_message(connection: &quinn::Connection, message: WireMessage) -> Result<()> {
     let mut stream = connection
         .open_uni()
         .await
         .context("failed to open control stream")?;
-    let message = WireMessage::Control(ControlMessage::Hello(HelloMessage {
-        username: username.to_string(),
-        password,
-        public_key,
-    }));
     protocol::write_message(&mut stream, &message).await?;
     let _ = stream.finish();
     Ok(())
 }
 
-async fn send_control_message(
+/// Acknowledges chat message or transfer `id` to the peer that sent it. Acks are sealed
+/// like chat, so nobody on the path can fake a delivery.
+async fn acknowledge(
     connection: &quinn::Connection,
-    message: ControlMessage,
+    meta: &ConnectionMeta,
+    id: u64,
+    kind: AckKind,
 ) -> Result<()> {
-    let mut stream = connection
-        .open_uni()
-        .await
-        .context("failed to open control stream")?;
-    protocol::write_message(&mut stream, &WireMessage::Control(message)).await?;
-    let _ = stream.finish();
+    let plaintext = bincode::serde::encode_to_vec(Ack { id, kind }, bincode::config::standard())
+        .context("failed to encode acknowledgement")?;
+    send_wire_message(connection, WireMessage::SealedAck(meta.seal(&plaintext)?)).await
+}
+
+/// Seals a chat message with the next sequence number of the connection.
+fn seal_queued(meta: &ConnectionMeta, message: &QueuedMessage) -> Result<WireMessage> {
+    let text = TextMessage {
+        id: message.id,
+        seq: meta.next_seq.fetch_add(1, Ordering::Relaxed),
+        author: message.author.clone(),
+        body: message.body.clone(),
+        timestamp: message.timestamp,
+        amends: message.amends,
+        reply_to: message.reply_to,
+    };
+    encrypt_text(meta, &text)
+}
+
+/// Sends the messages still waiting in the outbox of `peer` once the handshake is done.
+async fn flush_outbox(
+    connection: &quinn::Connection,
+    ctx: &PeerContext,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+) -> Result<()> {
+    let mut ids = Vec::new();
+    for message in ctx.outbox.list(peer) {
+        send_wire_message(connection, seal_queued(&ctx.meta, &message)?).await?;
+        ids.push(message.id);
+    }
+    if !ids.is_empty() {
+        event_tx
+            .send(ServiceEvent::OutboxFlushed { peer, ids })
+            .await
+            .ok();
+    }
     Ok(())
 }
 
 fn encrypt_text(meta: &ConnectionMeta, message: &TextMessage) -> Result<WireMessage> {
-    let key = meta
-        .shared_key()
-        .ok_or_else(|| anyhow!("secure channel not established yet"))?;
-    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key));
-    let mut nonce = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce);
     let plaintext = bincode::serde::encode_to_vec(message, bincode::config::standard())
         .context("failed to encode plaintext")?;
-    let ciphertext = cipher
-        .encrypt(GenericArray::from_slice(&nonce), plaintext.as_ref())
-        .context("failed to encrypt payload")?;
-    Ok(WireMessage::Ciphertext(CipherFrame {
-        nonce,
-        body: ciphertext,
-    }))
+    Ok(WireMessage::Ciphertext(meta.seal(&plaintext)?))
+}
+
+/// Opens the chat message a stream starts with. Only messages sealed with the session key
+/// are accepted, so a plaintext one cannot slip in an edit or a delete. The hello is handled
+/// on another stream, so a message that overtakes it waits for the session first.
+async fn open_text(meta: &ConnectionMeta, message: WireMessage) -> Result<TextMessage> {
+    match message {
+        WireMessage::Ciphertext(frame) => {
+            meta.wait_established().await?;
+            decrypt_text(meta, &frame)
+        }
+        WireMessage::Text(_) => bail!("rejected unencrypted chat message"),
+        _ => bail!("expected a chat message"),
+    }
 }
 
 fn decrypt_text(meta: &ConnectionMeta, frame: &CipherFrame) -> Result<TextMessage> {
-    let key = meta
-        .shared_key()
-        .ok_or_else(|| anyhow!("secure channel not established yet"))?;
-    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key));
-    let plaintext = cipher
-        .decrypt(GenericArray::from_slice(&frame.nonce), frame.body.as_ref())
-        .context("failed to decrypt payload")?;
+    let plaintext = meta.open(frame)?;
     let (message, _) = bincode::serde::decode_from_slice(&plaintext, bincode::config::standard())
         .context("failed to decode message")?;
     Ok(message)
@@ -1047,6 +2206,14 @@ async fn read_connection(
     ctx: PeerContext,
 ) -> Result<()> {
     let peer = connection.remote_address();
+    let (text_tx, text_rx) = mpsc::channel(EVENT_BUFFER);
+    tokio::spawn(order_messages(
+        text_rx,
+        chat_log,
+        event_tx.clone(),
+        peer,
+        ctx.clone(),
+    ));
     loop {
         tokio::select! {
             res = connection.closed() => {
@@ -1056,11 +2223,11 @@ async fn read_connection(
             stream = connection.accept_uni() => match stream {
                 Ok(recv) => {
                     let tx = event_tx.clone();
-                    let log = chat_log.clone();
+                    let texts = text_tx.clone();
                     let conn = connection.clone();
                     let peer_ctx = ctx.clone();
                     tokio::spawn(async move {
-                        if let Err(err) = handle_stream(recv, log, tx, peer, conn, peer_ctx).await {
+                        if let Err(err) = handle_stream(recv, texts, tx, peer, conn, peer_ctx).await {
                             tracing::warn!(?peer, "stream error: {err:#}");
                         }
                     });
@@ -1073,11 +2240,11 @@ async fn read_connection(
             stream = connection.accept_bi() => match stream {
                 Ok((_send, recv)) => {
                     let tx = event_tx.clone();
-                    let log = chat_log.clone();
+                    let texts = text_tx.clone();
                     let conn = connection.clone();
                     let peer_ctx = ctx.clone();
                     tokio::spawn(async move {
-                        if let Err(err) = handle_stream(recv, log, tx, peer, conn, peer_ctx).await {
+                        if let Err(err) = handle_stream(recv, texts, tx, peer, conn, peer_ctx).await {
                             tracing::warn!(?peer, "bi stream error: {err:#}");
                         }
                     });
@@ -1092,89 +2259,188 @@ async fn read_connection(
     Ok(())
 }
 
+/// Shows the chat messages of one connection in the order they were sent.
+///
+/// Each message arrives on its own stream, so they are put back in order first. Messages
+/// still waiting when the connection goes away are shown anyway.
+async fn order_messages(
+    mut texts: mpsc::Receiver<TextMessage>,
+    chat_log: Option<PathBuf>,
+    event_tx: mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    ctx: PeerContext,
+) {
+    let mut buffer = ReorderBuffer::new(REORDER_HOLD);
+    loop {
+        let deadline = buffer.deadline();
+        let wake = deadline.unwrap_or_else(Instant::now).into();
+        let ready = tokio::select! {
+            text = texts.recv() => match text {
+                Some(text) => buffer.push(text, Instant::now()),
+                None => break,
+            },
+            _ = tokio::time::sleep_until(wake), if deadline.is_some() => {
+                buffer.expire(Instant::now())
+            }
+        };
+        for text in ready {
+            deliver_text(text, &chat_log, &event_tx, peer, &ctx).await;
+        }
+    }
+    for text in buffer.drain() {
+        deliver_text(text, &chat_log, &event_tx, peer, &ctx).await;
+    }
+}
+
+/// Shows a received chat message and records it in the chat log and history. Amendments
+/// are only recorded in the history. A message shown before, on this connection or an
+/// earlier one, was acknowledged again and is dropped.
+async fn deliver_text(
+    text: TextMessage,
+    chat_log: &Option<PathBuf>,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    ctx: &PeerContext,
+) {
+    if let Some(identity) = ctx.meta.remote_identity() {
+        let mut delivered = ctx.delivered.lock();
+        if !delivered.entry(identity).or_default().insert(text.id) {
+            return;
+        }
+    }
+    if let Some(amendment) = text.amends {
+        event_tx
+            .send(ServiceEvent::MessageAmended {
+                peer,
+                author: text.author.clone(),
+                amendment,
+                text: text.body.clone(),
+                outgoing: false,
+            })
+            .await
+            .ok();
+    } else {
+        event_tx
+            .send(ServiceEvent::MessageReceived {
+                peer,
+                id: text.id,
+                author: text.author.clone(),
+                text: text.body.clone(),
+                reply_to: text.reply_to,
+            })
+            .await
+            .ok();
+        persist_chat(
+            chat_log.clone(),
+            format!("{} -> you: {}", text.author, text.body),
+        )
+        .await
+        .ok();
+    }
+    if ctx.history_enabled.load(Ordering::Relaxed) {
+        ctx.history
+            .record(
+                peer,
+                HistoryEntry {
+                    timestamp: text.timestamp,
+                    outgoing: false,
+                    author: text.author,
+                    text: text.body,
+                    id: text.id,
+                    amends: text.amends,
+                    reply_to: text.reply_to,
+                },
+            )
+            .ok();
+    }
+}
+
 async fn handle_stream(
     mut recv: quinn::RecvStream,
-    chat_log: Option<PathBuf>,
+    texts: mpsc::Sender<TextMessage>,
     event_tx: mpsc::Sender<ServiceEvent>,
     peer: std::net::SocketAddr,
     connection: quinn::Connection,
     ctx: PeerContext,
 ) -> Result<()> {
     match protocol::read_message(&mut recv).await? {
-        Some(WireMessage::Text(text)) => {
-            event_tx
-                .send(ServiceEvent::MessageReceived {
-                    peer,
-                    author: text.author.clone(),
-                    text: text.body.clone(),
-                })
-                .await
-                .ok();
-            persist_chat(
-                chat_log.clone(),
-                format!("{} -> you: {}", text.author, text.body),
-            )
-            .await
-            .ok();
-            if ctx.history_enabled.load(Ordering::Relaxed) {
-                ctx.history
-                    .record(
-                        peer,
-                        HistoryEntry {
-                            timestamp: text.timestamp,
-                            outgoing: false,
-                            author: text.author.clone(),
-                            text: text.body.clone(),
-                        },
-                    )
+        Some(WireMessage::FileMeta(meta)) => {
+            bail!("rejected unencrypted file stream for '{}'", meta.name);
+        }
+        Some(WireMessage::FileFrame(frame)) => {
+            if let Err(err) =
+                receive_file_stream(recv, frame, connection, event_tx.clone(), peer, ctx.clone())
+                    .await
+            {
+                event_tx
+                    .send(ServiceEvent::Error {
+                        peer: Some(peer),
+                        message: format!("file transfer failed: {err:#}"),
+                    })
+                    .await
                     .ok();
             }
         }
-        Some(WireMessage::FileMeta(meta)) => {
-            receive_file_stream(recv, meta, event_tx.clone(), peer, ctx.clone()).await?;
+        Some(WireMessage::Control(ControlMessage::Presence(_))) => {
+            bail!("rejected unencrypted presence");
+        }
+        Some(WireMessage::Control(ControlMessage::Typing(_))) => {
+            bail!("rejected unencrypted typing notice");
         }
         Some(WireMessage::Control(control)) => {
             handle_control(control, connection, ctx.clone(), event_tx.clone(), peer).await?;
         }
-        Some(WireMessage::Ciphertext(frame)) => match decrypt_text(&ctx.meta, &frame) {
-            Ok(text) => {
+        Some(WireMessage::SealedControl(frame)) => match open_control(&ctx.meta, &frame).await {
+            Ok(control) => {
+                handle_control(control, connection, ctx.clone(), event_tx.clone(), peer).await?;
+            }
+            Err(err) => {
                 event_tx
-                    .send(ServiceEvent::MessageReceived {
-                        peer,
-                        author: text.author.clone(),
-                        text: text.body.clone(),
+                    .send(ServiceEvent::Error {
+                        peer: Some(peer),
+                        message: format!("decryption error from {peer}: {err:#}"),
                     })
                     .await
                     .ok();
-                persist_chat(
-                    chat_log.clone(),
-                    format!("{} -> you: {}", text.author, text.body),
-                )
-                .await
-                .ok();
-                if ctx.history_enabled.load(Ordering::Relaxed) {
-                    ctx.history
-                        .record(
-                            peer,
-                            HistoryEntry {
-                                timestamp: text.timestamp,
-                                outgoing: false,
-                                author: text.author.clone(),
-                                text: text.body.clone(),
-                            },
-                        )
-                        .ok();
-                }
             }
+        },
+        Some(WireMessage::Ack(ack)) => {
+            bail!("rejected unencrypted acknowledgement of {}", ack.id);
+        }
+        Some(WireMessage::SealedAck(frame)) => match open_ack(&ctx.meta, &frame).await {
+            Ok(ack) => handle_ack(ack, &ctx, &event_tx, peer).await,
             Err(err) => {
                 event_tx
                     .send(ServiceEvent::Error {
+                        peer: Some(peer),
                         message: format!("decryption error from {peer}: {err:#}"),
                     })
                     .await
                     .ok();
             }
         },
+        Some(message @ (WireMessage::Text(_) | WireMessage::Ciphertext(_))) => {
+            let sealed = matches!(message, WireMessage::Ciphertext(_));
+            match open_text(&ctx.meta, message).await {
+                Ok(text) => {
+                    acknowledge(&connection, &ctx.meta, text.id, AckKind::Received)
+                        .await
+                        .ok();
+                    texts.send(text).await.ok();
+                }
+                // Like a plaintext file stream, a plaintext message is dropped unacknowledged.
+                Err(err) if !sealed => return Err(err),
+                Err(err) => {
+                    event_tx
+                        .send(ServiceEvent::Error {
+                            peer: Some(peer),
+                            message: format!("decryption error from {peer}: {err:#}"),
+                        })
+                        .await
+                        .ok();
+                }
+            }
+        }
         Some(other) => {
             tracing::debug!(?other, "unexpected first frame");
         }
@@ -1183,6 +2449,71 @@ async fn handle_stream(
     Ok(())
 }
 
+/// Acts on an acknowledgement `peer` sent for one of our messages or transfers.
+async fn handle_ack(
+    ack: Ack,
+    ctx: &PeerContext,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+) {
+    let id = ack.id;
+    match ack.kind {
+        AckKind::Received => {
+            ctx.outbox.remove(peer, id).ok();
+            event_tx
+                .send(ServiceEvent::MessageDelivered { peer, id })
+                .await
+                .ok();
+        }
+        AckKind::Read => {
+            event_tx
+                .send(ServiceEvent::MessageRead { peer, id })
+                .await
+                .ok();
+        }
+        AckKind::Completed => {
+            ctx.transfer_controls.lock().remove(&id);
+            let transfer = ctx.pending_transfers.lock().remove(&id);
+            if let Some(transfer) = transfer {
+                event_tx
+                    .send(ServiceEvent::FileTransfer(FileTransferProgress {
+                        peer,
+                        id: transfer.offer.id,
+                        name: transfer.offer.name,
+                        transferred: transfer.offer.original_size,
+                        total: transfer.offer.original_size,
+                        direction: TransferDirection::Outgoing,
+                        path: Some(transfer.original_path),
+                        entry: None,
+                        bytes_per_sec: None,
+                        eta: None,
+                        completed: true,
+                    }))
+                    .await
+                    .ok();
+            }
+        }
+    }
+}
+
+/// Opens a sealed acknowledgement, once the session it was sealed with is established.
+async fn open_ack(meta: &ConnectionMeta, frame: &CipherFrame) -> Result<Ack> {
+    meta.wait_established().await?;
+    let plaintext = meta.open(frame)?;
+    let (ack, _) = bincode::serde::decode_from_slice(&plaintext, bincode::config::standard())
+        .context("failed to decode acknowledgement")?;
+    Ok(ack)
+}
+
+/// Opens a sealed control message, once the session it was sealed with is established.
+async fn open_control(meta: &ConnectionMeta, frame: &CipherFrame) -> Result<ControlMessage> {
+    meta.wait_established().await?;
+    let plaintext = meta.open(frame)?;
+    let (control, _) = bincode::serde::decode_from_slice(&plaintext, bincode::config::standard())
+        .context("failed to decode control message")?;
+    Ok(control)
+}
+
 async fn handle_control(
     control: ControlMessage,
     connection: quinn::Connection,
@@ -1192,6 +2523,10 @@ async fn handle_control(
 ) -> Result<()> {
     match control {
         ControlMessage::Hello(hello) => {
+            // The handshake, outbox flush and resumes run once per connection.
+            if ctx.meta.greeted.swap(true, Ordering::AcqRel) {
+                bail!("peer {peer} sent a second hello on the same connection");
+            }
             if let Some(required) = &ctx.required_password {
                 if hello.password.as_deref() != Some(required) {
                     let _ = send_control_message(
@@ -1203,11 +2538,41 @@ async fn handle_control(
                     bail!("peer {peer} failed password validation");
                 }
             }
+            if let Err(err) = crypto::verify_hello(&hello) {
+                connection.close(0u32.into(), b"invalid handshake signature");
+                return Err(err.context(format!("peer {peer} failed identity verification")));
+            }
+            let conflict =
+                ctx.peers
+                    .conflicting_pin(&hello.identity_key, peer, &hello.username, ctx.dialed);
+            if let Some(saved) = conflict {
+                let expected = saved
+                    .identity_key
+                    .as_deref()
+                    .and_then(crypto::key_from_hex)
+                    .unwrap_or_default();
+                connection.close(0u32.into(), b"identity key changed");
+                event_tx
+                    .send(ServiceEvent::IdentityKeyChanged {
+                        peer,
+                        saved: saved.addr,
+                        name: hello.username,
+                        expected: crypto::short_fingerprint(&expected),
+                        received: crypto::short_fingerprint(&hello.identity_key),
+                    })
+                    .await
+                    .ok();
+                return Ok(());
+            }
             ctx.meta.set_name(&hello.username);
+            ctx.meta.set_codecs(hello.codecs.clone());
             ctx.meta
-                .derive(&hello.public_key)
+                .derive(&hello)
                 .context("failed to derive shared key")?;
-            if let Ok(list) = ctx.peers.record(peer, &hello.username) {
+            let recorded = ctx
+                .peers
+                .record(peer, &hello.username, &hello.identity_key, ctx.dialed);
+            if let Ok(list) = recorded {
                 event_tx.send(ServiceEvent::SavedPeers(list)).await.ok();
             }
             event_tx
@@ -1217,101 +2582,289 @@ async fn handle_control(
                 })
                 .await
                 .ok();
+            let presence = ControlMessage::Presence(ctx.presence.lock().clone());
+            if let Err(err) = send_sealed_control(&connection, &ctx.meta, presence).await {
+                tracing::warn!(?peer, "failed to announce presence: {err:#}");
+            }
+            if let Err(err) = flush_outbox(&connection, &ctx, &event_tx, peer).await {
+                tracing::warn!(?peer, "failed to flush outbox: {err:#}");
+            }
+            resume_partials(&connection, &ctx, &event_tx, peer, &hello.identity_key).await;
         }
         ControlMessage::FileOffer(offer) => {
+            let checked =
+                manifest::validate(&offer.entries, offer.original_size).and_then(|entries| {
+                    if segment_count(offer.original_size, offer.segment_bytes) > MAX_SEGMENTS {
+                        bail!("transfer is split into too many segments");
+                    }
+                    Ok(entries)
+                });
+            let entries = match checked {
+                Ok(entries) => entries,
+                Err(err) => {
+                    send_control_message(
+                        &connection,
+                        ControlMessage::FileReject(FileReject {
+                            id: offer.id,
+                            reason: Some(format!("{err}")),
+                            expired: false,
+                        }),
+                    )
+                    .await?;
+                    event_tx
+                        .send(ServiceEvent::Error {
+                            peer: Some(peer),
+                            message: format!(
+                                "rejected file offer '{}': {err}",
+                                manifest::sanitize_name(&offer.name)
+                            ),
+                        })
+                        .await
+                        .ok();
+                    return Ok(());
+                }
+            };
+            if offer.original_size > ctx.limits.max_file_bytes {
+                let reason = AbortReason::TooLarge {
+                    size: offer.original_size,
+                    limit: ctx.limits.max_file_bytes,
+                };
+                let name = manifest::sanitize_name(&offer.name);
+                abort_download(
+                    &connection,
+                    &ctx.partials,
+                    &event_tx,
+                    peer,
+                    offer.id,
+                    name,
+                    reason,
+                )
+                .await;
+                return Ok(());
+            }
             let notice = FileOfferNotice {
                 id: offer.id,
-                name: offer.name.clone(),
+                name: manifest::sanitize_name(&offer.name),
                 original_size: offer.original_size,
-                compressed_size: offer.compressed_size,
+                codec: offer.codec,
+                estimated_ratio: offer.estimated_ratio,
+                entries,
+                segment_bytes: offer.segment_bytes,
+                expires_at: offer_ttl(&ctx.limits).map(|ttl| Instant::now() + ttl),
                 peer,
             };
             {
                 let mut pending = ctx.incoming_offers.lock();
                 pending.insert(offer.id, notice.clone());
             }
+            let rule = ctx.meta.remote_identity().and_then(|identity| {
+                auto_accept_rule(&ctx.auto_accept, &ctx.peers.list(), &identity, &notice)
+            });
+            if let Some(rule) = rule {
+                ctx.internal_tx
+                    .send(InternalSignal::AutoAccept {
+                        peer,
+                        id: notice.id,
+                        destination: rule.destination.clone(),
+                    })
+                    .await
+                    .ok();
+                return Ok(());
+            }
+            if let Some(ttl) = offer_ttl(&ctx.limits) {
+                spawn_offer_expiry(
+                    connection.clone(),
+                    ctx.incoming_offers.clone(),
+                    event_tx.clone(),
+                    peer,
+                    notice.id,
+                    ttl,
+                );
+            }
             event_tx.send(ServiceEvent::FileOffer(notice)).await.ok();
         }
         ControlMessage::FileAccept(ack) => {
-            let transfer = {
-                let mut pending = ctx.pending_transfers.lock();
-                pending.remove(&ack.id)
-            };
-            if let Some(transfer) = transfer {
-                let limits = ctx.limits.clone();
-                let tx = event_tx.clone();
-                let conn = connection.clone();
-                tokio::spawn(async move {
-                    if let Err(err) =
-                        transmit_prepared_file(conn, transfer, limits, tx.clone()).await
-                    {
-                        let _ = tx
-                            .send(ServiceEvent::Error {
-                                message: format!("file transfer failed: {err:#}"),
-                            })
-                            .await;
+            if let Some(transfer) = ctx.pending_transfers.lock().get_mut(&ack.id) {
+                transfer.accepted = true;
+            }
+            spawn_transmit(&connection, &ctx, &event_tx, peer, ack.id, None);
+        }
+        ControlMessage::FileResume(resume) => {
+            let known = ctx.pending_transfers.lock().contains_key(&resume.id);
+            if known {
+                // The receiver may come back from another address.
+                let paused = match ctx.transfer_controls.lock().get_mut(&resume.id) {
+                    Some(control) => {
+                        control.peer = peer;
+                        control.is_paused()
                     }
-                });
+                    None => false,
+                };
+                if paused {
+                    send_control_message(
+                        &connection,
+                        ControlMessage::FileControl(FileControl {
+                            id: resume.id,
+                            action: TransferAction::Pause,
+                        }),
+                    )
+                    .await?;
+                }
+                let segments = Some(resume.segments);
+                spawn_transmit(&connection, &ctx, &event_tx, peer, resume.id, segments);
+            } else {
+                send_control_message(
+                    &connection,
+                    ControlMessage::FileReject(FileReject {
+                        id: resume.id,
+                        reason: Some("transfer is no longer available".into()),
+                        expired: false,
+                    }),
+                )
+                .await?;
             }
         }
         ControlMessage::FileReject(reject) => {
+            let reason = reject
+                .reason
+                .unwrap_or_else(|| "peer declined the transfer".into());
             let transfer = {
                 let mut pending = ctx.pending_transfers.lock();
                 pending.remove(&reject.id)
             };
+            let incoming = ctx.incoming_transfers.lock().remove(&reject.id);
+            // Stops the streams still sending the transfer.
+            if let Some(control) = ctx.transfer_controls.lock().remove(&reject.id) {
+                control.cancel();
+            }
             if let Some(transfer) = transfer {
-                let reason = reject
-                    .reason
-                    .unwrap_or_else(|| "peer declined the transfer".into());
                 let name = transfer.offer.name;
+                let event = if reject.expired {
+                    ServiceEvent::OfferExpired {
+                        peer,
+                        id: reject.id,
+                        name,
+                        direction: TransferDirection::Outgoing,
+                    }
+                } else {
+                    ServiceEvent::Error {
+                        peer: Some(peer),
+                        message: format!("File '{name}' was rejected: {reason}"),
+                    }
+                };
+                event_tx.send(event).await.ok();
+            } else if let Some(incoming) = incoming {
+                ctx.partials.remove(reject.id);
+                let name = incoming.original_name.clone();
                 event_tx
                     .send(ServiceEvent::Error {
-                        message: format!("File '{name}' was rejected: {reason}"),
+                        peer: Some(peer),
+                        message: format!("Download of '{name}' was cancelled: {reason}"),
                     })
                     .await
                     .ok();
             }
         }
-        ControlMessage::Denied(reason) => {
+        ControlMessage::FileControl(FileControl {
+            id,
+            action: TransferAction::Cancel,
+        }) => {
+            cancelled_by_peer(&ctx, &event_tx, peer, id).await;
+        }
+        ControlMessage::FileControl(FileControl { id, action }) => {
+            let control = ctx
+                .transfer_controls
+                .lock()
+                .get(&id)
+                .filter(|control| control.peer == peer)
+                .cloned();
+            let paused = action == TransferAction::Pause;
+            if control.is_some_and(|control| control.set_paused(paused)) {
+                event_tx
+                    .send(ServiceEvent::TransferPaused { peer, id, paused })
+                    .await
+                    .ok();
+            }
+        }
+        ControlMessage::Presence(presence) => {
+            let last_seen = protocol::utc_timestamp();
+            if let Some(key) = ctx.meta.remote_identity() {
+                ctx.peers.heard(&key, last_seen);
+            }
+            let status = presence.status.map(|status| {
+                status
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(MAX_STATUS_CHARS)
+                    .collect()
+            });
             event_tx
-                .send(ServiceEvent::Error {
-                    message: format!("conexão recusada por {peer}: {reason}"),
+                .send(ServiceEvent::PeerPresence {
+                    peer,
+                    state: presence.state,
+                    status,
+                    last_seen,
                 })
                 .await
                 .ok();
-            connection.close(0u32.into(), b"remote denied");
         }
-        ControlMessage::Info(info) => {
+        ControlMessage::Typing(typing) => {
             event_tx
-                .send(ServiceEvent::Error {
-                    message: format!("mensagem de {peer}: {info}"),
-                })
+                .send(ServiceEvent::PeerTyping { peer, typing })
                 .await
                 .ok();
         }
-    }
+        ControlMessage::Denied(reason) => {
+            ctx.internal_tx
+                .send(InternalSignal::Denied { peer })
+                .await
+                .ok();
+            event_tx
+                .send(ServiceEvent::Error {
+                    peer: Some(peer),
+                    message: format!("conexão recusada por {peer}: {reason}"),
+                })
+                .await
+                .ok();
+            connection.close(0u32.into(), b"remote denied");
+        }
+        ControlMessage::Info(info) => {
+            event_tx
+                .send(ServiceEvent::Error {
+                    peer: Some(peer),
+                    message: format!("mensagem de {peer}: {info}"),
+                })
+                .await
+                .ok();
+        }
+    }
     Ok(())
 }
 
 async fn receive_file_stream(
     mut recv: quinn::RecvStream,
-    meta: FileMetadata,
+    header: FileFrame,
+    connection: quinn::Connection,
     event_tx: mpsc::Sender<ServiceEvent>,
     peer: std::net::SocketAddr,
     ctx: PeerContext,
 ) -> Result<()> {
-    if meta.original_size > ctx.limits.max_file_bytes {
-        bail!("incoming file exceeds configured limit");
-    }
-    let transfer = {
-        let mut guard = ctx.incoming_transfers.lock();
-        guard.remove(&meta.id)
+    let cipher = ctx
+        .meta
+        .file_cipher(header.id, header.segment, header.attempt)?;
+    let meta = match cipher.open(&header, FILE_META_OFFSET)? {
+        WireMessage::FileMeta(meta) if meta.id == header.id && meta.segment == header.segment => {
+            meta
+        }
+        _ => bail!("file stream did not start with its metadata"),
     };
+    let transfer = ctx.incoming_transfers.lock().get(&meta.id).cloned();
     let transfer = match transfer {
         Some(t) => t,
         None => {
             event_tx
                 .send(ServiceEvent::Error {
+                    peer: Some(peer),
                     message: format!("received unexpected file '{}' without approval", meta.name),
                 })
                 .await
@@ -1319,67 +2872,614 @@ async fn receive_file_stream(
             return Ok(());
         }
     };
-    let mut file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(&transfer.temp_path)
-        .await
-        .context("unable to create temporary receive file")?;
-
-    let mut total = 0u64;
-    loop {
-        match protocol::read_message(&mut recv).await? {
-            Some(WireMessage::FileChunk(chunk)) => {
-                if chunk.id != meta.id {
-                    continue;
+    if meta.original_size != transfer.original_size {
+        bail!("file stream size does not match the accepted offer");
+    }
+    if meta.original_size > ctx.limits.max_file_bytes {
+        let reason = AbortReason::TooLarge {
+            size: meta.original_size,
+            limit: ctx.limits.max_file_bytes,
+        };
+        let _ = recv.stop(quinn::VarInt::from_u32(STREAM_ABORTED));
+        abort_incoming(
+            &connection,
+            &ctx,
+            &event_tx,
+            peer,
+            meta.id,
+            &transfer,
+            reason,
+        )
+        .await;
+        return Ok(());
+    }
+    let Some((range, start)) = transfer.claim(meta.segment) else {
+        bail!(
+            "segment {} of '{}' was not requested",
+            meta.segment,
+            transfer.original_name
+        );
+    };
+    let control = ctx
+        .transfer_controls
+        .lock()
+        .get(&meta.id)
+        .cloned()
+        .unwrap_or_else(|| TransferControl::new(peer, transfer.original_name.clone()));
+    let mut writer = EntryWriter::new(transfer.staged_files(), start);
+    let mut changes = control.state.subscribe();
+    // Bytes of the segment written so far, and how far of that is recorded on disk.
+    let mut total = start;
+    let mut checkpoint = start;
+    let received = async {
+        let mut decoder = ChunkDecoder::new(meta.codec)?;
+        while total < range.end {
+            let frame = match protocol::read_message(&mut recv).await? {
+                Some(WireMessage::FileFrame(frame)) => frame,
+                Some(_) => bail!("unencrypted frame inside file stream"),
+                None => bail!("file stream ended before the last chunk"),
+            };
+            // Reading slower holds the sender back through QUIC flow control.
+            bandwidth::throttle(
+                &[&transfer.meter.limit, &ctx.download_limit],
+                frame.body.len(),
+            )
+            .await;
+            let chunk = match cipher.open(&frame, total)? {
+                WireMessage::FileChunk(chunk) if chunk.id == meta.id && chunk.offset == total => {
+                    chunk
                 }
-                file.write_all(&chunk.bytes).await?;
-                total += chunk.bytes.len() as u64;
-                event_tx
-                    .send(ServiceEvent::FileTransfer(FileTransferProgress {
-                        id: meta.id,
-                        name: transfer.original_name.clone(),
-                        transferred: total,
-                        total: meta.compressed_size,
-                        direction: TransferDirection::Incoming,
-                        path: Some(transfer.target_path.clone()),
-                        completed: false,
-                    }))
-                    .await
-                    .ok();
-                if chunk.last || total >= meta.compressed_size {
-                    break;
+                _ => bail!("unexpected frame inside file stream"),
+            };
+            // Never inflate past what is left of the segment, which the checks above keep
+            // within the configured limit.
+            let bytes = decoder
+                .decode(&chunk.bytes, range.end - total)
+                .map_err(|err| anyhow::Error::new(AbortReason::Corrupt(format!("{err:#}"))))?;
+            if bytes.is_empty() {
+                bail!("empty chunk inside file stream");
+            }
+            writer.write(&bytes).await?;
+            total += bytes.len() as u64;
+            // Chunks stop arriving while the transfer is paused; leave such gaps out.
+            if changes.has_changed().unwrap_or(false) {
+                changes.borrow_and_update();
+                transfer.meter.pause();
+            }
+            let (transferred, bytes_per_sec, eta) = transfer.meter.add(bytes.len() as u64);
+            event_tx
+                .send(ServiceEvent::FileTransfer(FileTransferProgress {
+                    peer,
+                    id: meta.id,
+                    name: transfer.original_name.clone(),
+                    transferred,
+                    total: meta.original_size,
+                    direction: TransferDirection::Incoming,
+                    path: Some(transfer.target_path.clone()),
+                    entry: entry_progress(&transfer.entries, transferred),
+                    bytes_per_sec,
+                    eta,
+                    completed: false,
+                }))
+                .await
+                .ok();
+            if chunk.last && total != range.end {
+                bail!("file stream ended before the announced size");
+            }
+            if total - checkpoint >= SEGMENT_CHECKPOINT_BYTES {
+                writer.flush().await?;
+                ctx.partials
+                    .record_segment(meta.id, meta.segment, total, None);
+                checkpoint = total;
+            }
+        }
+        writer.flush().await?;
+        let frame = match protocol::read_message(&mut recv).await? {
+            Some(WireMessage::FileFrame(frame)) => frame,
+            _ => bail!("file stream ended before its digest"),
+        };
+        match cipher.open(&frame, FILE_DIGEST_OFFSET)? {
+            WireMessage::FileDigest(digest) if digest.id == meta.id => Ok(digest.sha256),
+            _ => bail!("file stream did not end with its digest"),
+        }
+    };
+    let received = tokio::select! {
+        received = received => received,
+        _ = control.cancelled() => Err(anyhow!("transfer cancelled")),
+    };
+    // Whoever cancelled already discarded the partial download.
+    if control.is_cancelled() {
+        let code = if transfer.aborted.load(Ordering::SeqCst) {
+            STREAM_ABORTED
+        } else {
+            STREAM_CANCELLED
+        };
+        let _ = recv.stop(quinn::VarInt::from_u32(code));
+        return Ok(());
+    }
+    // The partial download stays on disk so the transfer can resume after a reconnect, unless
+    // the stream itself was bad.
+    let sha256 = match received {
+        Ok(sha256) => sha256,
+        Err(err) if is_cancelled_stream(&err) => {
+            cancelled_by_peer(&ctx, &event_tx, peer, meta.id).await;
+            return Ok(());
+        }
+        Err(err) => match err.downcast::<AbortReason>() {
+            Ok(reason) => {
+                let _ = recv.stop(quinn::VarInt::from_u32(STREAM_ABORTED));
+                abort_incoming(
+                    &connection,
+                    &ctx,
+                    &event_tx,
+                    peer,
+                    meta.id,
+                    &transfer,
+                    reason,
+                )
+                .await;
+                return Ok(());
+            }
+            Err(err) => {
+                if writer.flush().await.is_ok() {
+                    checkpoint = total;
+                }
+                transfer.release(meta.segment, checkpoint);
+                ctx.partials
+                    .record_segment(meta.id, meta.segment, checkpoint, None);
+                if transfer.failed.swap(true, Ordering::SeqCst) {
+                    return Ok(());
                 }
+                return Err(err);
             }
-            Some(_) => {}
-            None => break,
+        },
+    };
+    drop(writer);
+    ctx.partials
+        .record_segment(meta.id, meta.segment, range.end, Some(sha256));
+    // The stream bringing in the last segment places the download.
+    let Some(expected) = transfer.complete(meta.segment, sha256) else {
+        return Ok(());
+    };
+    {
+        let mut incoming = ctx.incoming_transfers.lock();
+        if incoming
+            .get(&meta.id)
+            .is_some_and(|current| Arc::ptr_eq(current, &transfer))
+        {
+            incoming.remove(&meta.id);
         }
     }
-    file.flush().await?;
-    drop(file);
-    decompress_to_destination(&transfer.temp_path, &transfer.target_path).await?;
-    tokio::fs::remove_file(&transfer.temp_path).await.ok();
+    let placed = finalize_download(
+        transfer.staged_files(),
+        transfer.entries.clone(),
+        &transfer.target_path,
+        transfer.on_collision,
+        expected,
+    )
+    .await?;
+    ctx.transfer_controls.lock().remove(&meta.id);
+    ctx.partials.remove(meta.id);
+    let Some(placed) = placed else {
+        send_control_message(
+            &connection,
+            ControlMessage::FileReject(FileReject {
+                id: meta.id,
+                reason: Some("content verification failed".into()),
+                expired: false,
+            }),
+        )
+        .await
+        .ok();
+        event_tx
+            .send(ServiceEvent::FileIntegrityFailed {
+                peer,
+                id: meta.id,
+                name: transfer.original_name.clone(),
+            })
+            .await
+            .ok();
+        return Ok(());
+    };
+    acknowledge(&connection, &ctx.meta, meta.id, AckKind::Completed)
+        .await
+        .ok();
     event_tx
         .send(ServiceEvent::FileTransfer(FileTransferProgress {
+            peer,
             id: meta.id,
             name: transfer.original_name.clone(),
-            transferred: meta.compressed_size,
-            total: meta.compressed_size,
+            transferred: meta.original_size,
+            total: meta.original_size,
             direction: TransferDirection::Incoming,
-            path: Some(transfer.target_path.clone()),
+            path: Some(placed.clone()),
+            entry: None,
+            bytes_per_sec: None,
+            eta: None,
             completed: true,
         }))
         .await
         .ok();
     tracing::info!(
         ?peer,
-        path = %transfer.target_path.display(),
+        path = %placed.display(),
         "file received and decompressed"
     );
     Ok(())
 }
 
+/// Gives up on incoming `transfer` from one of its streams. The other streams stop and leave
+/// the reason to the `FileReject` sent here.
+async fn abort_incoming(
+    connection: &quinn::Connection,
+    ctx: &PeerContext,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    id: u64,
+    transfer: &IncomingTransfer,
+    reason: AbortReason,
+) {
+    ctx.incoming_transfers.lock().remove(&id);
+    transfer.aborted.store(true, Ordering::SeqCst);
+    if let Some(control) = ctx.transfer_controls.lock().remove(&id) {
+        control.cancel();
+    }
+    let name = transfer.original_name.clone();
+    abort_download(connection, &ctx.partials, event_tx, peer, id, name, reason).await;
+}
+
+/// Starts sending pending transfer `id` on a background task, either all of it or only the
+/// `segments` a resuming receiver is missing.
+fn spawn_transmit(
+    connection: &quinn::Connection,
+    ctx: &PeerContext,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    id: u64,
+    segments: Option<Vec<SegmentResume>>,
+) {
+    let Some(transfer) = touch_transfer(&ctx.pending_transfers, id) else {
+        return;
+    };
+    let segments = segments.unwrap_or_else(|| transfer.segments());
+    let control = ctx
+        .transfer_controls
+        .lock()
+        .get(&id)
+        .cloned()
+        .unwrap_or_else(|| TransferControl::new(peer, transfer.offer.name.clone()));
+    let ctx = ctx.clone();
+    let tx = event_tx.clone();
+    let conn = connection.clone();
+    tokio::spawn(async move {
+        let result = async {
+            ctx.meta.wait_established().await?;
+            transmit_prepared_file(conn, &ctx, transfer, segments, tx.clone(), control).await
+        };
+        let result = result.await;
+        touch_transfer(&ctx.pending_transfers, id);
+        match result {
+            Ok(()) => {}
+            Err(err) if is_cancelled_stream(&err) => {
+                cancelled_by_peer(&ctx, &tx, peer, id).await;
+            }
+            // The receiver gave up and says why in a `FileReject`.
+            Err(err) if stream_closed_with(&err, STREAM_ABORTED) => {}
+            Err(err) => {
+                let _ = tx
+                    .send(ServiceEvent::Error {
+                        peer: Some(peer),
+                        message: format!("file transfer failed: {err:#}"),
+                    })
+                    .await;
+            }
+        }
+    });
+}
+
+/// Marks pending transfer `id` as active now and returns a copy of it.
+fn touch_transfer(
+    pending: &Mutex<HashMap<u64, PreparedTransfer>>,
+    id: u64,
+) -> Option<PreparedTransfer> {
+    let mut pending = pending.lock();
+    let transfer = pending.get_mut(&id)?;
+    transfer.last_active = Instant::now();
+    Some(transfer.clone())
+}
+
+/// Records progress on pending transfer `id`, so a slow transfer is not taken for an idle one.
+fn mark_active(pending: &Mutex<HashMap<u64, PreparedTransfer>>, id: u64) {
+    if let Some(transfer) = pending.lock().get_mut(&id) {
+        transfer.last_active = Instant::now();
+    }
+}
+
+/// Removes the accepted transfers that have not moved for `ttl` from `pending`.
+fn take_idle_transfers(
+    pending: &Mutex<HashMap<u64, PreparedTransfer>>,
+    ttl: Duration,
+) -> Vec<PreparedTransfer> {
+    let mut pending = pending.lock();
+    let idle: Vec<u64> = pending
+        .iter()
+        .filter(|(_, transfer)| transfer.accepted && transfer.last_active.elapsed() >= ttl)
+        .map(|(id, _)| *id)
+        .collect();
+    idle.into_iter()
+        .filter_map(|id| pending.remove(&id))
+        .collect()
+}
+
+/// How long an offer may wait for an answer; `None` keeps it until it is answered.
+fn offer_ttl(limits: &LimitsConfig) -> Option<Duration> {
+    (limits.offer_ttl_secs > 0).then(|| Duration::from_secs(limits.offer_ttl_secs))
+}
+
+/// Declines incoming offer `id` if it is still unanswered after `ttl`.
+fn spawn_offer_expiry(
+    connection: quinn::Connection,
+    offers: Arc<Mutex<HashMap<u64, FileOfferNotice>>>,
+    event_tx: mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    id: u64,
+    ttl: Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(ttl).await;
+        let offer = {
+            let mut offers = offers.lock();
+            match offers.get(&id) {
+                Some(offer) if offer.peer == peer => offers.remove(&id),
+                _ => None,
+            }
+        };
+        let Some(offer) = offer else {
+            return;
+        };
+        send_control_message(
+            &connection,
+            ControlMessage::FileReject(FileReject {
+                id,
+                reason: Some(format!(
+                    "offer was not answered within {} seconds",
+                    ttl.as_secs()
+                )),
+                expired: true,
+            }),
+        )
+        .await
+        .ok();
+        event_tx
+            .send(ServiceEvent::OfferExpired {
+                peer,
+                id,
+                name: offer.name,
+                direction: TransferDirection::Incoming,
+            })
+            .await
+            .ok();
+    });
+}
+
+/// Withdraws outgoing offer `id` if the recipient has not accepted it after `ttl`, for
+/// recipients that went away or never expire offers themselves.
+fn spawn_offer_withdrawal(
+    connection: quinn::Connection,
+    pending: Arc<Mutex<HashMap<u64, PreparedTransfer>>>,
+    controls: Arc<Mutex<HashMap<u64, TransferControl>>>,
+    event_tx: mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    id: u64,
+    ttl: Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(ttl).await;
+        let transfer = {
+            let mut pending = pending.lock();
+            match pending.get(&id) {
+                Some(transfer) if !transfer.accepted => pending.remove(&id),
+                _ => None,
+            }
+        };
+        let Some(transfer) = transfer else {
+            return;
+        };
+        controls.lock().remove(&id);
+        send_control_message(
+            &connection,
+            ControlMessage::FileControl(FileControl {
+                id,
+                action: TransferAction::Cancel,
+            }),
+        )
+        .await
+        .ok();
+        event_tx
+            .send(ServiceEvent::OfferExpired {
+                peer,
+                id,
+                name: transfer.offer.name,
+                direction: TransferDirection::Outgoing,
+            })
+            .await
+            .ok();
+    });
+}
+
+/// Tears down transfer `id` after `peer` cancelled it. The cancel message and the reset of
+/// the file stream both end up here; whichever arrives first does the work.
+async fn cancelled_by_peer(
+    ctx: &PeerContext,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    id: u64,
+) {
+    let control = {
+        let mut controls = ctx.transfer_controls.lock();
+        match controls.get(&id) {
+            Some(control) if control.peer == peer => controls.remove(&id),
+            _ => None,
+        }
+    };
+    let name = match control {
+        Some(control) => {
+            control.cancel();
+            Some(control.name)
+        }
+        // An offer that was never accepted has nothing running yet.
+        None => {
+            let mut offers = ctx.incoming_offers.lock();
+            match offers.get(&id) {
+                Some(offer) if offer.peer == peer => offers.remove(&id).map(|offer| offer.name),
+                _ => None,
+            }
+        }
+    };
+    let Some(name) = name else {
+        return;
+    };
+    ctx.pending_transfers.lock().remove(&id);
+    ctx.incoming_transfers.lock().remove(&id);
+    ctx.partials.remove(id);
+    event_tx
+        .send(ServiceEvent::TransferAborted {
+            peer,
+            id,
+            name,
+            reason: AbortReason::Cancelled { by_peer: true },
+        })
+        .await
+        .ok();
+}
+
+/// Whether `err` comes from the peer resetting or stopping a file stream because it cancelled
+/// the transfer.
+fn is_cancelled_stream(err: &anyhow::Error) -> bool {
+    stream_closed_with(err, STREAM_CANCELLED)
+}
+
+/// Whether `err` comes from the peer resetting or stopping a stream with error `code`.
+fn stream_closed_with(err: &anyhow::Error, code: u32) -> bool {
+    let code = quinn::VarInt::from_u32(code);
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .filter_map(|io| io.get_ref())
+        .any(|inner| {
+            inner.downcast_ref::<quinn::ReadError>() == Some(&quinn::ReadError::Reset(code))
+                || inner.downcast_ref::<quinn::WriteError>()
+                    == Some(&quinn::WriteError::Stopped(code))
+        })
+}
+
+/// Gives up on incoming transfer `id` for good: drops its partial download, tells the sender
+/// why and reports the reason.
+async fn abort_download(
+    connection: &quinn::Connection,
+    partials: &PartialStore,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    id: u64,
+    name: String,
+    reason: AbortReason,
+) {
+    partials.remove(id);
+    send_control_message(
+        connection,
+        ControlMessage::FileReject(FileReject {
+            id,
+            reason: Some(reason.to_string()),
+            expired: false,
+        }),
+    )
+    .await
+    .ok();
+    event_tx
+        .send(ServiceEvent::TransferAborted {
+            peer,
+            id,
+            name,
+            reason,
+        })
+        .await
+        .ok();
+}
+
+/// Checks that `size` more bytes fit on the disks holding `paths` while keeping `reserve`
+/// bytes free. Paths that do not exist yet are checked through their closest ancestor.
+fn ensure_disk_space<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+    size: u64,
+    reserve: u64,
+) -> std::result::Result<(), AbortReason> {
+    let needed = size.saturating_add(reserve);
+    for path in paths {
+        let Some(available) = path
+            .ancestors()
+            .find(|ancestor| ancestor.exists())
+            .and_then(|existing| fs4::available_space(existing).ok())
+        else {
+            continue;
+        };
+        if available < needed {
+            return Err(AbortReason::DiskFull { needed, available });
+        }
+    }
+    Ok(())
+}
+
+/// Re-registers unfinished downloads from `identity` and asks the sender to resume them.
+async fn resume_partials(
+    connection: &quinn::Connection,
+    ctx: &PeerContext,
+    event_tx: &mpsc::Sender<ServiceEvent>,
+    peer: std::net::SocketAddr,
+    identity: &[u8; 32],
+) {
+    let identity = crypto::key_to_hex(identity);
+    for partial in ctx
+        .partials
+        .load()
+        .into_iter()
+        .filter(|partial| partial.peer_identity == identity)
+    {
+        let segments = ctx.partials.segments(&partial);
+        let staged = ctx.partials.staged_paths(&partial);
+        let limit = ctx.limits.transfer_download_bytes_per_sec;
+        let transfer = IncomingTransfer::new(&partial, staged, segments, limit);
+        let request = ControlMessage::FileResume(FileResume {
+            id: partial.id,
+            segments: transfer.missing(),
+        });
+        let received = transfer.meter.transferred.load(Ordering::Relaxed);
+        ctx.incoming_transfers
+            .lock()
+            .insert(partial.id, Arc::new(transfer));
+        ctx.transfer_controls
+            .lock()
+            .insert(partial.id, TransferControl::new(peer, partial.name.clone()));
+        if send_control_message(connection, request).await.is_err() {
+            continue;
+        }
+        event_tx
+            .send(ServiceEvent::FileTransfer(FileTransferProgress {
+                peer,
+                id: partial.id,
+                name: partial.name,
+                transferred: received,
+                total: partial.original_size,
+                direction: TransferDirection::Incoming,
+                path: Some(partial.target_path),
+                entry: entry_progress(&partial.entries, received),
+                bytes_per_sec: None,
+                eta: None,
+                completed: false,
+            }))
+            .await
+            .ok();
+    }
+}
+
 async fn persist_chat(chat_log: Option<PathBuf>, line: String) -> Result<()> {
     if let Some(path) = chat_log {
         if let Some(parent) = path.parent() {
@@ -1397,12 +3497,20 @@ async fn persist_chat(chat_log: Option<PathBuf>, line: String) -> Result<()> {
     Ok(())
 }
 
+/// One history record. Fields are only ever appended, so readers can still decode records
+/// written before a field existed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct HistoryEntry {
     timestamp: i64,
     outgoing: bool,
     author: String,
     text: String,
+    /// Wire id of the message, which amendments refer to.
+    id: u64,
+    /// Set on records that change an earlier message instead of adding one.
+    amends: Option<Amendment>,
+    /// Id of the message this one answers.
+    reply_to: Option<u64>,
 }
 
 struct HistoryWriter {
@@ -1437,7 +3545,7 @@ impl HistoryWriter {
         }
         let mut key = [0u8; 32];
         OsRng.fill_bytes(&mut key);
-        fs::write(&key_path, &key).context("failed to write history key")?;
+        fs::write(&key_path, key).context("failed to write history key")?;
         Ok(key)
     }
 
@@ -1480,24 +3588,156 @@ impl HistoryWriter {
     }
 }
 
-struct SavedPeersStore {
-    path: PathBuf,
-    peers: Mutex<Vec<SavedPeer>>,
+/// Chat message waiting in the outbox for its peer to acknowledge it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedMessage {
+    id: u64,
+    author: String,
+    body: String,
+    timestamp: i64,
+    amends: Option<Amendment>,
+    reply_to: Option<u64>,
 }
 
-impl SavedPeersStore {
-    fn new(path: PathBuf) -> Result<Self> {
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).with_context(|| {
-                format!("failed to create peers directory {}", parent.display())
-            })?;
+/// Messages not yet acknowledged, one encrypted file per peer next to its history.
+///
+/// Each file holds a nonce followed by the sealed list of messages and is rewritten as a
+/// whole, so it stays small and never carries acknowledged messages.
+struct OutboxStore {
+    dir: PathBuf,
+    key: [u8; 32],
+    guard: Mutex<()>,
+}
+
+impl OutboxStore {
+    /// Uses the history directory and key.
+    fn new(history: &HistoryWriter) -> Self {
+        Self {
+            dir: history.dir.clone(),
+            key: history.key,
+            guard: Mutex::new(()),
         }
-        let mut peers: Vec<SavedPeer> = if path.exists() {
-            let data = fs::read(&path).context("failed to read peers file")?;
-            if data.is_empty() {
-                Vec::new()
-            } else {
-                serde_json::from_slice(&data).unwrap_or_default()
+    }
+
+    /// Messages queued for `peer`, oldest first.
+    fn list(&self, peer: std::net::SocketAddr) -> Vec<QueuedMessage> {
+        let _lock = self.guard.lock();
+        self.load(peer).unwrap_or_else(|err| {
+            tracing::warn!(?peer, "ignoring unreadable outbox: {err:#}");
+            Vec::new()
+        })
+    }
+
+    fn push(&self, peer: std::net::SocketAddr, message: &QueuedMessage) -> Result<()> {
+        let _lock = self.guard.lock();
+        let mut messages = self.load(peer).unwrap_or_default();
+        messages.push(message.clone());
+        self.store(peer, &messages)
+    }
+
+    fn remove(&self, peer: std::net::SocketAddr, id: u64) -> Result<()> {
+        let _lock = self.guard.lock();
+        let mut messages = self.load(peer)?;
+        let before = messages.len();
+        messages.retain(|message| message.id != id);
+        if messages.len() == before {
+            return Ok(());
+        }
+        self.store(peer, &messages)
+    }
+
+    fn load(&self, peer: std::net::SocketAddr) -> Result<Vec<QueuedMessage>> {
+        let path = self.file_for(peer);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read(&path).context("failed to read outbox")?;
+        if data.len() < 12 {
+            bail!("outbox file is truncated");
+        }
+        let (nonce, ciphertext) = data.split_at(12);
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.key));
+        let plaintext = cipher
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("failed to decrypt outbox"))?;
+        let (messages, _) =
+            bincode::serde::decode_from_slice(&plaintext, bincode::config::standard())
+                .context("failed to decode outbox")?;
+        Ok(messages)
+    }
+
+    fn store(&self, peer: std::net::SocketAddr, messages: &[QueuedMessage]) -> Result<()> {
+        let path = self.file_for(peer);
+        if messages.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path).context("failed to remove empty outbox")?;
+            }
+            return Ok(());
+        }
+        let encoded = bincode::serde::encode_to_vec(messages, bincode::config::standard())
+            .context("failed to encode outbox")?;
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.key));
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), encoded.as_ref())
+            .context("failed to encrypt outbox")?;
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        // Written beside the old file and renamed over it, so a crash keeps one of the two.
+        let staged = path.with_extension("outbox.tmp");
+        fs::write(&staged, data).context("failed to write outbox")?;
+        fs::rename(&staged, &path).context("failed to store outbox")?;
+        Ok(())
+    }
+
+    fn file_for(&self, peer: std::net::SocketAddr) -> PathBuf {
+        let name = format!("{}", peer).replace(':', "_");
+        self.dir.join(format!("{name}.outbox"))
+    }
+}
+
+/// First rule that lets the peer holding `identity` send `offer` without asking.
+///
+/// A rule names its peer by identity key, or by the name of a saved peer whose key was
+/// verified; names are chosen by the peers themselves and mean nothing on their own.
+fn auto_accept_rule<'a>(
+    rules: &'a [AutoAcceptRule],
+    saved: &[SavedPeer],
+    identity: &[u8; 32],
+    offer: &FileOfferNotice,
+) -> Option<&'a AutoAcceptRule> {
+    let hex = crypto::key_to_hex(identity);
+    rules.iter().find(|rule| {
+        let trusted = crypto::key_from_hex(&rule.peer).as_ref() == Some(identity)
+            || saved.iter().any(|peer| {
+                peer.verified
+                    && peer.name == rule.peer
+                    && peer.identity_key.as_deref() == Some(hex.as_str())
+            });
+        let paths = offer.entries.iter().map(|entry| entry.path.as_str());
+        trusted && rule.allows(offer.original_size, paths)
+    })
+}
+
+struct SavedPeersStore {
+    path: PathBuf,
+    peers: Mutex<Vec<SavedPeer>>,
+}
+
+impl SavedPeersStore {
+    fn new(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("failed to create peers directory {}", parent.display())
+            })?;
+        }
+        let mut peers: Vec<SavedPeer> = if path.exists() {
+            let data = fs::read(&path).context("failed to read peers file")?;
+            if data.is_empty() {
+                Vec::new()
+            } else {
+                serde_json::from_slice(&data).unwrap_or_default()
             }
         } else {
             Vec::new()
@@ -1513,159 +3753,1366 @@ impl SavedPeersStore {
         self.peers.lock().clone()
     }
 
-    fn record(&self, addr: std::net::SocketAddr, name: &str) -> Result<Vec<SavedPeer>> {
+    fn find(&self, addr: std::net::SocketAddr) -> Option<SavedPeer> {
+        self.peers
+            .lock()
+            .iter()
+            .find(|peer| peer.addr == addr)
+            .cloned()
+    }
+
+    /// Identity key pinned for `addr`, if any.
+    fn pinned_identity(&self, addr: std::net::SocketAddr) -> Option<[u8; 32]> {
+        self.peers
+            .lock()
+            .iter()
+            .find(|peer| peer.addr == addr)
+            .and_then(|peer| peer.identity_key.as_deref())
+            .and_then(crypto::key_from_hex)
+    }
+
+    /// Saved peer whose pin another key would break: the one pinned at `addr` when we dialed
+    /// it, or the one with the same `name` when it dialed us. `None` when `key` is pinned
+    /// already or nothing is pinned for that peer.
+    fn conflicting_pin(
+        &self,
+        key: &[u8; 32],
+        addr: std::net::SocketAddr,
+        name: &str,
+        dialed: bool,
+    ) -> Option<SavedPeer> {
+        let hex = crypto::key_to_hex(key);
+        let peers = self.peers.lock();
+        if peers
+            .iter()
+            .any(|peer| peer.identity_key.as_deref() == Some(hex.as_str()))
+        {
+            return None;
+        }
+        peers
+            .iter()
+            .find(|peer| peer.identity_key.is_some() && claims(peer, addr, name, dialed))
+            .cloned()
+    }
+
+    /// Records a completed handshake. Peers are matched by identity key; the address only
+    /// follows connections we dialed.
+    fn record(
+        &self,
+        addr: std::net::SocketAddr,
+        name: &str,
+        identity_key: &[u8; 32],
+        dialed: bool,
+    ) -> Result<Vec<SavedPeer>> {
         let mut peers = self.peers.lock();
-        if let Some(existing) = peers.iter_mut().find(|peer| peer.addr == addr) {
+        let now = protocol::utc_timestamp();
+        let hex = crypto::key_to_hex(identity_key);
+        let pinned = peers
+            .iter()
+            .position(|peer| peer.identity_key.as_deref() == Some(hex.as_str()));
+        let unpinned = || {
+            peers
+                .iter()
+                .position(|peer| peer.identity_key.is_none() && claims(peer, addr, name, dialed))
+        };
+        if let Some(index) = pinned.or_else(unpinned) {
+            let existing = &mut peers[index];
             existing.name = name.to_string();
+            existing.last_seen = Some(now);
+            if dialed {
+                existing.addr = addr;
+            }
+            if existing.identity_key.is_none() {
+                existing.identity_key = Some(hex);
+                existing.verified = false;
+            }
         } else {
             peers.push(SavedPeer {
                 addr,
                 name: name.to_string(),
+                identity_key: Some(hex),
+                verified: false,
+                last_seen: Some(now),
             });
         }
         peers.sort_by(|a, b| a.name.cmp(&b.name));
-        let serialized = serde_json::to_vec_pretty(&*peers).context("failed to encode peers")?;
-        fs::write(&self.path, serialized).context("failed to store peers file")?;
+        self.persist(&peers)?;
+        Ok(peers.clone())
+    }
+
+    fn reset_identity(&self, addr: std::net::SocketAddr) -> Result<Vec<SavedPeer>> {
+        let mut peers = self.peers.lock();
+        let peer = peers
+            .iter_mut()
+            .find(|peer| peer.addr == addr)
+            .ok_or_else(|| anyhow!("{addr} is not a saved peer"))?;
+        peer.identity_key = None;
+        peer.verified = false;
+        self.persist(&peers)?;
+        Ok(peers.clone())
+    }
+
+    fn set_verified(&self, addr: std::net::SocketAddr, verified: bool) -> Result<Vec<SavedPeer>> {
+        let mut peers = self.peers.lock();
+        let peer = peers
+            .iter_mut()
+            .find(|peer| peer.addr == addr)
+            .ok_or_else(|| anyhow!("{addr} is not a saved peer"))?;
+        if peer.identity_key.is_none() {
+            bail!("no identity key pinned for {addr} yet");
+        }
+        peer.verified = verified;
+        self.persist(&peers)?;
         Ok(peers.clone())
     }
+
+    /// Records that the peer with identity `key` was just heard from. Returns the updated
+    /// list, or `None` when the peer is not saved.
+    fn seen(&self, key: &[u8; 32], at: i64) -> Result<Option<Vec<SavedPeer>>> {
+        let hex = crypto::key_to_hex(key);
+        let mut peers = self.peers.lock();
+        let Some(peer) = peers
+            .iter_mut()
+            .find(|peer| peer.identity_key.as_deref() == Some(hex.as_str()))
+        else {
+            return Ok(None);
+        };
+        peer.last_seen = Some(at);
+        self.persist(&peers)?;
+        Ok(Some(peers.clone()))
+    }
+
+    /// Records in memory that the peer with identity `key` was just heard from. Heartbeats
+    /// land here, so the file is only rewritten with the next change to it or when the
+    /// connection closes.
+    fn heard(&self, key: &[u8; 32], at: i64) {
+        let hex = crypto::key_to_hex(key);
+        let mut peers = self.peers.lock();
+        let peer = peers
+            .iter_mut()
+            .find(|peer| peer.identity_key.as_deref() == Some(hex.as_str()));
+        if let Some(peer) = peer {
+            peer.last_seen = Some(at);
+        }
+    }
+
+    fn persist(&self, peers: &[SavedPeer]) -> Result<()> {
+        let serialized = serde_json::to_vec_pretty(peers).context("failed to encode peers")?;
+        fs::write(&self.path, serialized).context("failed to store peers file")?;
+        Ok(())
+    }
+}
+
+/// Whether a connection that has no pinned key yet stands for saved peer `peer`: by the
+/// address we dialed, or by name for a peer that dialed us.
+fn claims(peer: &SavedPeer, addr: std::net::SocketAddr, name: &str, dialed: bool) -> bool {
+    if dialed {
+        peer.addr == addr
+    } else {
+        peer.name == name
+    }
 }
 
+#[derive(Clone)]
 struct PreparedTransfer {
     offer: FileOffer,
+    /// Local file behind each manifest entry, in the same order.
+    sources: Vec<PathBuf>,
+    /// First path the user picked, shown in progress events.
     original_path: PathBuf,
-    compressed_path: PathBuf,
+    /// The recipient answered the offer, so it no longer expires.
+    accepted: bool,
+    /// When the transfer last started, stopped or sent a chunk; accepted transfers idle for
+    /// `[limits] partial_ttl_secs` are dropped.
+    last_active: Instant,
+    /// Streams opened for the transfer so far, numbering each stream's key.
+    streams_opened: Arc<AtomicU32>,
+}
+
+impl PreparedTransfer {
+    fn files(&self) -> Vec<(PathBuf, u64)> {
+        self.sources
+            .iter()
+            .cloned()
+            .zip(self.offer.entries.iter().map(|entry| entry.size))
+            .collect()
+    }
+
+    /// Every segment of the transfer, from its start.
+    fn segments(&self) -> Vec<SegmentResume> {
+        segment_ranges(self.offer.original_size, self.offer.segment_bytes)
+            .into_iter()
+            .enumerate()
+            .map(|(index, range)| SegmentResume {
+                index: index as u32,
+                offset: range.start,
+            })
+            .collect()
+    }
 }
 
+/// Accepted download, shared by the streams bringing in its segments.
 struct IncomingTransfer {
     target_path: PathBuf,
-    temp_path: PathBuf,
+    /// Staging file for each manifest entry, in the same order.
+    staged: Vec<PathBuf>,
+    entries: Vec<ManifestEntry>,
+    on_collision: CollisionPolicy,
     original_name: String,
+    original_size: u64,
+    segment_bytes: u64,
+    segments: Mutex<Vec<SegmentState>>,
+    meter: TransferMeter,
+    /// Set once the download is given up, so the remaining streams leave the explanation to
+    /// the `FileReject`.
+    aborted: AtomicBool,
+    /// Set once a stream failed, so a lost connection is reported once rather than per stream.
+    failed: AtomicBool,
 }
 
-async fn prepare_transfer(path: PathBuf, limits: &LimitsConfig) -> Result<PreparedTransfer> {
-    let metadata = tokio::fs::metadata(&path)
-        .await
-        .with_context(|| format!("unable to read {}", path.display()))?;
-    if !metadata.is_file() {
-        bail!("{} is not a file", path.display());
-    }
-    if metadata.len() > limits.max_file_bytes {
-        bail!("file exceeds maximum permitted size");
-    }
-    let original_size = metadata.len();
-    let name = path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or("file.bin")
-        .to_string();
-    let path_clone = path.clone();
-    let (compressed_path, compressed_size) = spawn_blocking(move || -> Result<(PathBuf, u64)> {
-        let mut source = std::fs::File::open(&path_clone)
-            .with_context(|| format!("unable to open {}", path_clone.display()))?;
-        let temp = NamedTempFile::new().context("failed to create temp file")?;
-        let mut encoder = GzEncoder::new(temp, Compression::default());
-        std::io::copy(&mut source, &mut encoder).context("failed to compress file")?;
-        let mut finished = encoder.finish().context("failed to finalize compression")?;
-        finished.as_file_mut().flush().ok();
-        let compressed_size = finished
-            .as_file()
-            .metadata()
-            .context("failed to inspect compressed file")?
-            .len();
-        let temp_path = finished.into_temp_path();
-        let stored = temp_path
-            .keep()
-            .context("failed to persist compressed file")?;
-        Ok((stored, compressed_size))
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SegmentState {
+    /// Still to be received from `offset` on.
+    Missing { offset: u64 },
+    /// A stream is bringing it in.
+    Receiving,
+    /// Stored; the digest the sender sent is checked once all segments are in.
+    Done([u8; 32]),
+}
+
+impl IncomingTransfer {
+    fn new(
+        partial: &PartialState,
+        staged: Vec<PathBuf>,
+        segments: Vec<SegmentState>,
+        bytes_per_sec: u64,
+    ) -> Self {
+        let ranges = segment_ranges(partial.original_size, partial.segment_bytes);
+        let received = ranges
+            .iter()
+            .zip(&segments)
+            .map(|(range, segment)| match segment {
+                SegmentState::Missing { offset } => offset - range.start,
+                SegmentState::Receiving => 0,
+                SegmentState::Done(_) => range.end - range.start,
+            })
+            .sum();
+        Self {
+            target_path: partial.target_path.clone(),
+            staged,
+            entries: partial.entries.clone(),
+            on_collision: partial.on_collision,
+            original_name: partial.name.clone(),
+            original_size: partial.original_size,
+            segment_bytes: partial.segment_bytes,
+            segments: Mutex::new(segments),
+            meter: TransferMeter::new(received, partial.original_size, bytes_per_sec),
+            aborted: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    fn staged_files(&self) -> Vec<(PathBuf, u64)> {
+        self.staged
+            .iter()
+            .cloned()
+            .zip(self.entries.iter().map(|entry| entry.size))
+            .collect()
+    }
+
+    /// Segments still to be received, as a resume request lists them.
+    fn missing(&self) -> Vec<SegmentResume> {
+        self.segments
+            .lock()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, segment)| match segment {
+                SegmentState::Missing { offset } => Some(SegmentResume {
+                    index: index as u32,
+                    offset: *offset,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reserves missing segment `index` for a stream; returns its range and where to
+    /// continue from.
+    fn claim(&self, index: u32) -> Option<(Range<u64>, u64)> {
+        let mut segments = self.segments.lock();
+        let segment = segments.get_mut(index as usize)?;
+        let SegmentState::Missing { offset } = *segment else {
+            return None;
+        };
+        *segment = SegmentState::Receiving;
+        let range =
+            segment_ranges(self.original_size, self.segment_bytes).swap_remove(index as usize);
+        Some((range, offset))
+    }
+
+    /// Hands segment `index` back after its stream failed at `offset`.
+    fn release(&self, index: u32, offset: u64) {
+        if let Some(segment) = self.segments.lock().get_mut(index as usize) {
+            *segment = SegmentState::Missing { offset };
+        }
+    }
+
+    /// Marks segment `index` as stored. Once that was the last one, returns every segment
+    /// with the digest to check it against.
+    fn complete(&self, index: u32, sha256: [u8; 32]) -> Option<Vec<(Range<u64>, [u8; 32])>> {
+        let mut segments = self.segments.lock();
+        segments[index as usize] = SegmentState::Done(sha256);
+        segment_ranges(self.original_size, self.segment_bytes)
+            .into_iter()
+            .zip(segments.iter())
+            .map(|(range, segment)| match segment {
+                SegmentState::Done(sha256) => Some((range, *sha256)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Progress, speed and per-transfer rate limit shared by the streams moving one transfer.
+struct TransferMeter {
+    transferred: AtomicU64,
+    throughput: Mutex<Throughput>,
+    limit: RateLimiter,
+}
+
+impl TransferMeter {
+    fn new(transferred: u64, total: u64, bytes_per_sec: u64) -> Self {
+        Self {
+            transferred: AtomicU64::new(transferred),
+            throughput: Mutex::new(Throughput::new(transferred, total)),
+            limit: RateLimiter::new(bytes_per_sec),
+        }
+    }
+
+    /// Counts `bytes` more and returns the new total with the speed and time left.
+    fn add(&self, bytes: u64) -> (u64, Option<u64>, Option<Duration>) {
+        let transferred = self.transferred.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let mut throughput = self.throughput.lock();
+        throughput.update(transferred);
+        (transferred, throughput.bytes_per_sec(), throughput.eta())
+    }
+
+    fn pause(&self) {
+        self.throughput.lock().pause();
+    }
+}
+
+/// Pause and cancel switch shared by the task moving a transfer and whatever acts on it.
+#[derive(Clone)]
+struct TransferControl {
+    peer: std::net::SocketAddr,
+    name: String,
+    state: Arc<watch::Sender<ControlState>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+impl TransferControl {
+    fn new(peer: std::net::SocketAddr, name: String) -> Self {
+        Self {
+            peer,
+            name,
+            state: Arc::new(watch::Sender::new(ControlState::Running)),
+        }
+    }
+
+    /// Pauses or resumes the transfer; returns whether that changed anything.
+    fn set_paused(&self, paused: bool) -> bool {
+        self.state.send_if_modified(|state| {
+            let next = match (*state, paused) {
+                (ControlState::Running, true) => ControlState::Paused,
+                (ControlState::Paused, false) => ControlState::Running,
+                _ => return false,
+            };
+            *state = next;
+            true
+        })
+    }
+
+    fn cancel(&self) {
+        self.state.send_replace(ControlState::Cancelled);
+    }
+
+    fn is_paused(&self) -> bool {
+        *self.state.borrow() == ControlState::Paused
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *self.state.borrow() == ControlState::Cancelled
+    }
+
+    /// Returns as soon as the transfer is not paused.
+    async fn wait_unpaused(&self) {
+        let mut state = self.state.subscribe();
+        let _ = state.wait_for(|state| *state != ControlState::Paused).await;
+    }
+
+    async fn cancelled(&self) {
+        let mut state = self.state.subscribe();
+        let _ = state
+            .wait_for(|state| *state == ControlState::Cancelled)
+            .await;
+    }
+}
+
+/// Accepted download persisted in `<download_dir>/.dezap-partial/<id>/state.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialState {
+    id: u64,
+    name: String,
+    /// Destination file, or destination directory for a multi-file transfer.
+    target_path: PathBuf,
+    original_size: u64,
+    entries: Vec<ManifestEntry>,
+    #[serde(default)]
+    on_collision: CollisionPolicy,
+    /// Hex identity key of the sender; only that identity may resume the download.
+    peer_identity: String,
+    /// Downloads stored before segments existed come in as a single one.
+    #[serde(default)]
+    segment_bytes: u64,
+}
+
+/// Progress of one segment, appended as a line to `<id>/segments`. The last line about a
+/// segment wins.
+#[derive(Debug, Serialize, Deserialize)]
+struct SegmentRecord {
+    index: u32,
+    /// Position in the transfer up to which the segment is stored.
+    offset: u64,
+    /// Hex digest the sender sent, once the whole segment is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+/// Unfinished downloads kept on disk until they complete or are cancelled. Entry `n` of a
+/// download is staged in `<id>/files/<n>`.
+struct PartialStore {
+    dir: PathBuf,
+}
+
+impl PartialStore {
+    fn new(download_dir: &Path) -> Self {
+        Self {
+            dir: download_dir.join(PARTIAL_DIR),
+        }
+    }
+
+    fn staged_paths(&self, state: &PartialState) -> Vec<PathBuf> {
+        let files = self.dir.join(state.id.to_string()).join("files");
+        (0..state.entries.len())
+            .map(|index| files.join(index.to_string()))
+            .collect()
+    }
+
+    /// Records a newly accepted download and returns the staging file of each entry.
+    fn create(&self, state: &PartialState) -> Result<Vec<PathBuf>> {
+        let dir = self.dir.join(state.id.to_string());
+        fs::create_dir_all(dir.join("files"))
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let encoded = serde_json::to_vec_pretty(state).context("failed to encode partial state")?;
+        fs::write(dir.join("state.json"), encoded).context("failed to store partial state")?;
+        let staged = self.staged_paths(state);
+        for path in &staged {
+            fs::File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+        }
+        Ok(staged)
+    }
+
+    fn load(&self) -> Vec<PartialState> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| fs::read(entry.path().join("state.json")).ok())
+            .filter_map(|data| serde_json::from_slice(&data).ok())
+            .collect()
+    }
+
+    /// Notes that segment `index` of download `id` is stored up to `offset`, and with
+    /// `sha256` whether it is complete.
+    fn record_segment(&self, id: u64, index: u32, offset: u64, sha256: Option<[u8; 32]>) {
+        let record = SegmentRecord {
+            index,
+            offset,
+            sha256: sha256.as_ref().map(crypto::key_to_hex),
+        };
+        let path = self.dir.join(id.to_string()).join("segments");
+        let appended = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?;
+                writeln!(file, "{line}")?;
+                Ok(())
+            });
+        if let Err(err) = appended {
+            tracing::warn!(path = %path.display(), "failed to record download progress: {err:#}");
+        }
+    }
+
+    /// Where each segment of the download stands, i.e. what to resume from.
+    fn segments(&self, state: &PartialState) -> Vec<SegmentState> {
+        let ranges = segment_ranges(state.original_size, state.segment_bytes);
+        let mut segments: Vec<_> = ranges
+            .iter()
+            .map(|range| SegmentState::Missing {
+                offset: range.start,
+            })
+            .collect();
+        let log = fs::read_to_string(self.dir.join(state.id.to_string()).join("segments"));
+        for record in log
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<SegmentRecord>(line).ok())
+        {
+            let Some(range) = ranges.get(record.index as usize) else {
+                continue;
+            };
+            segments[record.index as usize] =
+                match record.sha256.as_deref().and_then(crypto::key_from_hex) {
+                    Some(sha256) => SegmentState::Done(sha256),
+                    None => SegmentState::Missing {
+                        offset: record.offset.clamp(range.start, range.end),
+                    },
+                };
+        }
+        // Everything arrived but was never placed: fetch the last segment again to finish.
+        if segments
+            .iter()
+            .all(|segment| matches!(segment, SegmentState::Done(_)))
+        {
+            if let (Some(last), Some(range)) = (segments.last_mut(), ranges.last()) {
+                *last = SegmentState::Missing {
+                    offset: range.start,
+                };
+            }
+        }
+        // A lone segment is written front to back, so the staged files show how far it got.
+        if let [SegmentState::Missing { offset }] = segments.as_mut_slice() {
+            let files: Vec<_> = self
+                .staged_paths(state)
+                .into_iter()
+                .zip(state.entries.iter().map(|entry| entry.size))
+                .collect();
+            *offset = manifest::staged_len(&files);
+        }
+        segments
+    }
+
+    fn remove(&self, id: u64) {
+        let _ = fs::remove_dir_all(self.dir.join(id.to_string()));
+    }
+
+    /// Deletes what earlier runs left behind: anything that is not a download with a
+    /// readable state, and downloads untouched for longer than `max_age`. Returns how many
+    /// entries went away.
+    fn sweep(&self, max_age: Option<Duration>) -> usize {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return 0;
+        };
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let state = fs::read(path.join("state.json"))
+                .ok()
+                .and_then(|data| serde_json::from_slice::<PartialState>(&data).ok())
+                .filter(|state| entry.file_name() == state.id.to_string().as_str());
+            let stale = max_age.is_some_and(|max_age| {
+                let touched = last_modified(&path).unwrap_or(SystemTime::UNIX_EPOCH);
+                now.duration_since(touched).unwrap_or_default() > max_age
+            });
+            if state.is_some() && !stale {
+                continue;
+            }
+            let result = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            if result.is_ok() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+/// Latest modification time of `dir` and the files below it.
+fn last_modified(dir: &Path) -> Option<SystemTime> {
+    let mut latest = fs::metadata(dir).and_then(|meta| meta.modified()).ok();
+    for entry in fs::read_dir(dir).ok()?.filter_map(|entry| entry.ok()) {
+        let modified = if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            last_modified(&entry.path())
+        } else {
+            entry.metadata().and_then(|meta| meta.modified()).ok()
+        };
+        latest = latest.max(modified);
+    }
+    latest
+}
+
+/// Segment size that spreads `size` bytes over up to `streams` streams, leaving most of the
+/// peer's stream allowance to messages and other transfers.
+fn segment_bytes(size: u64, streams: usize) -> u64 {
+    let most = (net::MAX_CONCURRENT_UNI_STREAMS / 4) as usize;
+    let streams = streams.clamp(1, most) as u64;
+    size.div_ceil(streams).max(MIN_SEGMENT_BYTES)
+}
+
+/// Number of segments `size` bytes are split into; a segment size of 0 means a single one.
+fn segment_count(size: u64, segment_bytes: u64) -> u64 {
+    match segment_bytes {
+        0 => 1,
+        bytes => size.div_ceil(bytes).max(1),
+    }
+}
+
+fn segment_ranges(size: u64, segment_bytes: u64) -> Vec<Range<u64>> {
+    let count = segment_count(size, segment_bytes);
+    let bytes = if count == 1 { size } else { segment_bytes };
+    (0..count)
+        .map(|index| index * bytes..((index + 1) * bytes).min(size))
+        .collect()
+}
+
+/// Progress inside the file of a multi-file transfer that `transferred` has reached.
+fn entry_progress(entries: &[ManifestEntry], transferred: u64) -> Option<EntryProgress> {
+    if manifest::is_single_file(entries) {
+        return None;
+    }
+    let mut start = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        if transferred < start + entry.size || index + 1 == entries.len() {
+            return Some(EntryProgress {
+                index,
+                count: entries.len(),
+                path: entry.path.clone(),
+                transferred: transferred.saturating_sub(start).min(entry.size),
+                size: entry.size,
+            });
+        }
+        start += entry.size;
+    }
+    None
+}
+
+async fn prepare_transfer(
+    paths: Vec<PathBuf>,
+    limits: &LimitsConfig,
+    forced: Option<Codec>,
+    peer_codecs: Vec<Codec>,
+    streams: usize,
+) -> Result<PreparedTransfer> {
+    let Some(original_path) = paths.first().cloned() else {
+        bail!("no files to send");
+    };
+    let roots = paths.len();
+    let max_file_bytes = limits.max_file_bytes;
+    let (sources, codec, estimated_ratio) = spawn_blocking(move || -> Result<_> {
+        let sources = manifest::collect(&paths)?;
+        let total = sources
+            .iter()
+            .try_fold(0u64, |total, source| total.checked_add(source.entry.size));
+        if !matches!(total, Some(total) if total <= max_file_bytes) {
+            bail!("transfer exceeds maximum permitted size");
+        }
+        // The largest file dominates the stream, so its samples decide the codec.
+        let largest = sources
+            .iter()
+            .max_by_key(|source| source.entry.size)
+            .expect("collect returns at least one file");
+        let sample = compression::sample_file(&largest.path)?;
+        let (codec, ratio) = compression::choose_codec(&sample, forced, &peer_codecs)?;
+        Ok((sources, codec, ratio))
     })
     .await??;
+    let name = if roots == 1 {
+        sources[0]
+            .entry
+            .path
+            .split('/')
+            .next()
+            .unwrap_or("file.bin")
+            .to_string()
+    } else {
+        format!("{roots} items")
+    };
+    let original_size = sources.iter().map(|source| source.entry.size).sum();
     let offer = FileOffer {
         id: rand::random(),
         name,
         original_size,
-        compressed_size,
+        codec,
+        estimated_ratio,
+        entries: sources.iter().map(|source| source.entry.clone()).collect(),
+        segment_bytes: segment_bytes(original_size, streams),
     };
+    // Leave room for the encryption envelope around the offer.
+    let encoded = protocol::encode_message(&WireMessage::Control(ControlMessage::FileOffer(
+        offer.clone(),
+    )))?;
+    if encoded.len() > protocol::MAX_FRAME_BYTES - 1024 {
+        bail!("too many files to offer in one transfer; send them in smaller batches");
+    }
     Ok(PreparedTransfer {
         offer,
-        original_path: path,
-        compressed_path,
+        sources: sources.into_iter().map(|source| source.path).collect(),
+        original_path,
+        accepted: false,
+        last_active: Instant::now(),
+        streams_opened: Arc::new(AtomicU32::new(0)),
     })
 }
 
+/// Sends `segments` of the source files, each on its own stream and all at once. The offer
+/// keeps the number of segments within the configured stream count.
 async fn transmit_prepared_file(
     connection: quinn::Connection,
+    ctx: &PeerContext,
     transfer: PreparedTransfer,
-    limits: LimitsConfig,
+    mut segments: Vec<SegmentResume>,
     event_tx: mpsc::Sender<ServiceEvent>,
+    control: TransferControl,
 ) -> Result<()> {
-    let mut file = tokio::fs::File::open(&transfer.compressed_path)
-        .await
-        .context("failed to open compressed file")?;
+    let size = transfer.offer.original_size;
+    let ranges = segment_ranges(size, transfer.offer.segment_bytes);
+    segments.sort_by_key(|segment| segment.index);
+    segments.dedup_by_key(|segment| segment.index);
+    let mut left = 0;
+    for segment in &segments {
+        let Some(range) = ranges.get(segment.index as usize) else {
+            bail!("'{}' has no segment {}", transfer.offer.name, segment.index);
+        };
+        if !(range.start..=range.end).contains(&segment.offset) {
+            bail!(
+                "resume offset {} is outside segment {} of '{}'",
+                segment.offset,
+                segment.index,
+                transfer.offer.name
+            );
+        }
+        left += range.end - segment.offset;
+    }
+    let meter = Arc::new(TransferMeter::new(
+        size - left,
+        size,
+        ctx.limits.transfer_upload_bytes_per_sec,
+    ));
+    let transfer = Arc::new(transfer);
+    let mut streams = JoinSet::new();
+    for segment in segments {
+        streams.spawn(transmit_segment(
+            connection.clone(),
+            ctx.clone(),
+            transfer.clone(),
+            segment,
+            ranges[segment.index as usize].clone(),
+            meter.clone(),
+            event_tx.clone(),
+            control.clone(),
+        ));
+    }
+    // Dropping the set on the first failure stops the other streams as well.
+    while let Some(sent) = streams.join_next().await {
+        sent.context("segment task failed")??;
+    }
+    Ok(())
+}
+
+/// Streams one segment from `segment.offset` to the end of `range`, compressing each chunk
+/// as it is read. Writes wait on QUIC flow control, so a slow receiver throttles reading and
+/// compression. Chunks are held back while `control` is paused, and the stream is reset once
+/// it is cancelled.
+#[allow(clippy::too_many_arguments)]
+async fn transmit_segment(
+    connection: quinn::Connection,
+    ctx: PeerContext,
+    transfer: Arc<PreparedTransfer>,
+    segment: SegmentResume,
+    range: Range<u64>,
+    meter: Arc<TransferMeter>,
+    event_tx: mpsc::Sender<ServiceEvent>,
+    control: TransferControl,
+) -> Result<()> {
+    let peer = connection.remote_address();
+    let size = transfer.offer.original_size;
+    let offset = segment.offset;
+    let attempt = transfer.streams_opened.fetch_add(1, Ordering::Relaxed);
+    let cipher = ctx
+        .meta
+        .file_cipher(transfer.offer.id, segment.index, attempt)?;
+    let files = transfer.files();
+    let prefix = files.clone();
+    let start = range.start;
+    // The digest covers the whole segment, including what was sent before a resume.
+    let mut hasher = spawn_blocking(move || -> Result<Sha256> {
+        let mut hasher = Sha256::new();
+        manifest::hash_range(&prefix, start, offset, &mut hasher)?;
+        Ok(hasher)
+    })
+    .await??;
+    let mut reader = EntryReader::open(files, offset).await?;
     let mut stream = connection
         .open_uni()
         .await
         .context("cannot open file-transfer stream")?;
-    protocol::write_message(
-        &mut stream,
-        &WireMessage::FileMeta(FileMetadata {
-            id: transfer.offer.id,
-            name: transfer.offer.name.clone(),
-            compressed_size: transfer.offer.compressed_size,
-            original_size: transfer.offer.original_size,
-        }),
-    )
-    .await?;
+    let header = WireMessage::FileMeta(FileMetadata {
+        id: transfer.offer.id,
+        name: transfer.offer.name.clone(),
+        original_size: size,
+        codec: transfer.offer.codec,
+        segment: segment.index,
+    });
+    protocol::write_message(&mut stream, &cipher.seal(FILE_META_OFFSET, &header)?).await?;
 
-    let mut transferred = 0u64;
-    let mut buffer = vec![0u8; limits.chunk_size_bytes];
-    loop {
-        let read = file.read(&mut buffer).await?;
-        if read == 0 {
-            break;
+    let mut encoder = ChunkEncoder::new(transfer.offer.codec)?;
+    let mut position = offset;
+    let chunk_size = ctx
+        .limits
+        .chunk_size_bytes
+        .clamp(1, compression::MAX_CHUNK_BYTES);
+    let mut buffer = vec![0u8; chunk_size];
+    let sent = async {
+        while position < range.end {
+            if control.is_paused() {
+                meter.pause();
+                control.wait_unpaused().await;
+            }
+            let want = buffer.len().min((range.end - position) as usize);
+            let read = reader.read(&mut buffer[..want]).await?;
+            if read == 0 {
+                bail!("'{}' shrank while it was being sent", transfer.offer.name);
+            }
+            hasher.update(&buffer[..read]);
+            let bytes = encoder.encode(&buffer[..read])?;
+            bandwidth::throttle(&[&meter.limit, &ctx.upload_limit], bytes.len()).await;
+            let chunk = WireMessage::FileChunk(FileChunk {
+                id: transfer.offer.id,
+                offset: position,
+                bytes,
+                last: position + read as u64 == range.end,
+            });
+            protocol::write_message(&mut stream, &cipher.seal(position, &chunk)?).await?;
+            mark_active(&ctx.pending_transfers, transfer.offer.id);
+            position += read as u64;
+            let (transferred, bytes_per_sec, eta) = meter.add(read as u64);
+            event_tx
+                .send(ServiceEvent::FileTransfer(FileTransferProgress {
+                    peer,
+                    id: transfer.offer.id,
+                    name: transfer.offer.name.clone(),
+                    transferred,
+                    total: size,
+                    direction: TransferDirection::Outgoing,
+                    path: Some(transfer.original_path.clone()),
+                    entry: entry_progress(&transfer.offer.entries, transferred),
+                    bytes_per_sec,
+                    eta,
+                    completed: false,
+                }))
+                .await
+                .ok();
         }
-        let chunk = WireMessage::FileChunk(FileChunk {
+        let digest = WireMessage::FileDigest(FileDigest {
             id: transfer.offer.id,
-            offset: transferred,
-            bytes: buffer[..read].to_vec(),
-            last: transferred + read as u64 >= transfer.offer.compressed_size,
+            sha256: hasher.finalize().into(),
         });
-        protocol::write_message(&mut stream, &chunk).await?;
-        transferred += read as u64;
-        event_tx
-            .send(ServiceEvent::FileTransfer(FileTransferProgress {
-                id: transfer.offer.id,
-                name: transfer.offer.name.clone(),
-                transferred,
-                total: transfer.offer.compressed_size,
-                direction: TransferDirection::Outgoing,
-                path: Some(transfer.original_path.clone()),
-                completed: transferred >= transfer.offer.compressed_size,
-            }))
-            .await
-            .ok();
+        protocol::write_message(&mut stream, &cipher.seal(FILE_DIGEST_OFFSET, &digest)?).await
+    };
+    let sent = tokio::select! {
+        sent = sent => sent,
+        _ = control.cancelled() => Ok(()),
+    };
+    if control.is_cancelled() {
+        let _ = stream.reset(quinn::VarInt::from_u32(STREAM_CANCELLED));
+        return Ok(());
     }
+    sent?;
+    // Completion is reported once the receiver acknowledges a verified copy.
     let _ = stream.finish();
-    tokio::fs::remove_file(&transfer.compressed_path).await.ok();
     Ok(())
 }
 
-async fn decompress_to_destination(source: &Path, destination: &Path) -> Result<()> {
-    let src = source.to_path_buf();
+/// Checks finished staging files against the digest of each segment and moves them to
+/// `destination`.
+///
+/// Returns where the download was placed, which differs from `destination` when a clashing
+/// single file was renamed, or `None`, leaving `destination` untouched, when the content
+/// does not match.
+async fn finalize_download(
+    staged: Vec<(PathBuf, u64)>,
+    entries: Vec<ManifestEntry>,
+    destination: &Path,
+    on_collision: CollisionPolicy,
+    expected: Vec<(Range<u64>, [u8; 32])>,
+) -> Result<Option<PathBuf>> {
     let dst = destination.to_path_buf();
-    spawn_blocking(move || -> Result<()> {
-        if let Some(parent) = dst.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("failed to create {}", parent.display()))?;
-        }
-        let input = std::fs::File::open(&src)
-            .with_context(|| format!("failed to open {}", src.display()))?;
-        let mut decoder = GzDecoder::new(input);
-        let mut output = std::fs::File::create(&dst)
-            .with_context(|| format!("failed to create {}", dst.display()))?;
-        std::io::copy(&mut decoder, &mut output).context("failed to decompress payload")?;
-        output.flush().ok();
-        Ok(())
+    spawn_blocking(move || -> Result<Option<PathBuf>> {
+        for (range, digest) in expected {
+            let mut hasher = Sha256::new();
+            manifest::hash_range(&staged, range.start, range.end, &mut hasher)?;
+            if <[u8; 32]>::from(hasher.finalize()) != digest {
+                return Ok(None);
+            }
+        }
+        let paths: Vec<_> = staged.into_iter().map(|(path, _)| path).collect();
+        manifest::place(&paths, &entries, &dst, on_collision).map(Some)
     })
-    .await??;
-    Ok(())
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn downloads_are_checked_against_the_streamed_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = b"integrity matters".repeat(64);
+        let digest: [u8; 32] = Sha256::digest(&content).into();
+        let partial = dir.path().join("partial");
+
+        fs::write(&partial, &content).unwrap();
+        let staged = vec![(partial.clone(), content.len() as u64)];
+        let entries = vec![ManifestEntry {
+            path: "good.bin".into(),
+            size: content.len() as u64,
+        }];
+        let bad = dir.path().join("bad.bin");
+        let policy = CollisionPolicy::Rename;
+        let whole = 0..content.len() as u64;
+        let expected = vec![(whole.clone(), [0; 32])];
+        let placed = finalize_download(staged.clone(), entries.clone(), &bad, policy, expected);
+        assert!(placed.await.unwrap().is_none());
+        assert!(!bad.exists());
+
+        let good = dir.path().join("out").join("good.bin");
+        let placed = finalize_download(staged, entries, &good, policy, vec![(whole, digest)]);
+        let placed = placed.await;
+        assert_eq!(placed.unwrap(), Some(good.clone()));
+        assert_eq!(fs::read(&good).unwrap(), content);
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn segments_resume_from_their_last_record() {
+        assert_eq!(segment_ranges(100, 40), vec![0..40, 40..80, 80..100]);
+        assert_eq!(segment_ranges(5, 0), vec![0..5]);
+        assert_eq!(segment_ranges(0, 40), vec![0..0]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = PartialStore::new(dir.path());
+        let partial = PartialState {
+            id: 7,
+            name: "big.bin".into(),
+            target_path: dir.path().join("big.bin"),
+            original_size: 100,
+            entries: vec![ManifestEntry {
+                path: "big.bin".into(),
+                size: 100,
+            }],
+            on_collision: CollisionPolicy::Rename,
+            peer_identity: String::new(),
+            segment_bytes: 40,
+        };
+        let staged = store.create(&partial).unwrap();
+        store.record_segment(7, 0, 40, Some([1; 32]));
+        store.record_segment(7, 1, 50, None);
+        store.record_segment(7, 1, 60, None);
+        let segments = store.segments(&partial);
+        assert_eq!(
+            segments,
+            vec![
+                SegmentState::Done([1; 32]),
+                SegmentState::Missing { offset: 60 },
+                SegmentState::Missing { offset: 80 },
+            ]
+        );
+
+        let transfer = IncomingTransfer::new(&partial, staged, segments, 0);
+        assert_eq!(transfer.meter.transferred.load(Ordering::Relaxed), 60);
+        assert_eq!(transfer.claim(1), Some((40..80, 60)));
+        assert_eq!(transfer.claim(1), None);
+        assert_eq!(transfer.complete(1, [2; 32]), None);
+        assert_eq!(transfer.claim(2), Some((80..100, 80)));
+        let expected = transfer.complete(2, [3; 32]).unwrap();
+        assert_eq!(expected[1], (40..80, [2; 32]));
+
+        // Stored but never placed: the last segment is fetched again.
+        store.record_segment(7, 1, 80, Some([2; 32]));
+        store.record_segment(7, 2, 100, Some([3; 32]));
+        let segments = store.segments(&partial);
+        assert_eq!(segments[2], SegmentState::Missing { offset: 80 });
+    }
+
+    #[test]
+    fn sweep_removes_leftovers_and_stale_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PartialStore::new(dir.path());
+        let state = |id| PartialState {
+            id,
+            name: format!("{id}.bin"),
+            target_path: dir.path().join(format!("{id}.bin")),
+            original_size: 4,
+            entries: vec![ManifestEntry {
+                path: format!("{id}.bin"),
+                size: 4,
+            }],
+            on_collision: CollisionPolicy::Rename,
+            peer_identity: String::new(),
+            segment_bytes: 0,
+        };
+        store.create(&state(1)).unwrap();
+        let old = store.create(&state(2)).unwrap();
+        fs::create_dir_all(store.dir.join("3").join("files")).unwrap();
+        fs::write(store.dir.join("stray"), b"?").unwrap();
+        let week_ago = SystemTime::now() - Duration::from_secs(7 * 24 * 60 * 60);
+        let touched = ["", "files", "state.json"].map(|path| store.dir.join("2").join(path));
+        for path in touched.iter().chain(&old) {
+            let file = fs::File::open(path).unwrap();
+            file.set_modified(week_ago).unwrap();
+        }
+
+        assert_eq!(store.sweep(None), 2);
+        assert_eq!(store.load().len(), 2);
+        assert_eq!(store.sweep(Some(Duration::from_secs(24 * 60 * 60))), 1);
+        let left: Vec<u64> = store.load().iter().map(|state| state.id).collect();
+        assert_eq!(left, vec![1]);
+    }
+
+    #[test]
+    fn last_seen_is_persisted_for_saved_peers_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let store = SavedPeersStore::new(path.clone()).unwrap();
+        let saved: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        store.record(saved, "alice", &[7u8; 32], true).unwrap();
+        assert!(store.find(saved).unwrap().last_seen.is_some());
+
+        assert!(store.seen(&[9u8; 32], 42).unwrap().is_none());
+        store.seen(&[7u8; 32], 42).unwrap().unwrap();
+        let reopened = SavedPeersStore::new(path.clone()).unwrap();
+        assert_eq!(reopened.find(saved).unwrap().last_seen, Some(42));
+        assert_eq!(reopened.list().len(), 1);
+
+        // Heartbeats only touch memory until the connection closes.
+        store.heard(&[7u8; 32], 50);
+        assert_eq!(store.find(saved).unwrap().last_seen, Some(50));
+        let reopened = SavedPeersStore::new(path.clone()).unwrap();
+        assert_eq!(reopened.find(saved).unwrap().last_seen, Some(42));
+        store.seen(&[7u8; 32], 60).unwrap();
+        let reopened = SavedPeersStore::new(path).unwrap();
+        assert_eq!(reopened.find(saved).unwrap().last_seen, Some(60));
+    }
+
+    #[test]
+    fn pins_follow_the_identity_key_not_the_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SavedPeersStore::new(dir.path().join("peers.json")).unwrap();
+        let listen: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let ephemeral: std::net::SocketAddr = "127.0.0.1:49152".parse().unwrap();
+        let other: std::net::SocketAddr = "127.0.0.1:49153".parse().unwrap();
+        store.record(listen, "alice", &[7u8; 32], true).unwrap();
+
+        // The same key coming back inbound from a fresh port reuses the pin.
+        assert!(store
+            .conflicting_pin(&[7u8; 32], ephemeral, "alice", false)
+            .is_none());
+        store.record(ephemeral, "alice", &[7u8; 32], false).unwrap();
+        let peers = store.list();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, listen);
+
+        // A different key claiming the pinned peer is refused either way.
+        let inbound = store
+            .conflicting_pin(&[8u8; 32], other, "alice", false)
+            .unwrap();
+        assert_eq!(inbound.addr, listen);
+        let dialed = store
+            .conflicting_pin(&[8u8; 32], listen, "mallory", true)
+            .unwrap();
+        assert_eq!(dialed.addr, listen);
+
+        // An unrelated newcomer gets an entry of its own.
+        assert!(store
+            .conflicting_pin(&[9u8; 32], other, "bob", false)
+            .is_none());
+        store.record(other, "bob", &[9u8; 32], false).unwrap();
+        assert_eq!(store.list().len(), 2);
+    }
+
+    #[test]
+    fn outbox_keeps_messages_until_acknowledged() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = HistoryWriter::new(dir.path().to_path_buf()).unwrap();
+        let outbox = OutboxStore::new(&history);
+        let peer: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let message = |id| QueuedMessage {
+            id,
+            author: "me".into(),
+            body: format!("message {id}"),
+            timestamp: 0,
+            amends: None,
+            reply_to: None,
+        };
+        for id in 1..=3 {
+            outbox.push(peer, &message(id)).unwrap();
+        }
+        let raw = fs::read(outbox.file_for(peer)).unwrap();
+        assert!(!raw.windows(9).any(|window| window == b"message 1"));
+
+        outbox.remove(peer, 2).unwrap();
+        outbox.remove(peer, 9).unwrap();
+        let reopened = OutboxStore::new(&HistoryWriter::new(dir.path().to_path_buf()).unwrap());
+        let ids: Vec<u64> = reopened
+            .list(peer)
+            .iter()
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        for id in ids {
+            reopened.remove(peer, id).unwrap();
+        }
+        assert!(!outbox.file_for(peer).exists());
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_the_cap_with_jitter() {
+        let policy = crate::config::ReconnectConfig {
+            enabled: true,
+            initial_delay_ms: 500,
+            max_delay_ms: 4_000,
+            jitter: 0.5,
+            max_attempts: 0,
+        };
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| policy.delay(attempt, 0.0).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, vec![500, 1_000, 2_000, 4_000, 4_000, 4_000]);
+        assert_eq!(policy.delay(2, -1.0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2, 1.0), Duration::from_millis(1_500));
+        assert_eq!(policy.delay(u32::MAX, 0.0), Duration::from_millis(4_000));
+    }
+
+    #[test]
+    fn offers_need_free_disk_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("not/created/yet");
+        assert!(ensure_disk_space([dir.path(), missing.as_path()], 1, 0).is_ok());
+        let err = ensure_disk_space([missing.as_path()], u64::MAX / 2, u64::MAX / 2);
+        assert!(matches!(err, Err(AbortReason::DiskFull { .. })));
+    }
+
+    #[test]
+    fn auto_accept_needs_a_trusted_peer_and_matching_files() {
+        let identity = [7u8; 32];
+        let addr: std::net::SocketAddr = ([127, 0, 0, 1], 5000).into();
+        let mut saved = SavedPeer {
+            addr,
+            name: "alice".into(),
+            identity_key: Some(crypto::key_to_hex(&identity)),
+            verified: false,
+            last_seen: None,
+        };
+        let offer = FileOfferNotice {
+            id: 1,
+            name: "photos".into(),
+            original_size: 300,
+            codec: Codec::None,
+            estimated_ratio: 1.0,
+            entries: vec![
+                ManifestEntry {
+                    path: "photos/a.JPG".into(),
+                    size: 100,
+                },
+                ManifestEntry {
+                    path: "photos/b.tar.gz".into(),
+                    size: 200,
+                },
+            ],
+            segment_bytes: 300,
+            expires_at: None,
+            peer: addr,
+        };
+        let by_name = AutoAcceptRule {
+            peer: "alice".into(),
+            extensions: vec![".jpg".into(), "tar.gz".into()],
+            ..AutoAcceptRule::default()
+        };
+        let rules = [by_name.clone()];
+        // Names are only trusted once the key behind them was verified.
+        assert!(
+            auto_accept_rule(&rules, std::slice::from_ref(&saved), &identity, &offer).is_none()
+        );
+        saved.verified = true;
+        assert!(
+            auto_accept_rule(&rules, std::slice::from_ref(&saved), &identity, &offer).is_some()
+        );
+        assert!(auto_accept_rule(&rules, std::slice::from_ref(&saved), &[8; 32], &offer).is_none());
+
+        let by_key = AutoAcceptRule {
+            peer: crypto::key_to_hex(&identity),
+            max_bytes: 299,
+            ..AutoAcceptRule::default()
+        };
+        assert!(auto_accept_rule(std::slice::from_ref(&by_key), &[], &identity, &offer).is_none());
+        let by_key = AutoAcceptRule {
+            max_bytes: 300,
+            ..by_key
+        };
+        assert!(auto_accept_rule(&[by_key], &[], &identity, &offer).is_some());
+
+        let only_jpg = AutoAcceptRule {
+            extensions: vec!["jpg".into(), "gz.".into()],
+            ..by_name
+        };
+        assert!(auto_accept_rule(&[only_jpg], &[saved], &identity, &offer).is_none());
+    }
+
+    #[tokio::test]
+    async fn paused_transfers_wait_until_resumed() {
+        let control = TransferControl::new(([127, 0, 0, 1], 5000).into(), "file.bin".into());
+        assert!(control.set_paused(true));
+        assert!(!control.set_paused(true));
+        let mut waiting = Box::pin(control.wait_unpaused());
+        let early = tokio::time::timeout(Duration::from_millis(20), &mut waiting).await;
+        assert!(early.is_err());
+        assert!(control.set_paused(false));
+        waiting.await;
+
+        control.cancel();
+        control.cancelled().await;
+        assert!(!control.set_paused(true));
+    }
+
+    #[tokio::test]
+    async fn only_accepted_transfers_go_idle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        fs::write(&path, b"payload").unwrap();
+        let limits = LimitsConfig::default();
+        let pending = Mutex::new(HashMap::new());
+        for (id, accepted) in [(1, false), (2, true)] {
+            let mut transfer = prepare_transfer(vec![path.clone()], &limits, None, Vec::new(), 1)
+                .await
+                .unwrap();
+            transfer.offer.id = id;
+            transfer.accepted = accepted;
+            pending.lock().insert(id, transfer);
+        }
+        assert!(take_idle_transfers(&pending, Duration::from_secs(60)).is_empty());
+        let idle = take_idle_transfers(&pending, Duration::ZERO);
+        assert_eq!(idle.len(), 1);
+        assert_eq!(idle[0].offer.id, 2);
+        assert!(pending.lock().contains_key(&1));
+    }
+
+    fn meta_for(dir: &Path, name: &str) -> ConnectionMeta {
+        let path = dir.join(name);
+        fs::create_dir_all(&path).unwrap();
+        ConnectionMeta::new(name, Arc::new(Identity::load_or_create(&path).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn plaintext_amendments_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = meta_for(dir.path(), "alice");
+        let bob = meta_for(dir.path(), "bob");
+        alice.derive(&bob.hello("bob", None)).unwrap();
+        bob.derive(&alice.hello("alice", None)).unwrap();
+
+        let delete = QueuedMessage {
+            id: 7,
+            author: "alice".into(),
+            body: String::new(),
+            timestamp: 0,
+            amends: Some(Amendment {
+                id: 3,
+                kind: AmendmentKind::Delete,
+            }),
+            reply_to: None,
+        };
+        let plain = WireMessage::Text(TextMessage {
+            id: delete.id,
+            seq: 0,
+            author: delete.author.clone(),
+            body: delete.body.clone(),
+            timestamp: delete.timestamp,
+            amends: delete.amends,
+            reply_to: None,
+        });
+        let err = open_text(&bob, plain).await.unwrap_err();
+        assert!(err.to_string().contains("unencrypted chat message"));
+        let sealed = seal_queued(&alice, &delete).unwrap();
+        let opened = open_text(&bob, sealed).await.unwrap();
+        assert_eq!(opened.amends, delete.amends);
+    }
+
+    #[tokio::test]
+    async fn messages_that_overtake_the_hello_wait_for_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = meta_for(dir.path(), "alice");
+        let bob = meta_for(dir.path(), "bob");
+        alice.derive(&bob.hello("bob", None)).unwrap();
+        let message = QueuedMessage {
+            id: 7,
+            author: "alice".into(),
+            body: "first".into(),
+            timestamp: 0,
+            amends: None,
+            reply_to: None,
+        };
+        let text = seal_queued(&alice, &message).unwrap();
+        let ack = bincode::serde::encode_to_vec(
+            Ack {
+                id: 3,
+                kind: AckKind::Read,
+            },
+            bincode::config::standard(),
+        )
+        .unwrap();
+        let ack = alice.seal(&ack).unwrap();
+
+        // Bob has not processed Alice's hello yet when both streams arrive.
+        let receiver = bob.clone();
+        let opened = tokio::spawn(async move {
+            let text = open_text(&receiver, text).await.unwrap();
+            (text, open_ack(&receiver, &ack).await.unwrap())
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!opened.is_finished());
+        bob.derive(&alice.hello("alice", None)).unwrap();
+        let (text, ack) = opened.await.unwrap();
+        assert_eq!(text.body, "first");
+        assert_eq!(ack.id, 3);
+    }
+
+    #[test]
+    fn peer_cancellation_is_told_apart_from_other_stream_errors() {
+        let code = quinn::VarInt::from_u32(STREAM_CANCELLED);
+        let reset = std::io::Error::from(quinn::ReadError::Reset(code));
+        let err: Result<()> = Err(reset).context("failed to read frame body");
+        assert!(is_cancelled_stream(&err.unwrap_err()));
+        let stopped = std::io::Error::from(quinn::WriteError::Stopped(code));
+        assert!(is_cancelled_stream(&anyhow::Error::new(stopped)));
+        let other = std::io::Error::from(quinn::ReadError::Reset(quinn::VarInt::from_u32(0)));
+        assert!(!is_cancelled_stream(&anyhow::Error::new(other)));
+    }
 }
@@ -4,6 +4,7 @@ use std::io::{self, Read, Write};
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
 use chacha20poly1305::{
     aead::{Aead, generic_array::GenericArray, KeyInit},
@@ -16,14 +17,16 @@ use crossterm::event::{
 };
 use crossterm::ExecutableCommand;
 use ratatui::style::Color;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use time::OffsetDateTime;
 
 use crate::cli::TuiCommand;
-use crate::config::AppConfig;
+use crate::config::{AppConfig, CollisionPolicy};
+use crate::protocol::{self, Amendment, AmendmentKind, Presence, PresenceState};
 use crate::service::{
-    DiscoveryEvent, FileOfferNotice, FileTransferProgress, SavedPeer, ServiceCommand, ServiceEvent,
-    TransferDirection,
+    DiscoveryEvent, EntryProgress, FileOfferNotice, FileTransferProgress, SavedPeer,
+    ServiceCommand, ServiceEvent, TransferDirection,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -31,24 +34,61 @@ pub enum PanelFocus {
     None,
     Discovered,
     Saved,
+    Transfers,
+    Offers,
+}
+
+/// Safety number dialog opened with Ctrl+V.
+#[derive(Debug, Clone)]
+pub struct SafetyDialog {
+    pub peer: SocketAddr,
+    pub name: String,
+    pub number: String,
+    pub verified: bool,
+}
+
+/// Accepted offer waiting for the user to decide what to do with existing files.
+#[derive(Debug, Clone)]
+struct CollisionPrompt {
+    peer: SocketAddr,
+    id: u64,
+    name: String,
+    path: PathBuf,
 }
 
 #[derive(Debug, Clone)]
 struct HistoryPrompt {
+    peer: SocketAddr,
     name: String,
     path: PathBuf,
     bytes: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 struct StoredHistoryEntry {
     timestamp: i64,
     outgoing: bool,
     author: String,
     text: String,
+    id: u64,
+    amends: Option<Amendment>,
+    reply_to: Option<u64>,
+}
+
+/// Fields every history record starts with; the others were appended to the layout later.
+#[derive(Debug, Deserialize)]
+struct HistoryEntryHead {
+    timestamp: i64,
+    outgoing: bool,
+    author: String,
+    text: String,
 }
 
 const MAX_MESSAGES: usize = 512;
+/// While typing goes on, it is announced again this often.
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+/// A peer counts as typing for this long after its last announcement.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
 
 /// High-level application state powering the TUI.
 pub struct App {
@@ -64,6 +104,14 @@ pub struct App {
     pub connection: ConnectionStatus,
     pub username: String,
     peer_names: HashMap<SocketAddr, String>,
+    listener: Option<(SocketAddr, bool)>,
+    online_peers: Vec<SocketAddr>,
+    active_peer: Option<SocketAddr>,
+    dialing: Option<SocketAddr>,
+    /// Dropped peers being redialed, with the attempt under way.
+    reconnecting: HashMap<SocketAddr, u32>,
+    /// Last availability announced by each online peer.
+    presence: HashMap<SocketAddr, Presence>,
     default_bind: SocketAddr,
     default_peer: Option<SocketAddr>,
     pub discovery_enabled: bool,
@@ -87,7 +135,21 @@ pub struct App {
     active_offer: Option<FileOfferNotice>,
     panel_focus: PanelFocus,
     saved_peer_index: usize,
+    transfer_index: usize,
+    offer_index: usize,
     history_prompt: Option<HistoryPrompt>,
+    collision_prompt: Option<CollisionPrompt>,
+    safety_dialog: Option<SafetyDialog>,
+    read_receipts: bool,
+    typing_indicators: bool,
+    /// Peer told it is typing and when, while the input holds an unsent message.
+    typing_sent: Option<(SocketAddr, Instant)>,
+    /// Peers typing to us, with their last announcement.
+    typing: HashMap<SocketAddr, Instant>,
+    /// Received message ids per peer not yet reported as read.
+    unread: HashMap<SocketAddr, Vec<u64>>,
+    /// Commands raised outside of key handling, sent by the event loop.
+    commands: Vec<ServiceCommand>,
 }
 
 impl App {
@@ -105,6 +167,12 @@ impl App {
             connection: ConnectionStatus::Disconnected,
             username: config.identity.username.clone(),
             peer_names: HashMap::new(),
+            listener: None,
+            online_peers: Vec::new(),
+            active_peer: None,
+            dialing: None,
+            reconnecting: HashMap::new(),
+            presence: HashMap::new(),
             default_bind: args.bind.unwrap_or(config.listen.bind_addr),
             default_peer: args.connect.or(config.peer.default_peer),
             discovery_enabled: !args.disable_discovery && config.discovery.enabled,
@@ -128,7 +196,17 @@ impl App {
             active_offer: None,
             panel_focus: PanelFocus::None,
             saved_peer_index: 0,
+            transfer_index: 0,
+            offer_index: 0,
             history_prompt: None,
+            collision_prompt: None,
+            safety_dialog: None,
+            read_receipts: config.ui.read_receipts,
+            typing_indicators: config.ui.typing_indicators,
+            typing_sent: None,
+            typing: HashMap::new(),
+            unread: HashMap::new(),
+            commands: Vec::new(),
         }
     }
 
@@ -141,6 +219,14 @@ impl App {
             return None;
         }
 
+        if self.collision_prompt.is_some() {
+            return self.handle_collision_key(&key);
+        }
+
+        if self.safety_dialog.is_some() {
+            return self.handle_safety_key(&key);
+        }
+
         let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
 
         if self.panel_focus != PanelFocus::None {
@@ -157,9 +243,26 @@ impl App {
                     self.panel_move_selection(1);
                     return None;
                 }
+                KeyCode::Enter if self.panel_focus == PanelFocus::Offers => {
+                    self.open_selected_offer();
+                    return None;
+                }
                 KeyCode::Enter => {
                     return self.panel_connect_selection();
                 }
+                KeyCode::Char('p') | KeyCode::Char(' ')
+                    if self.panel_focus == PanelFocus::Transfers =>
+                {
+                    return self.toggle_selected_transfer_pause();
+                }
+                KeyCode::Char('x') | KeyCode::Delete
+                    if self.panel_focus == PanelFocus::Transfers =>
+                {
+                    return self.cancel_selected_transfer();
+                }
+                KeyCode::Char('x') | KeyCode::Delete if self.panel_focus == PanelFocus::Offers => {
+                    return self.decline_selected_offer();
+                }
                 _ => {}
             }
             return None;
@@ -238,6 +341,29 @@ impl App {
                     self.copy_selected_message();
                     return None;
                 }
+                KeyCode::Char(ch)
+                    if !ctrl && !self.raw_transcript && ch.eq_ignore_ascii_case(&'e') =>
+                {
+                    self.start_amending(AmendmentKind::Edit);
+                    return None;
+                }
+                KeyCode::Char(ch)
+                    if !ctrl && !self.raw_transcript && ch.eq_ignore_ascii_case(&'r') =>
+                {
+                    self.start_amending(AmendmentKind::React);
+                    return None;
+                }
+                KeyCode::Char(ch)
+                    if !ctrl && !self.raw_transcript && ch.eq_ignore_ascii_case(&'d') =>
+                {
+                    return self.start_amending(AmendmentKind::Delete);
+                }
+                KeyCode::Char(ch)
+                    if !ctrl && !self.raw_transcript && ch.eq_ignore_ascii_case(&'q') =>
+                {
+                    self.start_reply();
+                    return None;
+                }
                 KeyCode::Char('/') if !ctrl && self.raw_transcript => {
                     self.start_transcript_search();
                     return None;
@@ -252,18 +378,18 @@ impl App {
         match key.code {
             KeyCode::Char('c') if ctrl => {
                 self.should_quit = true;
-                return Some(ServiceCommand::Disconnect);
+                return Some(ServiceCommand::Disconnect { peer: None });
             }
             KeyCode::Esc => {
                 if let Mode::IncomingFile(id) = self.mode {
-                    let label = self
+                    let (label, peer) = self
                         .active_offer
                         .as_ref()
-                        .map(|offer| offer.name.clone())
-                        .unwrap_or_else(|| "file".into());
+                        .map(|offer| (offer.name.clone(), Some(offer.peer)))
+                        .unwrap_or_else(|| ("file".into(), None));
                     self.status_line = format!("Declined '{}'", label);
                     self.mark_offer_handled();
-                    return Some(ServiceCommand::DeclineFile { id });
+                    return peer.map(|peer| ServiceCommand::DeclineFile { peer, id });
                 }
                 self.mode = Mode::Chat;
                 if !self.chat_focus {
@@ -309,8 +435,17 @@ impl App {
                 return None;
             }
             KeyCode::Char('x') if ctrl => {
+                let peer = self.active_target()?;
                 self.status_line = "Disconnecting…".into();
-                return Some(ServiceCommand::Disconnect);
+                return Some(ServiceCommand::Disconnect { peer: Some(peer) });
+            }
+            KeyCode::Char('n') if ctrl => {
+                self.cycle_active_peer();
+                return None;
+            }
+            KeyCode::Char('v') if ctrl => {
+                let peer = self.active_target()?;
+                return Some(ServiceCommand::ShowSafetyNumber { peer });
             }
             KeyCode::Char('p') if ctrl => {
                 self.focus_discovered_panel();
@@ -325,6 +460,14 @@ impl App {
                 self.focus_saved_panel();
                 return None;
             }
+            KeyCode::Char('t') if ctrl => {
+                self.focus_transfers_panel();
+                return None;
+            }
+            KeyCode::Char('o') if ctrl => {
+                self.focus_offers_panel();
+                return None;
+            }
             KeyCode::Char('r') if ctrl => {
                 self.mode = Mode::DiscoveryNetwork;
                 self.input = self
@@ -333,16 +476,75 @@ impl App {
                     .unwrap_or_else(|| "255.255.255.255".into());
                 self.status_line = "Discovery broadcast IP (blank = auto)".into();
             }
-            KeyCode::Enter => return self.commit_input(),
+            KeyCode::Enter => {
+                let command = self.commit_input();
+                if matches!(command, Some(ServiceCommand::SendText { .. })) {
+                    // The message itself ends the peer's typing indicator.
+                    self.typing_sent = None;
+                }
+                self.update_typing();
+                return command;
+            }
             KeyCode::Char(ch) => {
                 self.input.push(ch);
             }
             _ => {}
         }
         self.update_command_hint();
+        self.update_typing();
         None
     }
 
+    /// Tells the active peer a message is being typed while the chat input holds one,
+    /// repeating it every `TYPING_REFRESH`, and that typing stopped once it is cleared.
+    fn update_typing(&mut self) {
+        if !self.typing_indicators {
+            return;
+        }
+        let composing = matches!(self.mode, Mode::Chat | Mode::Reply(_))
+            && !self.input.trim().is_empty()
+            && !self.input.starts_with("d/");
+        let target = self.active_target_online().filter(|_| composing);
+        match (target, self.typing_sent) {
+            (Some(peer), Some((sent_to, at)))
+                if sent_to == peer && at.elapsed() < TYPING_REFRESH => {}
+            (Some(peer), sent) => {
+                // Switching peers mid-message withdraws the indicator from the previous one.
+                if let Some((sent_to, _)) = sent.filter(|(sent_to, _)| *sent_to != peer) {
+                    self.commands.push(ServiceCommand::SetTyping {
+                        peer: sent_to,
+                        typing: false,
+                    });
+                }
+                self.commands
+                    .push(ServiceCommand::SetTyping { peer, typing: true });
+                self.typing_sent = Some((peer, Instant::now()));
+            }
+            (None, Some((peer, _))) => {
+                self.commands.push(ServiceCommand::SetTyping {
+                    peer,
+                    typing: false,
+                });
+                self.typing_sent = None;
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Name of the active peer while it is typing to us.
+    pub fn typing_peer(&self) -> Option<String> {
+        let peer = self.active_peer?;
+        self.typing
+            .get(&peer)
+            .filter(|at| at.elapsed() < TYPING_TIMEOUT)
+            .map(|_| {
+                self.peer_names
+                    .get(&peer)
+                    .cloned()
+                    .unwrap_or_else(|| peer.to_string())
+            })
+    }
+
     fn toggle_chat_focus(&mut self) {
         if self.chat_focus {
             self.leave_chat_focus();
@@ -402,6 +604,95 @@ impl App {
             "Saved peers focused · ↑/↓ to navigate, Enter to connect, Esc to cancel".into();
     }
 
+    fn focus_transfers_panel(&mut self) {
+        if self.transfers.is_empty() {
+            self.status_line = "No transfers yet.".into();
+            return;
+        }
+        self.transfer_index = self
+            .transfer_index
+            .min(self.transfers.len().saturating_sub(1));
+        self.panel_focus = PanelFocus::Transfers;
+        self.status_line =
+            "Transfers focused · ↑/↓ to navigate, p to pause/resume, x to cancel, Esc to exit"
+                .into();
+    }
+
+    fn focus_offers_panel(&mut self) {
+        if self.active_offer.is_none() {
+            self.status_line = "No file offers waiting.".into();
+            return;
+        }
+        self.offer_index = self.offer_index.min(self.offer_queue.len());
+        self.panel_focus = PanelFocus::Offers;
+        self.status_line =
+            "Offers focused · ↑/↓ to navigate, Enter to answer, x to decline, Esc to exit".into();
+    }
+
+    /// Brings the highlighted offer up in the save dialog; the one it replaces goes back to
+    /// the front of the queue.
+    fn open_selected_offer(&mut self) {
+        let Some(index) = self.selected_offer() else {
+            return;
+        };
+        self.exit_panel_focus();
+        if index == 0 {
+            return;
+        }
+        let Some(offer) = self.offer_queue.remove(index - 1) else {
+            return;
+        };
+        if let Some(current) = self.active_offer.take() {
+            self.offer_queue.push_front(current);
+        }
+        self.activate_offer(offer);
+        self.offer_index = 0;
+    }
+
+    fn decline_selected_offer(&mut self) -> Option<ServiceCommand> {
+        let index = self.selected_offer()?;
+        let offer = if index == 0 {
+            self.active_offer.clone()?
+        } else {
+            self.offer_queue.get(index - 1)?.clone()
+        };
+        self.drop_offer(offer.id);
+        self.status_line = format!("Declined '{}'", offer.name);
+        Some(ServiceCommand::DeclineFile {
+            peer: offer.peer,
+            id: offer.id,
+        })
+    }
+
+    fn toggle_selected_transfer_pause(&mut self) -> Option<ServiceCommand> {
+        let transfer = self.transfers.get(self.transfer_index)?;
+        if transfer.completed {
+            self.status_line = format!("'{}' already finished", transfer.name);
+            return None;
+        }
+        let (peer, id) = (transfer.peer, transfer.id);
+        if transfer.paused {
+            self.status_line = format!("Resuming '{}'…", transfer.name);
+            Some(ServiceCommand::ResumeTransfer { peer, id })
+        } else {
+            self.status_line = format!("Pausing '{}'…", transfer.name);
+            Some(ServiceCommand::PauseTransfer { peer, id })
+        }
+    }
+
+    fn cancel_selected_transfer(&mut self) -> Option<ServiceCommand> {
+        let transfer = self.transfers.get(self.transfer_index)?;
+        if transfer.completed {
+            self.status_line = format!("'{}' already finished", transfer.name);
+            return None;
+        }
+        self.status_line = format!("Cancelling '{}'…", transfer.name);
+        Some(ServiceCommand::CancelTransfer {
+            peer: transfer.peer,
+            id: transfer.id,
+        })
+    }
+
     fn exit_panel_focus(&mut self) {
         self.panel_focus = PanelFocus::None;
         self.status_line.clear();
@@ -441,6 +732,41 @@ impl App {
                 let peer = &self.saved_peers[self.saved_peer_index];
                 self.status_line = format!("Selected {} ({})", peer.name, peer.addr);
             }
+            PanelFocus::Transfers => {
+                if self.transfers.is_empty() {
+                    return;
+                }
+                let len = self.transfers.len() as isize;
+                let mut next = self.transfer_index as isize + delta;
+                if next < 0 {
+                    next = len - 1;
+                }
+                if next >= len {
+                    next = 0;
+                }
+                self.transfer_index = next as usize;
+                let transfer = &self.transfers[self.transfer_index];
+                self.status_line =
+                    format!("Selected '{}' (p pause/resume, x cancel)", transfer.name);
+            }
+            PanelFocus::Offers => {
+                let len = self.offer_queue.len() as isize + 1;
+                let mut next = self.offer_index as isize + delta;
+                if next < 0 {
+                    next = len - 1;
+                }
+                if next >= len {
+                    next = 0;
+                }
+                self.offer_index = next as usize;
+                let name = self
+                    .queued_offers()
+                    .nth(self.offer_index)
+                    .map(|offer| offer.name.clone());
+                if let Some(name) = name {
+                    self.status_line = format!("Selected '{name}' (Enter answer, x decline)");
+                }
+            }
             PanelFocus::None => {}
         }
     }
@@ -596,10 +922,9 @@ impl App {
         if status.success() {
             Ok(())
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("{bin} failed with status {status}"),
-            ))
+            Err(io::Error::other(format!(
+                "{bin} failed with status {status}"
+            )))
         }
     }
 
@@ -610,11 +935,12 @@ impl App {
         if let Some(rest) = self.input.strip_prefix("d/") {
             let trimmed = rest.trim();
             let cmds = [
-                "clear", "save", "search", "mark", "last", "status", "purge", "anon", "help", "history",
+                "clear", "save", "search", "mark", "last", "status", "purge", "anon", "help",
+                "history", "trust", "presence",
             ];
             if trimmed.is_empty() {
                 self.status_line =
-                    "Commands: clear, save, search, mark, last, status, purge, anon, help, history"
+                    "Commands: clear, save, search, mark, last, status, purge, anon, help, history, trust, presence"
                         .into();
             } else if cmds.iter().any(|c| c.starts_with(trimmed)) {
                 self.status_line = format!("Commands: {}", cmds.join(", "));
@@ -693,10 +1019,10 @@ impl App {
                     let term = args.join(" ").to_lowercase();
                     let mut added = 0usize;
                     for (idx, msg) in self.messages.iter().enumerate() {
-                        if msg.text.to_lowercase().contains(&term) {
-                            if self.marked_messages.insert(idx) {
-                                added += 1;
-                            }
+                        if msg.text.to_lowercase().contains(&term)
+                            && self.marked_messages.insert(idx)
+                        {
+                            added += 1;
                         }
                     }
                     if added > 0 {
@@ -746,7 +1072,7 @@ impl App {
             }
             "help" | "" => {
                 self.status_line =
-                    "Commands: clear, save <path>, search <term>, mark <term>, last <n>, status, purge, anon, help, history list|rm <peer>|rm-all"
+                    "Commands: clear, save <path>, search <term>, mark <term>, last <n>, status, purge, anon, help, history list|rm <peer>|rm-all, trust <peer>, presence <state> [status]"
                         .into();
                 self.input.clear();
                 None
@@ -756,6 +1082,38 @@ impl App {
                 self.input.clear();
                 None
             }
+            "presence" => {
+                self.input.clear();
+                match args.first().map(|raw| raw.parse::<PresenceState>()) {
+                    Some(Ok(state)) => {
+                        let status = (args.len() > 1).then(|| args[1..].join(" "));
+                        self.status_line = match &status {
+                            Some(status) => format!("You are {state}: {status}"),
+                            None => format!("You are {state}"),
+                        };
+                        Some(ServiceCommand::SetPresence { state, status })
+                    }
+                    _ => {
+                        self.show_warning("Usage: d/presence online|away|busy [status]");
+                        None
+                    }
+                }
+            }
+            "trust" => {
+                self.input.clear();
+                match args.first().map(|raw| raw.parse::<SocketAddr>()) {
+                    Some(Ok(addr)) => {
+                        self.status_line = format!(
+                            "Identity pin for {addr} cleared; reconnect to trust the new key"
+                        );
+                        Some(ServiceCommand::ResetPeerIdentity { addr })
+                    }
+                    _ => {
+                        self.show_warning("Usage: d/trust <ip:port>");
+                        None
+                    }
+                }
+            }
             _ => {
                 self.show_warning("Unknown command. Try d/help");
                 self.input.clear();
@@ -839,6 +1197,9 @@ impl App {
                 Ok(entries) => {
                     let mut removed = 0;
                     for entry in entries.filter_map(|e| e.ok()) {
+                        if entry.file_name() == crate::crypto::IDENTITY_KEY_FILE {
+                            continue;
+                        }
                         let _ = fs::remove_file(entry.path());
                         removed += 1;
                     }
@@ -850,6 +1211,48 @@ impl App {
         }
     }
 
+    fn handle_safety_key(&mut self, key: &KeyEvent) -> Option<ServiceCommand> {
+        let dialog = self.safety_dialog.as_ref()?;
+        match key.code {
+            KeyCode::Char(ch) if ch.eq_ignore_ascii_case(&'v') => {
+                let addr = dialog.peer;
+                let verified = !dialog.verified;
+                Some(ServiceCommand::SetPeerVerified { addr, verified })
+            }
+            KeyCode::Esc | KeyCode::Enter => {
+                self.safety_dialog = None;
+                self.status_line.clear();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_collision_key(&mut self, key: &KeyEvent) -> Option<ServiceCommand> {
+        let prompt = self.collision_prompt.clone()?;
+        let on_collision = match key.code {
+            KeyCode::Char(ch) if ch.eq_ignore_ascii_case(&'o') => CollisionPolicy::Overwrite,
+            KeyCode::Char(ch) if ch.eq_ignore_ascii_case(&'r') => CollisionPolicy::Rename,
+            KeyCode::Esc => {
+                self.collision_prompt = None;
+                self.status_line = format!("Declined '{}'", prompt.name);
+                return Some(ServiceCommand::DeclineFile {
+                    peer: prompt.peer,
+                    id: prompt.id,
+                });
+            }
+            _ => return None,
+        };
+        self.collision_prompt = None;
+        self.status_line = "Preparing to receive file…".into();
+        Some(ServiceCommand::AcceptFile {
+            peer: prompt.peer,
+            id: prompt.id,
+            path: prompt.path,
+            on_collision: Some(on_collision),
+        })
+    }
+
     fn consume_history_prompt(&mut self, key: &KeyEvent) -> bool {
         let Some(prompt) = self.history_prompt.clone() else {
             return false;
@@ -970,16 +1373,25 @@ impl App {
                 skipped += 1;
                 continue;
             }
-            let decoded: StoredHistoryEntry = match bincode::serde::decode_from_slice(
-                &raw,
-                bincode::config::standard(),
-            ) {
-                Ok((entry, _)) => entry,
-                Err(_) => {
+            let decoded = match decode_history_entry(&raw) {
+                Some(entry) => entry,
+                None => {
                     skipped += 1;
                     continue;
                 }
             };
+            if let Some(amendment) = decoded.amends {
+                total -= 1;
+                apply_amendment(
+                    &mut entries,
+                    prompt.peer,
+                    decoded.author,
+                    amendment,
+                    decoded.text,
+                    decoded.outgoing,
+                );
+                continue;
+            }
             let author = decoded.author;
             let direction = if decoded.outgoing {
                 MessageDirection::Outgoing(author.clone())
@@ -992,6 +1404,13 @@ impl App {
                 text: decoded.text,
                 timestamp: OffsetDateTime::from_unix_timestamp(decoded.timestamp)
                     .unwrap_or_else(|_| OffsetDateTime::now_utc()),
+                id: (decoded.id != 0).then_some(decoded.id),
+                receipt: None,
+                peer: Some(prompt.peer),
+                edited: false,
+                deleted: false,
+                reactions: Vec::new(),
+                reply_to: decoded.reply_to,
             });
         }
         let total_count = total;
@@ -1099,17 +1518,25 @@ impl App {
             self.activate_offer(offer);
         } else {
             self.offer_queue.push_back(offer);
-            self.status_line = format!("Queued {} pending transfer(s)", self.offer_queue.len());
+            self.status_line = format!(
+                "Queued {} pending transfer(s) · Ctrl+O to list them",
+                self.offer_queue.len()
+            );
         }
     }
 
     fn activate_offer(&mut self, offer: FileOfferNotice) {
-        let suggested = self.download_dir.join(&offer.name);
+        // Trees are recreated inside the chosen directory, so suggest the directory itself.
+        let (suggested, what) = if offer.is_single_file() {
+            (self.download_dir.join(&offer.name), "path")
+        } else {
+            (self.download_dir.clone(), "directory")
+        };
         self.input = suggested.to_string_lossy().to_string();
         self.mode = Mode::IncomingFile(offer.id);
         self.active_offer = Some(offer.clone());
         self.status_line = format!(
-            "Incoming '{}' from {} ({}). Edit path & Enter to accept, Esc to decline.",
+            "Incoming '{}' from {} ({}). Edit {what} & Enter to accept, Esc to decline.",
             offer.name,
             offer.peer,
             human_size(offer.original_size)
@@ -1133,6 +1560,25 @@ impl App {
         self.history_disabled
     }
 
+    pub fn safety_dialog(&self) -> Option<&SafetyDialog> {
+        self.safety_dialog.as_ref()
+    }
+
+    /// Label and verification state of the active peer, when one is online.
+    pub fn active_peer_trust(&self) -> Option<(String, bool)> {
+        let peer = self.active_target_online()?;
+        let verified = self
+            .saved_peers
+            .iter()
+            .any(|entry| entry.addr == peer && entry.verified);
+        let name = self
+            .peer_names
+            .get(&peer)
+            .cloned()
+            .unwrap_or_else(|| peer.to_string());
+        Some((name, verified))
+    }
+
     pub fn selected_discovered(&self) -> Option<usize> {
         if self.discovered.is_empty() {
             None
@@ -1180,8 +1626,16 @@ impl App {
                 }
             }
             ConnectionStatus::Connecting(addr) => format!("Connecting to {addr}"),
+            ConnectionStatus::Reconnecting { peer, attempt } => {
+                format!("Reconnecting to {peer} (attempt {attempt})")
+            }
             ConnectionStatus::Connected { peer, name } => format!("Connected to {name} ({peer})"),
         };
+        let conn = if self.online_peers.len() > 1 {
+            format!("{conn} +{} more", self.online_peers.len() - 1)
+        } else {
+            conn
+        };
         format!(
             "User: @{} | Messages: {} | {} | Discovery: {}{} | History: {}",
             self.username,
@@ -1195,6 +1649,70 @@ impl App {
         )
     }
 
+    pub fn selected_transfer(&self) -> Option<usize> {
+        if self.transfers.is_empty() {
+            None
+        } else {
+            Some(self.transfer_index.min(self.transfers.len() - 1))
+        }
+    }
+
+    /// Offers waiting for an answer, the one in the save dialog first.
+    pub fn queued_offers(&self) -> impl Iterator<Item = &FileOfferNotice> {
+        self.active_offer.iter().chain(self.offer_queue.iter())
+    }
+
+    pub fn selected_offer(&self) -> Option<usize> {
+        self.active_offer
+            .as_ref()
+            .map(|_| self.offer_index.min(self.offer_queue.len()))
+    }
+
+    /// One line per waiting offer for the offers panel.
+    pub fn offer_labels(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.queued_offers()
+            .map(|offer| {
+                let mut label = format!(
+                    "{} ({}) from {}",
+                    offer.name,
+                    human_size(offer.original_size),
+                    self.peer_label(offer.peer)
+                );
+                if let Some(expires_at) = offer.expires_at {
+                    let secs = expires_at.saturating_duration_since(now).as_secs();
+                    label.push_str(&format!(" · {}:{:02} left", secs / 60, secs % 60));
+                }
+                label
+            })
+            .collect()
+    }
+
+    /// One line per saved peer: its presence while online, or when it was last seen.
+    pub fn saved_peer_labels(&self) -> Vec<String> {
+        let now = protocol::utc_timestamp();
+        self.saved_peers
+            .iter()
+            .map(|peer| {
+                let mut label = format!("{} ({})", peer.name, peer.addr);
+                if self.online_peers.contains(&peer.addr) {
+                    match self.presence.get(&peer.addr) {
+                        Some(presence) => {
+                            label.push_str(&format!(" · {}", presence.state));
+                            if let Some(status) = &presence.status {
+                                label.push_str(&format!(": {status}"));
+                            }
+                        }
+                        None => label.push_str(" · online"),
+                    }
+                } else if let Some(last_seen) = peer.last_seen {
+                    label.push_str(&format!(" · seen {}", seen_ago(now - last_seen)));
+                }
+                label
+            })
+            .collect()
+    }
+
     pub fn selected_saved(&self) -> Option<usize> {
         if self.saved_peers.is_empty() {
             None
@@ -1332,19 +1850,48 @@ impl App {
                     self.show_warning("Cannot send empty message");
                     return None;
                 }
+                let peer = self.message_target()?;
                 let text = self.input.clone();
                 self.input.clear();
-                return Some(ServiceCommand::SendText { text });
+                return Some(ServiceCommand::SendText {
+                    peer,
+                    text,
+                    reply_to: None,
+                });
+            }
+            Mode::Reply(id) => {
+                if self.input.trim().is_empty() {
+                    self.show_warning("Cannot send empty message");
+                    return None;
+                }
+                let peer = self
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.id == Some(id))
+                    .and_then(|entry| entry.peer)?;
+                let text = std::mem::take(&mut self.input);
+                self.mode = Mode::Chat;
+                self.status_line.clear();
+                return Some(ServiceCommand::SendText {
+                    peer,
+                    text,
+                    reply_to: Some(id),
+                });
             }
             Mode::File => {
                 if self.input.trim().is_empty() {
                     self.show_warning("Provide a file path");
                     return None;
                 }
+                let peer = self.active_target()?;
                 let path = PathBuf::from(self.input.trim());
                 self.input.clear();
                 self.mode = Mode::Chat;
-                return Some(ServiceCommand::SendFile { path });
+                return Some(ServiceCommand::SendFile {
+                    peer,
+                    paths: vec![path],
+                });
             }
             Mode::ListenAddress => match self.input.trim().parse::<SocketAddr>() {
                 Ok(addr) => {
@@ -1421,6 +1968,33 @@ impl App {
                     .unwrap_or_else(|| "Discovery reset to default broadcast".into());
                 return Some(ServiceCommand::SetDiscoveryTarget { target });
             }
+            Mode::EditMessage(id) | Mode::React(id) => {
+                let kind = if self.mode == Mode::EditMessage(id) {
+                    AmendmentKind::Edit
+                } else {
+                    AmendmentKind::React
+                };
+                if kind == AmendmentKind::Edit && self.input.trim().is_empty() {
+                    self.show_warning(
+                        "An edit cannot empty a message; press D while browsing to delete it",
+                    );
+                    return None;
+                }
+                let peer = self
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.id == Some(id))
+                    .and_then(|entry| entry.peer)?;
+                let text = std::mem::take(&mut self.input);
+                self.mode = Mode::Chat;
+                self.status_line.clear();
+                return Some(ServiceCommand::AmendMessage {
+                    peer,
+                    amendment: Amendment { id, kind },
+                    text,
+                });
+            }
             Mode::IncomingFile(id) => {
                 let trimmed = self.input.trim();
                 if trimmed.is_empty() {
@@ -1428,11 +2002,17 @@ impl App {
                     return None;
                 }
                 let path = PathBuf::from(trimmed);
+                let peer = self.active_offer.as_ref().map(|offer| offer.peer);
                 self.input.clear();
                 self.mode = Mode::Chat;
                 self.status_line = "Preparing to receive file…".into();
                 self.mark_offer_handled();
-                return Some(ServiceCommand::AcceptFile { id, path });
+                return peer.map(|peer| ServiceCommand::AcceptFile {
+                    peer,
+                    id,
+                    path,
+                    on_collision: None,
+                });
             }
         }
         None
@@ -1441,27 +2021,39 @@ impl App {
     pub fn handle_service_event(&mut self, event: ServiceEvent) {
         match event {
             ServiceEvent::Connected { peer, name } => {
-                self.history_prompt = None;
+                self.reconnecting.remove(&peer);
                 self.peer_names.insert(peer, name.clone());
-                self.connection = ConnectionStatus::Connected {
-                    peer,
-                    name: name.clone(),
-                };
+                if !self.online_peers.contains(&peer) {
+                    self.online_peers.push(peer);
+                }
+                let dialed = self.dialing.take() == Some(peer);
+                let activate = dialed || self.active_target_online().is_none();
                 self.push_system(format!("Connected to {name} ({peer})"));
-                self.maybe_prompt_history(peer, &name);
+                if activate {
+                    self.set_active_peer(peer);
+                } else {
+                    self.refresh_connection();
+                }
             }
             ServiceEvent::Connecting { peer } => {
-                self.connection = ConnectionStatus::Connecting(peer);
+                self.dialing = Some(peer);
+                self.refresh_connection();
                 self.status_line = format!("Connecting to {peer}…");
             }
+            ServiceEvent::Reconnecting { peer, attempt } => {
+                self.reconnecting.insert(peer, attempt);
+                self.refresh_connection();
+                self.status_line = format!(
+                    "Connection to {} lost · reconnecting (attempt {attempt})…",
+                    self.peer_label(peer)
+                );
+            }
             ServiceEvent::Listening {
                 addr,
                 password_protected,
             } => {
-                self.connection = ConnectionStatus::Listening {
-                    addr,
-                    locked: password_protected,
-                };
+                self.listener = Some((addr, password_protected));
+                self.refresh_connection();
                 if password_protected {
                     self.push_system(format!("Listening on {addr} (locked)"));
                 } else {
@@ -1469,33 +2061,130 @@ impl App {
                 }
             }
             ServiceEvent::ListenerStopped => {
-                self.connection = ConnectionStatus::Disconnected;
+                self.listener = None;
+                self.refresh_connection();
                 self.push_system("Listener stopped");
             }
-            ServiceEvent::Disconnected => {
-                self.history_prompt = None;
-                self.connection = ConnectionStatus::Disconnected;
-                self.push_system("Disconnected");
+            ServiceEvent::Disconnected { peer } => {
+                self.online_peers.retain(|addr| *addr != peer);
+                if self.dialing == Some(peer) {
+                    self.dialing = None;
+                }
+                let label = self.peer_label(peer);
+                self.unread.remove(&peer);
+                self.presence.remove(&peer);
+                self.typing.remove(&peer);
+                if self.active_peer == Some(peer) {
+                    self.history_prompt = None;
+                    // With nobody else online, messages keep going to the peer's outbox.
+                    self.active_peer = self.online_peers.first().copied().or(Some(peer));
+                    self.report_read();
+                }
+                self.refresh_connection();
+                self.push_system(format!("Disconnected from {label}"));
             }
-            ServiceEvent::MessageReceived { peer, author, text } => {
+            ServiceEvent::MessageReceived {
+                peer,
+                id,
+                author,
+                text,
+                reply_to,
+            } => {
                 self.peer_names.insert(peer, author.clone());
-                self.push_message(MessageDirection::Incoming(author), text);
+                self.typing.remove(&peer);
+                let entry = self.push_message(MessageDirection::Incoming(author), text);
+                entry.id = Some(id);
+                entry.peer = Some(peer);
+                entry.reply_to = reply_to;
+                if self.read_receipts {
+                    self.unread.entry(peer).or_default().push(id);
+                    self.report_read();
+                }
             }
-            ServiceEvent::MessageSent { author, text } => {
+            ServiceEvent::MessageSent {
+                peer,
+                id,
+                author,
+                text,
+                reply_to,
+            } => {
                 self.username = author.clone();
-                self.push_message(MessageDirection::Outgoing(author), text);
+                let entry = self.push_message(MessageDirection::Outgoing(author), text);
+                entry.id = Some(id);
+                entry.peer = Some(peer);
+                entry.reply_to = reply_to;
+                entry.receipt = Some(Receipt::Sent);
+            }
+            ServiceEvent::MessageQueued {
+                peer,
+                id,
+                author,
+                text,
+                reply_to,
+            } => {
+                let entry = self.push_message(MessageDirection::Outgoing(author), text);
+                entry.id = Some(id);
+                entry.peer = Some(peer);
+                entry.reply_to = reply_to;
+                entry.receipt = Some(Receipt::Pending);
+                self.status_line = format!(
+                    "{} is offline; the message will be sent when they are back",
+                    self.peer_label(peer)
+                );
+            }
+            ServiceEvent::OutboxFlushed { peer, ids } => {
+                for id in &ids {
+                    self.update_receipt(*id, Receipt::Sent);
+                }
+                let label = self.peer_label(peer);
+                self.push_system(format!("Sent {} queued message(s) to {label}", ids.len()));
+            }
+            ServiceEvent::MessageAmended {
+                peer,
+                author,
+                amendment,
+                text,
+                outgoing,
+            } => {
+                apply_amendment(&mut self.messages, peer, author, amendment, text, outgoing);
             }
+            ServiceEvent::MessageDelivered { id, .. } => {
+                self.update_receipt(id, Receipt::Delivered)
+            }
+            ServiceEvent::MessageRead { id, .. } => self.update_receipt(id, Receipt::Read),
             ServiceEvent::PeerProfile { peer, username } => {
                 self.peer_names.insert(peer, username.clone());
-                if let ConnectionStatus::Connected { peer: current, .. } = &mut self.connection {
-                    if *current == peer {
-                        self.connection = ConnectionStatus::Connected {
-                            peer,
-                            name: username.clone(),
-                        };
+                self.refresh_connection();
+                self.push_system(format!("{username} is now online ({peer})"));
+            }
+            ServiceEvent::PeerPresence {
+                peer,
+                state,
+                status,
+                ..
+            } => {
+                let presence = Presence { state, status };
+                // Heartbeats repeat the same presence; only changes are worth a mention, and a
+                // plain "online" on connect is implied by the connection itself.
+                let plain = Presence {
+                    state: PresenceState::Online,
+                    status: None,
+                };
+                if *self.presence.get(&peer).unwrap_or(&plain) != presence {
+                    let mut line = format!("{} is {state}", self.peer_label(peer));
+                    if let Some(status) = &presence.status {
+                        line.push_str(&format!(": {status}"));
                     }
+                    self.status_line = line;
+                }
+                self.presence.insert(peer, presence);
+            }
+            ServiceEvent::PeerTyping { peer, typing } => {
+                if typing {
+                    self.typing.insert(peer, Instant::now());
+                } else {
+                    self.typing.remove(&peer);
                 }
-                self.push_system(format!("{username} is now online ({peer})"));
             }
             ServiceEvent::FileTransfer(progress) => self.update_transfer(progress),
             ServiceEvent::Discovery(event) => match event {
@@ -1519,6 +2208,11 @@ impl App {
                 }
             },
             ServiceEvent::SavedPeers(list) => {
+                if let Some(dialog) = self.safety_dialog.as_mut() {
+                    if let Some(entry) = list.iter().find(|entry| entry.addr == dialog.peer) {
+                        dialog.verified = entry.verified;
+                    }
+                }
                 self.saved_peers = list;
                 if self.saved_peers.is_empty() {
                     self.saved_peer_index = 0;
@@ -1529,25 +2223,255 @@ impl App {
                 }
             }
             ServiceEvent::FileOffer(offer) => {
+                let what = if offer.is_single_file() {
+                    "file".to_string()
+                } else {
+                    format!("{} files", offer.entries.len())
+                };
                 self.push_system(format!(
-                    "Incoming file '{}' ({}) from {}",
+                    "Incoming {what} '{}' ({}, {}) from {}",
                     offer.name,
                     human_size(offer.original_size),
+                    offer.codec,
                     offer.peer
                 ));
                 self.enqueue_offer(offer);
             }
-            ServiceEvent::Error { message } => {
-                self.show_error(message);
+            ServiceEvent::IdentityKeyChanged {
+                peer,
+                saved,
+                name,
+                expected,
+                received,
+            } => {
+                if self.dialing == Some(peer) {
+                    self.dialing = None;
+                    self.refresh_connection();
+                }
+                if self
+                    .safety_dialog
+                    .as_ref()
+                    .is_some_and(|dialog| dialog.peer == peer)
+                {
+                    self.safety_dialog = None;
+                }
+                self.push_system(format!(
+                    "WARNING: {name} ({peer}) presented a different identity key ({received}, pinned {expected}). Connection refused; run d/trust {saved} only if you verified the change."
+                ));
+                self.show_error(format!("Identity key changed for {peer}"));
             }
-        }
-    }
-
-    fn maybe_prompt_history(&mut self, peer: SocketAddr, name: &str) {
-        let path = self.history_file_for(peer);
-        match fs::metadata(&path) {
-            Ok(meta) if meta.len() > 0 => {
-                let prompt = HistoryPrompt {
+            ServiceEvent::FileIntegrityFailed { peer, id, name } => {
+                self.remove_transfer(id);
+                self.push_system(format!(
+                    "WARNING: '{name}' from {peer} did not match its checksum and was discarded"
+                ));
+                self.show_error(format!("Integrity check failed for '{name}'"));
+            }
+            ServiceEvent::TransferAborted {
+                peer,
+                id,
+                name,
+                reason,
+            } => {
+                self.remove_transfer(id);
+                self.drop_offer(id);
+                self.push_warning(format!(
+                    "Transfer of '{name}' from {peer} aborted: {reason}"
+                ));
+                self.show_error(format!("Transfer of '{name}' aborted"));
+            }
+            ServiceEvent::FileAutoAccepted {
+                peer, name, path, ..
+            } => {
+                let label = self.peer_label(peer);
+                self.push_system(format!(
+                    "Auto-accepting '{name}' from {label} into {}",
+                    path.display()
+                ));
+            }
+            ServiceEvent::OfferExpired {
+                peer,
+                id,
+                name,
+                direction,
+            } => {
+                let label = self.peer_label(peer);
+                match direction {
+                    TransferDirection::Incoming => {
+                        self.drop_offer(id);
+                        self.push_warning(format!(
+                            "Offer of '{name}' from {label} expired unanswered"
+                        ));
+                    }
+                    TransferDirection::Outgoing => {
+                        self.remove_transfer(id);
+                        self.push_warning(format!(
+                            "{label} did not answer the offer of '{name}' in time; it was withdrawn"
+                        ));
+                    }
+                }
+            }
+            ServiceEvent::TransferPaused { id, paused, .. } => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|t| t.id == id) {
+                    transfer.paused = paused;
+                    let verb = if paused { "Paused" } else { "Resumed" };
+                    self.status_line = format!("{verb} '{}'", transfer.name);
+                }
+            }
+            ServiceEvent::FileCollision {
+                peer,
+                id,
+                name,
+                path,
+                existing,
+            } => {
+                let shown = existing
+                    .first()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| path.display().to_string());
+                let more = match existing.len() {
+                    0 | 1 => String::new(),
+                    n => format!(" and {} more", n - 1),
+                };
+                self.push_warning(format!("Saving '{name}' would replace {shown}{more}"));
+                self.status_line = "o: overwrite • r: keep both • Esc: decline".into();
+                self.collision_prompt = Some(CollisionPrompt {
+                    peer,
+                    id,
+                    name,
+                    path,
+                });
+            }
+            ServiceEvent::SafetyNumber {
+                peer,
+                name,
+                number,
+                verified,
+            } => {
+                self.status_line = "v: toggle verified • Esc: close".into();
+                self.safety_dialog = Some(SafetyDialog {
+                    peer,
+                    name,
+                    number,
+                    verified,
+                });
+            }
+            ServiceEvent::Error { peer, message } => {
+                if peer.is_some() && self.dialing == peer {
+                    self.dialing = None;
+                    self.refresh_connection();
+                }
+                // The service reports giving up on a redial as an error.
+                if let Some(peer) = peer.filter(|peer| !self.online_peers.contains(peer)) {
+                    if self.reconnecting.remove(&peer).is_some() {
+                        self.refresh_connection();
+                    }
+                }
+                self.show_error(message);
+            }
+        }
+    }
+
+    fn refresh_connection(&mut self) {
+        self.connection = if let Some(peer) = self.active_target_online() {
+            let name = self
+                .peer_names
+                .get(&peer)
+                .cloned()
+                .unwrap_or_else(|| "???".into());
+            ConnectionStatus::Connected { peer, name }
+        } else if let Some((peer, attempt)) = self
+            .active_peer
+            .and_then(|peer| Some((peer, *self.reconnecting.get(&peer)?)))
+        {
+            ConnectionStatus::Reconnecting { peer, attempt }
+        } else if let Some(addr) = self.dialing {
+            ConnectionStatus::Connecting(addr)
+        } else if let Some((addr, locked)) = self.listener {
+            ConnectionStatus::Listening { addr, locked }
+        } else {
+            ConnectionStatus::Disconnected
+        };
+    }
+
+    fn active_target_online(&self) -> Option<SocketAddr> {
+        self.active_peer
+            .filter(|peer| self.online_peers.contains(peer))
+    }
+
+    fn active_target(&mut self) -> Option<SocketAddr> {
+        let target = self.active_target_online();
+        if target.is_none() {
+            self.show_warning("Not connected to any peer (Ctrl+K to connect)");
+        }
+        target
+    }
+
+    /// Peer a chat message goes to: the active peer, or the saved peer that was active when
+    /// it went offline, whose messages are queued until it is back.
+    fn message_target(&mut self) -> Option<SocketAddr> {
+        let offline = self
+            .active_peer
+            .filter(|peer| self.saved_peers.iter().any(|entry| entry.addr == *peer));
+        match self.active_target_online().or(offline) {
+            Some(peer) => Some(peer),
+            None => self.active_target(),
+        }
+    }
+
+    fn set_active_peer(&mut self, peer: SocketAddr) {
+        self.active_peer = Some(peer);
+        self.report_read();
+        self.history_prompt = None;
+        self.refresh_connection();
+        let name = self
+            .peer_names
+            .get(&peer)
+            .cloned()
+            .unwrap_or_else(|| peer.to_string());
+        self.maybe_prompt_history(peer, &name);
+        self.update_typing();
+    }
+
+    fn cycle_active_peer(&mut self) {
+        if self.online_peers.len() < 2 {
+            self.status_line = if self.online_peers.is_empty() {
+                "No connected peers.".into()
+            } else {
+                "Only one peer connected.".into()
+            };
+            return;
+        }
+        let next = self
+            .active_peer
+            .and_then(|current| self.online_peers.iter().position(|peer| *peer == current))
+            .map(|idx| (idx + 1) % self.online_peers.len())
+            .unwrap_or(0);
+        let peer = self.online_peers[next];
+        self.set_active_peer(peer);
+        if self.history_prompt.is_none() {
+            self.status_line = format!("Now chatting with {}", self.peer_label(peer));
+        }
+    }
+
+    fn peer_label(&self, peer: SocketAddr) -> String {
+        self.peer_names
+            .get(&peer)
+            .map(|name| format!("{name} ({peer})"))
+            .unwrap_or_else(|| peer.to_string())
+    }
+
+    /// Number of peers with a live connection.
+    pub fn online_count(&self) -> usize {
+        self.online_peers.len()
+    }
+
+    fn maybe_prompt_history(&mut self, peer: SocketAddr, name: &str) {
+        let path = self.history_file_for(peer);
+        match fs::metadata(&path) {
+            Ok(meta) if meta.len() > 0 => {
+                let prompt = HistoryPrompt {
+                    peer,
                     name: name.to_string(),
                     path,
                     bytes: Some(meta.len()),
@@ -1572,22 +2496,53 @@ impl App {
         )
     }
 
+    fn remove_transfer(&mut self, id: u64) {
+        self.transfers.retain(|transfer| transfer.id != id);
+        if self.panel_focus == PanelFocus::Transfers && self.transfers.is_empty() {
+            self.exit_panel_focus();
+        }
+    }
+
+    /// Forgets an offer that was withdrawn or expired before it was answered.
+    fn drop_offer(&mut self, id: u64) {
+        self.offer_queue.retain(|offer| offer.id != id);
+        if self
+            .active_offer
+            .as_ref()
+            .is_some_and(|offer| offer.id == id)
+        {
+            self.mark_offer_handled();
+        }
+        if self.panel_focus == PanelFocus::Offers && self.active_offer.is_none() {
+            self.exit_panel_focus();
+        }
+    }
+
     fn update_transfer(&mut self, progress: FileTransferProgress) {
         let mut was_completed = false;
         if let Some(existing) = self.transfers.iter_mut().find(|t| t.id == progress.id) {
             was_completed = existing.completed;
+            existing.peer = progress.peer;
             existing.transferred = progress.transferred;
             existing.total = progress.total;
             existing.completed = progress.completed;
             existing.path = progress.path.clone();
+            existing.entry = progress.entry.clone();
+            existing.bytes_per_sec = progress.bytes_per_sec;
+            existing.eta = progress.eta;
         } else {
             self.transfers.push(TransferState {
+                peer: progress.peer,
                 id: progress.id,
                 name: progress.name.clone(),
                 direction: progress.direction,
                 transferred: progress.transferred,
                 total: progress.total,
                 path: progress.path.clone(),
+                entry: progress.entry.clone(),
+                bytes_per_sec: progress.bytes_per_sec,
+                eta: progress.eta,
+                paused: false,
                 completed: progress.completed,
             });
         }
@@ -1606,7 +2561,7 @@ impl App {
         }
     }
 
-    fn push_message(&mut self, direction: MessageDirection, text: String) {
+    fn push_message(&mut self, direction: MessageDirection, text: String) -> &mut ChatEntry {
         if self.messages.len() >= MAX_MESSAGES {
             self.messages.remove(0);
             if let Some(idx) = self.selected_message {
@@ -1628,8 +2583,43 @@ impl App {
             author,
             text,
             timestamp: OffsetDateTime::now_utc(),
+            id: None,
+            receipt: None,
+            peer: None,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: None,
         });
         self.clamp_selection();
+        self.messages.last_mut().expect("message was just pushed")
+    }
+
+    /// Raises the receipt of outgoing message `id`; receipts never go backwards.
+    fn update_receipt(&mut self, id: u64, receipt: Receipt) {
+        let entry = self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.id == Some(id) && entry.receipt.is_some());
+        if let Some(entry) = entry {
+            entry.receipt = entry.receipt.max(Some(receipt));
+        }
+    }
+
+    /// Reports messages from the active peer as read; they are on screen while it is active.
+    fn report_read(&mut self) {
+        let Some(peer) = self.active_peer else {
+            return;
+        };
+        if let Some(ids) = self.unread.remove(&peer).filter(|ids| !ids.is_empty()) {
+            self.commands.push(ServiceCommand::MarkRead { peer, ids });
+        }
+    }
+
+    /// Commands raised while handling service events, for the event loop to send.
+    pub fn take_commands(&mut self) -> Vec<ServiceCommand> {
+        std::mem::take(&mut self.commands)
     }
 
     pub fn peer_alias(&self, addr: &SocketAddr) -> Option<&String> {
@@ -1687,6 +2677,95 @@ impl App {
         self.marked_messages.retain(|idx| *idx < len);
     }
 
+    /// Acts on the message selected while browsing: deleting goes out at once, while edits
+    /// and reactions are typed into the input first.
+    fn start_amending(&mut self, kind: AmendmentKind) -> Option<ServiceCommand> {
+        let Some(entry) = self.selected_message.and_then(|idx| self.messages.get(idx)) else {
+            self.show_warning("No message selected.");
+            return None;
+        };
+        let (Some(id), Some(peer)) = (entry.id, entry.peer) else {
+            self.show_warning("Only chat messages can be changed.");
+            return None;
+        };
+        if entry.deleted {
+            self.show_warning("That message was deleted.");
+            return None;
+        }
+        let own = matches!(entry.direction, MessageDirection::Outgoing(_));
+        if kind != AmendmentKind::React && !own {
+            self.show_warning("Only your own messages can be edited or deleted.");
+            return None;
+        }
+        let text = entry.text.clone();
+        let own_reaction = entry
+            .reactions
+            .iter()
+            .find(|reaction| reaction.author == self.username)
+            .map(|reaction| reaction.emoji.clone());
+        self.leave_chat_focus();
+        match kind {
+            AmendmentKind::Delete => {
+                self.status_line = "Message deleted".into();
+                return Some(ServiceCommand::AmendMessage {
+                    peer,
+                    amendment: Amendment { id, kind },
+                    text: String::new(),
+                });
+            }
+            AmendmentKind::Edit => {
+                self.mode = Mode::EditMessage(id);
+                self.input = text;
+                self.status_line = "Editing message • Enter to save, Esc to cancel".into();
+            }
+            AmendmentKind::React => {
+                self.mode = Mode::React(id);
+                self.input = own_reaction.unwrap_or_else(|| "👍".into());
+                self.status_line =
+                    "Reaction • Enter to send, clear the input to remove yours, Esc to cancel"
+                        .into();
+            }
+        }
+        None
+    }
+
+    /// Starts writing an answer to the message selected while browsing.
+    fn start_reply(&mut self) {
+        let Some(entry) = self.selected_message.and_then(|idx| self.messages.get(idx)) else {
+            self.show_warning("No message selected.");
+            return;
+        };
+        let Some(id) = entry.id.filter(|_| entry.peer.is_some()) else {
+            self.show_warning("Only chat messages can be answered.");
+            return;
+        };
+        if entry.deleted {
+            self.show_warning("That message was deleted.");
+            return;
+        }
+        let author = entry.author.clone();
+        self.leave_chat_focus();
+        self.mode = Mode::Reply(id);
+        self.input.clear();
+        self.status_line = format!("Replying to {author} • Enter to send, Esc to cancel");
+    }
+
+    /// `author: text` of the message `entry` answers, or a placeholder once that message is
+    /// no longer loaded.
+    pub fn reply_quote(&self, entry: &ChatEntry) -> Option<String> {
+        let id = entry.reply_to?;
+        let parent = self
+            .messages
+            .iter()
+            .rev()
+            .find(|parent| parent.id == Some(id) && parent.peer == entry.peer);
+        Some(match parent {
+            Some(parent) if parent.deleted => format!("{}: message deleted", parent.author),
+            Some(parent) => format!("{}: {}", parent.author, parent.text.replace('\n', " ")),
+            None => "earlier message".into(),
+        })
+    }
+
     fn toggle_marked_message(&mut self) {
         if !self.chat_focus {
             self.show_warning("Press Ctrl+G to browse chat first.");
@@ -1721,6 +2800,12 @@ pub enum Mode {
     Username,
     DiscoveryNetwork,
     IncomingFile(u64),
+    /// Replacing the text of our message with this id.
+    EditMessage(u64),
+    /// Picking a reaction to the message with this id.
+    React(u64),
+    /// Writing an answer to the message with this id.
+    Reply(u64),
 }
 
 /// Connection state summary.
@@ -1729,6 +2814,7 @@ pub enum ConnectionStatus {
     Disconnected,
     Listening { addr: SocketAddr, locked: bool },
     Connecting(SocketAddr),
+    Reconnecting { peer: SocketAddr, attempt: u32 },
     Connected { peer: SocketAddr, name: String },
 }
 
@@ -1771,20 +2857,156 @@ pub struct ChatEntry {
     pub author: String,
     pub text: String,
     pub timestamp: OffsetDateTime,
+    /// Wire id of a chat message sent or received in this session.
+    pub id: Option<u64>,
+    /// How far an outgoing message got; `None` for everything else.
+    pub receipt: Option<Receipt>,
+    /// Peer the chat message was exchanged with.
+    pub peer: Option<SocketAddr>,
+    pub edited: bool,
+    /// Retracted by its author; the text is gone.
+    pub deleted: bool,
+    pub reactions: Vec<Reaction>,
+    /// Id of the message this one answers.
+    pub reply_to: Option<u64>,
+}
+
+/// Emoji reaction to a chat message, at most one per author.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reaction {
+    pub author: String,
+    pub emoji: String,
+}
+
+/// Decodes a history record. Fields were appended to the layout over time, so those an
+/// older record ends before keep their defaults.
+fn decode_history_entry(raw: &[u8]) -> Option<StoredHistoryEntry> {
+    let mut read = 0;
+    let head: HistoryEntryHead = decode_history_field(raw, &mut read)?;
+    let id = decode_history_field(raw, &mut read).unwrap_or(0);
+    let amends = decode_history_field(raw, &mut read).flatten();
+    let reply_to = decode_history_field(raw, &mut read).flatten();
+    Some(StoredHistoryEntry {
+        timestamp: head.timestamp,
+        outgoing: head.outgoing,
+        author: head.author,
+        text: head.text,
+        id,
+        amends,
+        reply_to,
+    })
+}
+
+/// Decodes the value starting at `read` and moves past it, if the record goes on that far.
+fn decode_history_field<T: DeserializeOwned>(raw: &[u8], read: &mut usize) -> Option<T> {
+    let (value, len) =
+        bincode::serde::decode_from_slice(raw.get(*read..)?, bincode::config::standard()).ok()?;
+    *read += len;
+    Some(value)
+}
+
+/// Applies an amendment by `author` to the message it names among those exchanged with
+/// `peer`. Only the author of a message may edit or delete it; anyone may react. Returns
+/// whether a message changed.
+fn apply_amendment(
+    messages: &mut [ChatEntry],
+    peer: SocketAddr,
+    author: String,
+    amendment: Amendment,
+    text: String,
+    outgoing: bool,
+) -> bool {
+    let Some(entry) = messages
+        .iter_mut()
+        .rev()
+        .find(|entry| entry.id == Some(amendment.id) && entry.peer == Some(peer))
+    else {
+        return false;
+    };
+    if entry.deleted {
+        return false;
+    }
+    match amendment.kind {
+        AmendmentKind::Edit | AmendmentKind::Delete => {
+            let own = match entry.direction {
+                MessageDirection::Outgoing(_) => outgoing,
+                MessageDirection::Incoming(_) => !outgoing,
+                _ => false,
+            };
+            if !own {
+                return false;
+            }
+            if amendment.kind == AmendmentKind::Edit {
+                entry.text = text;
+                entry.edited = true;
+            } else {
+                entry.text.clear();
+                entry.deleted = true;
+                entry.reactions.clear();
+            }
+        }
+        AmendmentKind::React => {
+            entry.reactions.retain(|reaction| reaction.author != author);
+            if !text.is_empty() {
+                entry.reactions.push(Reaction {
+                    author,
+                    emoji: text,
+                });
+            }
+        }
+    }
+    true
+}
+
+/// Delivery state of an outgoing message, in the order it advances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Receipt {
+    /// Waiting in the outbox for the peer to come back.
+    Pending,
+    Sent,
+    Delivered,
+    Read,
 }
 
 /// Transfer progress representation for the sidebar.
 #[derive(Debug, Clone)]
 pub struct TransferState {
+    pub peer: SocketAddr,
     pub id: u64,
     pub name: String,
     pub direction: TransferDirection,
     pub transferred: u64,
     pub total: u64,
     pub path: Option<PathBuf>,
+    /// File currently moving in a multi-file transfer.
+    pub entry: Option<EntryProgress>,
+    pub bytes_per_sec: Option<u64>,
+    pub eta: Option<Duration>,
+    pub paused: bool,
     pub completed: bool,
 }
 
+impl TransferState {
+    /// Speed and time left, e.g. `1.2 MB/s · 0:42 left`, while the transfer is moving.
+    pub fn speed_label(&self) -> Option<String> {
+        if self.completed || self.paused {
+            return None;
+        }
+        let rate = self.bytes_per_sec?;
+        let mut label = format!("{}/s", human_size(rate));
+        if let Some(eta) = self.eta {
+            let secs = eta.as_secs();
+            let left = if secs >= 3600 {
+                format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            } else {
+                format!("{}:{:02}", secs / 60, secs % 60)
+            };
+            label.push_str(&format!(" · {left} left"));
+        }
+        Some(label)
+    }
+}
+
 fn parse_color(raw: &str) -> Color {
     match raw.to_ascii_lowercase().as_str() {
         "red" => Color::Red,
@@ -1814,6 +3036,16 @@ fn longest_common_prefix(strings: &[String]) -> String {
     prefix
 }
 
+/// Rough age of a last-seen stamp `secs` seconds old, e.g. `5m ago`.
+fn seen_ago(secs: i64) -> String {
+    match secs.max(0) {
+        0..=59 => "just now".into(),
+        secs @ 60..=3599 => format!("{}m ago", secs / 60),
+        secs @ 3600..=86_399 => format!("{}h ago", secs / 3600),
+        secs => format!("{}d ago", secs / 86_400),
+    }
+}
+
 fn human_size(bytes: u64) -> String {
     const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
     if bytes == 0 {
@@ -1838,16 +3070,52 @@ mod tests {
         let config = AppConfig::default();
         let args = TuiCommand::default();
         let mut app = App::new(&config, &args);
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
         app.input = "".into();
         assert!(app.commit_input().is_none());
         app.mode = Mode::Chat;
         app.input = "hi".into();
+        assert!(app.commit_input().is_none(), "no peer connected yet");
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.input = "hi".into();
         assert!(matches!(
             app.commit_input(),
-            Some(ServiceCommand::SendText { text }) if text == "hi"
+            Some(ServiceCommand::SendText { peer: target, text, .. }) if text == "hi" && target == peer
         ));
     }
 
+    #[test]
+    fn safety_dialog_toggles_verification() {
+        let config = AppConfig::default();
+        let args = TuiCommand::default();
+        let mut app = App::new(&config, &args);
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::SafetyNumber {
+            peer,
+            name: "peer".into(),
+            number: "00000 11111 22222 33333 44444 55555".into(),
+            verified: false,
+        });
+        let press = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        assert!(matches!(
+            app.handle_key(press(KeyCode::Char('v'))),
+            Some(ServiceCommand::SetPeerVerified { addr, verified: true }) if addr == peer
+        ));
+        app.handle_service_event(ServiceEvent::SavedPeers(vec![SavedPeer {
+            addr: peer,
+            name: "peer".into(),
+            identity_key: None,
+            verified: true,
+            last_seen: None,
+        }]));
+        assert!(app.safety_dialog().is_some_and(|dialog| dialog.verified));
+        assert!(app.handle_key(press(KeyCode::Esc)).is_none());
+        assert!(app.safety_dialog().is_none());
+    }
+
     #[test]
     fn listen_flow_requests_password() {
         let config = AppConfig::default();
@@ -1864,12 +3132,549 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn offers_panel_picks_declines_and_expires_offers() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        for id in 1..=3 {
+            app.handle_service_event(ServiceEvent::FileOffer(FileOfferNotice {
+                id,
+                name: format!("file{id}.bin"),
+                original_size: 10,
+                codec: crate::compression::Codec::None,
+                estimated_ratio: 1.0,
+                entries: Vec::new(),
+                segment_bytes: 10,
+                expires_at: None,
+                peer,
+            }));
+        }
+        assert_eq!(app.offer_labels().len(), 3);
+        let ctrl_o = KeyEvent::new(KeyCode::Char('o'), KeyModifiers::CONTROL);
+        app.handle_key(ctrl_o);
+        assert_eq!(app.panel_focus(), PanelFocus::Offers);
+        app.handle_key(KeyEvent::new(KeyCode::Up, KeyModifiers::NONE));
+        app.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(app.mode, Mode::IncomingFile(3));
+        let ids: Vec<u64> = app.queued_offers().map(|offer| offer.id).collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+
+        app.handle_key(ctrl_o);
+        app.handle_key(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE));
+        let declined = app.handle_key(KeyEvent::new(KeyCode::Char('x'), KeyModifiers::NONE));
+        assert!(matches!(
+            declined,
+            Some(ServiceCommand::DeclineFile { id: 1, .. })
+        ));
+
+        app.handle_service_event(ServiceEvent::OfferExpired {
+            peer,
+            id: 3,
+            name: "file3.bin".into(),
+            direction: TransferDirection::Incoming,
+        });
+        assert_eq!(app.mode, Mode::IncomingFile(2));
+        assert_eq!(app.queued_offers().count(), 1);
+    }
+
+    #[test]
+    fn receipts_advance_and_read_follows_the_active_peer() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let alice: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let bob: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        for (peer, name) in [(alice, "alice"), (bob, "bob")] {
+            app.handle_service_event(ServiceEvent::Connected {
+                peer,
+                name: name.into(),
+            });
+        }
+        assert_eq!(app.active_peer, Some(alice));
+
+        app.handle_service_event(ServiceEvent::MessageSent {
+            peer: alice,
+            id: 7,
+            author: "me".into(),
+            text: "hi".into(),
+            reply_to: None,
+        });
+        let receipt = |app: &App| app.messages.last().unwrap().receipt;
+        assert_eq!(receipt(&app), Some(Receipt::Sent));
+        app.handle_service_event(ServiceEvent::MessageRead { peer: alice, id: 7 });
+        app.handle_service_event(ServiceEvent::MessageDelivered { peer: alice, id: 7 });
+        assert_eq!(receipt(&app), Some(Receipt::Read));
+
+        let received = |id, peer| ServiceEvent::MessageReceived {
+            peer,
+            id,
+            author: "peer".into(),
+            text: "yo".into(),
+            reply_to: None,
+        };
+        app.handle_service_event(received(1, alice));
+        app.handle_service_event(received(2, bob));
+        app.handle_service_event(received(3, bob));
+        let commands = app.take_commands();
+        assert!(matches!(
+            commands.as_slice(),
+            [ServiceCommand::MarkRead { peer, ids }] if *peer == alice && ids == &[1]
+        ));
+        app.cycle_active_peer();
+        let commands = app.take_commands();
+        assert!(matches!(
+            commands.as_slice(),
+            [ServiceCommand::MarkRead { peer, ids }] if *peer == bob && ids == &[2, 3]
+        ));
+    }
+
+    #[test]
+    fn messages_to_an_offline_saved_peer_are_queued() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.handle_service_event(ServiceEvent::Disconnected { peer });
+        app.input = "later".into();
+        assert!(app.commit_input().is_none(), "unsaved peers cannot queue");
+
+        app.handle_service_event(ServiceEvent::SavedPeers(vec![SavedPeer {
+            addr: peer,
+            name: "peer".into(),
+            identity_key: None,
+            verified: false,
+            last_seen: None,
+        }]));
+        app.input = "later".into();
+        assert!(matches!(
+            app.commit_input(),
+            Some(ServiceCommand::SendText { peer: target, .. }) if target == peer
+        ));
+        app.handle_service_event(ServiceEvent::MessageQueued {
+            peer,
+            id: 4,
+            author: "me".into(),
+            text: "later".into(),
+            reply_to: None,
+        });
+        let receipt = |app: &App| {
+            app.messages
+                .iter()
+                .find(|entry| entry.id == Some(4))
+                .unwrap()
+                .receipt
+        };
+        assert_eq!(receipt(&app), Some(Receipt::Pending));
+        app.handle_service_event(ServiceEvent::OutboxFlushed { peer, ids: vec![4] });
+        assert_eq!(receipt(&app), Some(Receipt::Sent));
+    }
+
+    #[test]
+    fn status_follows_reconnect_attempts() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.handle_service_event(ServiceEvent::Disconnected { peer });
+        app.handle_service_event(ServiceEvent::Reconnecting { peer, attempt: 2 });
+        assert!(matches!(
+            app.connection,
+            ConnectionStatus::Reconnecting { attempt: 2, .. }
+        ));
+        assert!(app.status_snapshot().contains("attempt 2"));
+
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        assert!(matches!(app.connection, ConnectionStatus::Connected { .. }));
+        app.handle_service_event(ServiceEvent::Disconnected { peer });
+        app.handle_service_event(ServiceEvent::Reconnecting { peer, attempt: 1 });
+        app.handle_service_event(ServiceEvent::Error {
+            peer: Some(peer),
+            message: "gave up".into(),
+        });
+        assert!(matches!(app.connection, ConnectionStatus::Disconnected));
+    }
+
+    #[test]
+    fn saved_peers_show_presence_then_last_seen() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let saved = SavedPeer {
+            addr: peer,
+            name: "peer".into(),
+            identity_key: None,
+            verified: false,
+            last_seen: None,
+        };
+        app.handle_service_event(ServiceEvent::SavedPeers(vec![saved.clone()]));
+        assert_eq!(app.saved_peer_labels(), ["peer (127.0.0.1:5000)"]);
+
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.handle_service_event(ServiceEvent::PeerPresence {
+            peer,
+            state: PresenceState::Busy,
+            status: Some("deploying".into()),
+            last_seen: protocol::utc_timestamp(),
+        });
+        assert_eq!(
+            app.saved_peer_labels(),
+            ["peer (127.0.0.1:5000) · busy: deploying"]
+        );
+        assert!(app.status_line.contains("is busy: deploying"));
+
+        app.handle_service_event(ServiceEvent::Disconnected { peer });
+        app.handle_service_event(ServiceEvent::SavedPeers(vec![SavedPeer {
+            last_seen: Some(protocol::utc_timestamp() - 3 * 3600),
+            ..saved
+        }]));
+        assert_eq!(
+            app.saved_peer_labels(),
+            ["peer (127.0.0.1:5000) · seen 3h ago"]
+        );
+    }
+
+    #[test]
+    fn presence_command_takes_a_state_and_optional_status() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        app.input = "d/presence away at lunch".into();
+        assert!(matches!(
+            app.commit_input(),
+            Some(ServiceCommand::SetPresence {
+                state: PresenceState::Away,
+                status: Some(status),
+            }) if status == "at lunch"
+        ));
+        app.input = "d/presence sleepy".into();
+        assert!(app.commit_input().is_none());
+    }
+
+    #[test]
+    fn typing_is_announced_once_and_withdrawn_when_cleared() {
+        let mut config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.take_commands();
+        let press = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        app.handle_key(press(KeyCode::Char('h')));
+        app.handle_key(press(KeyCode::Char('i')));
+        assert!(matches!(
+            app.take_commands().as_slice(),
+            [ServiceCommand::SetTyping { typing: true, .. }]
+        ));
+        app.handle_key(press(KeyCode::Backspace));
+        app.handle_key(press(KeyCode::Backspace));
+        assert!(matches!(
+            app.take_commands().as_slice(),
+            [ServiceCommand::SetTyping { typing: false, .. }]
+        ));
+
+        let other: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer: other,
+            name: "other".into(),
+        });
+        app.handle_key(press(KeyCode::Char('h')));
+        app.take_commands();
+        app.handle_key(KeyEvent::new(KeyCode::Char('n'), KeyModifiers::CONTROL));
+        assert!(matches!(
+            app.take_commands().as_slice(),
+            [
+                ServiceCommand::SetTyping { peer: left, typing: false },
+                ServiceCommand::SetTyping { peer: joined, typing: true },
+            ] if *left == peer && *joined == other
+        ));
+
+        config.ui.typing_indicators = false;
+        let mut quiet = App::new(&config, &TuiCommand::default());
+        quiet.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        quiet.take_commands();
+        quiet.handle_key(press(KeyCode::Char('h')));
+        assert!(quiet.take_commands().is_empty());
+    }
+
+    #[test]
+    fn peer_typing_ends_with_its_message() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.handle_service_event(ServiceEvent::PeerTyping { peer, typing: true });
+        assert_eq!(app.typing_peer().as_deref(), Some("peer"));
+        app.handle_service_event(ServiceEvent::MessageReceived {
+            peer,
+            id: 1,
+            author: "peer".into(),
+            text: "hi".into(),
+            reply_to: None,
+        });
+        assert!(app.typing_peer().is_none());
+    }
+
+    #[test]
+    fn browsing_edits_and_reacts_to_the_selected_message() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.handle_service_event(ServiceEvent::MessageSent {
+            peer,
+            id: 7,
+            author: "me".into(),
+            text: "helo".into(),
+            reply_to: None,
+        });
+        app.handle_service_event(ServiceEvent::MessageReceived {
+            peer,
+            id: 8,
+            author: "peer".into(),
+            text: "hi".into(),
+            reply_to: None,
+        });
+        let ctrl = |ch| KeyEvent::new(KeyCode::Char(ch), KeyModifiers::CONTROL);
+        let press = |code| KeyEvent::new(code, KeyModifiers::NONE);
+
+        app.handle_key(ctrl('g'));
+        assert_eq!(app.selected_message, Some(app.messages.len() - 1));
+        assert!(app.handle_key(press(KeyCode::Char('e'))).is_none());
+        assert_eq!(app.mode, Mode::Chat, "peers' messages cannot be edited");
+
+        app.handle_key(press(KeyCode::Char('r')));
+        assert_eq!(app.mode, Mode::React(8));
+        assert!(matches!(
+            app.handle_key(press(KeyCode::Enter)),
+            Some(ServiceCommand::AmendMessage {
+                amendment: Amendment { id: 8, kind: AmendmentKind::React },
+                text,
+                ..
+            }) if text == "👍"
+        ));
+
+        // The refused edit left a warning below the messages.
+        app.handle_key(ctrl('g'));
+        app.handle_key(press(KeyCode::Up));
+        app.handle_key(press(KeyCode::Up));
+        assert_eq!(app.messages[app.selected_message.unwrap()].id, Some(7));
+        app.handle_key(press(KeyCode::Char('e')));
+        assert_eq!(app.mode, Mode::EditMessage(7));
+        assert_eq!(app.input, "helo");
+        app.input = "hello".into();
+        assert!(matches!(
+            app.handle_key(press(KeyCode::Enter)),
+            Some(ServiceCommand::AmendMessage {
+                peer: target,
+                amendment: Amendment { id: 7, kind: AmendmentKind::Edit },
+                ..
+            }) if target == peer
+        ));
+    }
+
+    #[test]
+    fn amendments_only_change_messages_their_author_may_change() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::MessageSent {
+            peer,
+            id: 7,
+            author: "me".into(),
+            text: "mine".into(),
+            reply_to: None,
+        });
+        let amended = |kind, text: &str| ServiceEvent::MessageAmended {
+            peer,
+            author: "peer".into(),
+            amendment: Amendment { id: 7, kind },
+            text: text.into(),
+            outgoing: false,
+        };
+        app.handle_service_event(amended(AmendmentKind::Edit, "theirs"));
+        app.handle_service_event(amended(AmendmentKind::Delete, ""));
+        let entry = app.messages.last().unwrap();
+        assert_eq!(entry.text, "mine");
+        assert!(!entry.edited && !entry.deleted);
+
+        app.handle_service_event(amended(AmendmentKind::React, "🎉"));
+        app.handle_service_event(amended(AmendmentKind::React, "❤"));
+        assert_eq!(
+            app.messages.last().unwrap().reactions,
+            [Reaction {
+                author: "peer".into(),
+                emoji: "❤".into()
+            }]
+        );
+
+        app.handle_service_event(ServiceEvent::MessageAmended {
+            peer,
+            author: "me".into(),
+            amendment: Amendment {
+                id: 7,
+                kind: AmendmentKind::Delete,
+            },
+            text: String::new(),
+            outgoing: true,
+        });
+        let entry = app.messages.last().unwrap();
+        assert!(entry.deleted && entry.text.is_empty() && entry.reactions.is_empty());
+    }
+
+    #[test]
+    fn history_load_applies_amendments_and_reads_old_records() {
+        use flate2::{write::GzEncoder, Compression};
+        use serde::Serialize;
+
+        #[derive(Serialize)]
+        struct Legacy<'a>(i64, bool, &'a str, &'a str);
+        #[derive(Serialize)]
+        struct Record<'a>(i64, bool, &'a str, &'a str, u64, Option<Amendment>);
+        #[derive(Serialize)]
+        struct Reply<'a>(Record<'a>, Option<u64>);
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = [9u8; 32];
+        fs::write(dir.path().join("history.key"), key).unwrap();
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key));
+        let mut file = Vec::new();
+        let mut seal = |record: Vec<u8>| {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(&record).unwrap();
+            let nonce = [file.len() as u8; 12];
+            let sealed = cipher
+                .encrypt(
+                    GenericArray::from_slice(&nonce),
+                    gz.finish().unwrap().as_ref(),
+                )
+                .unwrap();
+            file.extend_from_slice(&nonce);
+            file.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+            file.extend_from_slice(&sealed);
+        };
+        fn encode(value: &impl Serialize) -> Vec<u8> {
+            bincode::serde::encode_to_vec(value, bincode::config::standard()).unwrap()
+        }
+        seal(encode(&Legacy(1, false, "peer", "old")));
+        seal(encode(&Record(2, true, "me", "helo", 7, None)));
+        seal(encode(&Record(3, false, "peer", "hi", 8, None)));
+        let edit = Amendment {
+            id: 7,
+            kind: AmendmentKind::Edit,
+        };
+        seal(encode(&Record(4, true, "me", "hello", 11, Some(edit))));
+        let react = Amendment {
+            id: 7,
+            kind: AmendmentKind::React,
+        };
+        seal(encode(&Record(5, false, "peer", "👍", 12, Some(react))));
+        let thanks = Record(6, false, "peer", "thanks", 13, None);
+        seal(encode(&Reply(thanks, Some(7))));
+        let path = dir.path().join("peer.hist");
+        fs::write(&path, &file).unwrap();
+
+        let mut config = AppConfig::default();
+        config.paths.history_dir = dir.path().to_path_buf();
+        let app = App::new(&config, &TuiCommand::default());
+        let prompt = HistoryPrompt {
+            peer: "127.0.0.1:5000".parse().unwrap(),
+            name: "peer".into(),
+            path,
+            bytes: None,
+        };
+        let (entries, total, skipped) = app.read_history_entries(&prompt).unwrap();
+        assert_eq!((total, skipped), (4, 0));
+        let texts: Vec<&str> = entries.iter().map(|entry| entry.text.as_str()).collect();
+        assert_eq!(texts, ["old", "hello", "hi", "thanks"]);
+        assert!(entries[1].edited);
+        assert_eq!(entries[1].reactions.len(), 1);
+        assert_eq!(entries[2].id, Some(8));
+        assert_eq!(entries[2].reply_to, None);
+        assert_eq!(entries[3].reply_to, Some(7));
+    }
+
+    #[test]
+    fn replies_carry_and_quote_the_selected_message() {
+        let config = AppConfig::default();
+        let mut app = App::new(&config, &TuiCommand::default());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        app.handle_service_event(ServiceEvent::Connected {
+            peer,
+            name: "peer".into(),
+        });
+        app.handle_service_event(ServiceEvent::MessageReceived {
+            peer,
+            id: 8,
+            author: "peer".into(),
+            text: "lunch\nat noon?".into(),
+            reply_to: None,
+        });
+        let press = |code| KeyEvent::new(code, KeyModifiers::NONE);
+
+        app.handle_key(KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL));
+        app.handle_key(press(KeyCode::Char('q')));
+        assert_eq!(app.mode, Mode::Reply(8));
+        app.input = "sure".into();
+        assert!(matches!(
+            app.handle_key(press(KeyCode::Enter)),
+            Some(ServiceCommand::SendText { peer: target, text, reply_to: Some(8) })
+                if target == peer && text == "sure"
+        ));
+        assert_eq!(app.mode, Mode::Chat);
+
+        app.handle_service_event(ServiceEvent::MessageSent {
+            peer,
+            id: 9,
+            author: "me".into(),
+            text: "sure".into(),
+            reply_to: Some(8),
+        });
+        let reply = app.messages.last().unwrap();
+        assert_eq!(
+            app.reply_quote(reply).as_deref(),
+            Some("peer: lunch at noon?")
+        );
+        let mut orphan = reply.clone();
+        orphan.reply_to = Some(1);
+        assert_eq!(app.reply_quote(&orphan).as_deref(), Some("earlier message"));
+        assert_eq!(app.reply_quote(&app.messages[0]), None);
+    }
+
     fn sample_message(text: &str) -> ChatEntry {
         ChatEntry {
             direction: MessageDirection::System,
             author: "system".into(),
             text: text.into(),
             timestamp: OffsetDateTime::now_utc(),
+            id: None,
+            receipt: None,
+            peer: None,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: None,
         }
     }
 
@@ -60,6 +60,7 @@ pub async fn run(mut service: DezapService, config: AppConfig, args: TuiCommand)
                     if let Some(cmd) = app.handle_key(key) {
                         if let Err(err) = service.send(cmd).await {
                             app.handle_service_event(ServiceEvent::Error {
+                                peer: None,
                                 message: err.to_string(),
                             });
                         }
@@ -76,6 +77,9 @@ pub async fn run(mut service: DezapService, config: AppConfig, args: TuiCommand)
                 }
             }
         }
+        for cmd in app.take_commands() {
+            service.send(cmd).await.ok();
+        }
 
         if app.should_quit {
             break;
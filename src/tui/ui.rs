@@ -1,8 +1,10 @@
 use std::borrow::Cow;
 
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::prelude::*;
-use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::widgets::{Block, Borders, Clear, Gauge, List, ListItem, ListState, Paragraph, Wrap};
 use time::macros::format_description;
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
@@ -10,7 +12,7 @@ use crate::service::TransferDirection;
 
 use textwrap::wrap;
 
-use super::app::{App, ConnectionStatus, MessageDirection, Mode, PanelFocus};
+use super::app::{App, ConnectionStatus, MessageDirection, Mode, PanelFocus, Receipt};
 
 const BANNER_LINE: &str = "Retro LAN QUIC Messenger";
 const TRANSCRIPT_TITLE: &str =
@@ -44,6 +46,7 @@ pub fn draw(frame: &mut Frame<'_>, app: &App) {
     draw_messages(frame, chat[0], app);
     draw_input(frame, chat[1], app);
     draw_sidebar(frame, columns[1], app);
+    draw_safety_dialog(frame, app);
 }
 
 fn draw_transcript(frame: &mut Frame<'_>, area: Rect, app: &App) {
@@ -66,6 +69,9 @@ fn draw_transcript(frame: &mut Frame<'_>, area: Rect, app: &App) {
             }
         }
         ConnectionStatus::Connecting(addr) => format!("Connecting to {addr}…"),
+        ConnectionStatus::Reconnecting { peer, attempt } => {
+            format!("Reconnecting to {peer}… (attempt {attempt})")
+        }
         ConnectionStatus::Connected { ref name, peer } => {
             format!("Connected to {name} ({peer})")
         }
@@ -138,10 +144,31 @@ fn draw_messages(frame: &mut Frame<'_>, area: Rect, app: &App) {
         } else {
             "  "
         };
-        let prefix = format!("{marker}[{ts}] {} • ", label);
-        let prefix_width = UnicodeWidthStr::width(prefix.as_str());
+        let prefix = format!("{marker}[{ts}] {} ", label);
+        // Outgoing messages carry ◷ while queued, ✓ once sent, ✓✓ once delivered, and a
+        // blue ✓✓ once read.
+        let (ticks, tick_color) = match entry.receipt {
+            Some(Receipt::Pending) => ("◷ pending ", Color::DarkGray),
+            Some(Receipt::Sent) => ("✓ ", Color::Gray),
+            Some(Receipt::Delivered) => ("✓✓ ", Color::Gray),
+            Some(Receipt::Read) => ("✓✓ ", Color::LightBlue),
+            None => ("", Color::Gray),
+        };
+        let prefix_width = UnicodeWidthStr::width(prefix.as_str())
+            + UnicodeWidthStr::width(ticks)
+            + UnicodeWidthStr::width("• ");
         let available = inner_width.saturating_sub(prefix_width).max(1);
-        let wrapped = wrap(&entry.text, available)
+        let (body, text_color): (Cow<'_, str>, Color) = if entry.deleted {
+            (Cow::Borrowed("🗑 message deleted"), Color::DarkGray)
+        } else if entry.edited {
+            (
+                Cow::Owned(format!("{} (edited)", entry.text)),
+                entry.direction.style(),
+            )
+        } else {
+            (Cow::Borrowed(entry.text.as_str()), entry.direction.style())
+        };
+        let wrapped = wrap(&body, available)
             .into_iter()
             .map(|cow| cow.to_string())
             .collect::<Vec<_>>();
@@ -152,23 +179,50 @@ fn draw_messages(frame: &mut Frame<'_>, area: Rect, app: &App) {
         };
         let indent = " ".repeat(prefix_width);
         let mut lines = Vec::new();
+        // Replies quote the start of the message they answer on the line above.
+        if let Some(quote) = app.reply_quote(entry) {
+            let quote = truncate_to_width(&format!("↪ {quote}"), available);
+            lines.push(Line::from(vec![
+                Span::raw(indent.clone()),
+                Span::styled(
+                    quote,
+                    Style::default()
+                        .fg(Color::DarkGray)
+                        .add_modifier(Modifier::ITALIC),
+                ),
+            ]));
+        }
         if let Some(first) = pieces.first() {
             lines.push(Line::from(vec![
                 Span::styled(prefix.clone(), Style::default().fg(Color::Gray)),
-                Span::styled(first.clone(), Style::default().fg(entry.direction.style())),
+                Span::styled(ticks, Style::default().fg(tick_color)),
+                Span::styled("• ", Style::default().fg(Color::Gray)),
+                Span::styled(first.clone(), Style::default().fg(text_color)),
             ]));
             for rest in pieces.iter().skip(1) {
                 lines.push(Line::from(vec![
                     Span::raw(indent.clone()),
-                    Span::styled(rest.clone(), Style::default().fg(entry.direction.style())),
+                    Span::styled(rest.clone(), Style::default().fg(text_color)),
                 ]));
             }
         }
-        heights.push(pieces.len().max(1));
+        if !entry.reactions.is_empty() {
+            let reactions = entry
+                .reactions
+                .iter()
+                .map(|reaction| format!("{} {}", reaction.emoji, reaction.author))
+                .collect::<Vec<_>>()
+                .join("  ");
+            lines.push(Line::from(vec![
+                Span::raw(indent.clone()),
+                Span::styled(reactions, Style::default().fg(Color::Yellow)),
+            ]));
+        }
+        heights.push(lines.len().max(1));
         items.push(ListItem::new(lines));
     }
     let title = if app.chat_focus {
-        "Chat ▸ browse (Esc to exit, ↑/↓ move, V mark, C copy)"
+        "Chat ▸ browse (Esc to exit, ↑/↓ move, V mark, C copy, E edit, D delete, R react, Q reply)"
     } else {
         "Chat"
     };
@@ -257,6 +311,7 @@ fn draw_sidebar(frame: &mut Frame<'_>, area: Rect, app: &App) {
             Constraint::Length(11),
             Constraint::Length(5),
             Constraint::Length(7),
+            Constraint::Length(5),
             Constraint::Length(4),
             Constraint::Length(4),
             Constraint::Min(5),
@@ -266,9 +321,10 @@ fn draw_sidebar(frame: &mut Frame<'_>, area: Rect, app: &App) {
     draw_header(frame, chunks[0], app);
     draw_status(frame, chunks[1], app);
     draw_transfers(frame, chunks[2], app);
-    draw_discovery(frame, chunks[3], app);
-    draw_saved_peers(frame, chunks[4], app);
-    draw_help(frame, chunks[5], app);
+    draw_offers(frame, chunks[3], app);
+    draw_discovery(frame, chunks[4], app);
+    draw_saved_peers(frame, chunks[5], app);
+    draw_help(frame, chunks[6], app);
 }
 
 fn draw_header(frame: &mut Frame<'_>, area: Rect, app: &App) {
@@ -283,6 +339,14 @@ fn draw_header(frame: &mut Frame<'_>, area: Rect, app: &App) {
             Style::default().fg(Color::DarkGray),
         )));
     }
+    if let Some((name, verified)) = app.active_peer_trust() {
+        let (label, color) = if verified {
+            (format!("✔ {name} verified"), Color::LightGreen)
+        } else {
+            (format!("⚠ {name} unverified (Ctrl+V)"), Color::LightYellow)
+        };
+        lines.push(Line::from(Span::styled(label, Style::default().fg(color))));
+    }
     let paragraph = Paragraph::new(lines).alignment(Alignment::Center).block(
         Block::default()
             .title("Dezap - TheJohn")
@@ -292,6 +356,57 @@ fn draw_header(frame: &mut Frame<'_>, area: Rect, app: &App) {
     frame.render_widget(paragraph, area);
 }
 
+fn draw_safety_dialog(frame: &mut Frame<'_>, app: &App) {
+    let Some(dialog) = app.safety_dialog() else {
+        return;
+    };
+    let mut lines: Vec<Line<'_>> = vec![
+        Line::from(format!("{} ({})", dialog.name, dialog.peer)),
+        Line::from(""),
+        Line::from(Span::styled(
+            dialog.number.clone(),
+            Style::default()
+                .fg(Color::LightYellow)
+                .add_modifier(Modifier::BOLD),
+        )),
+        Line::from(""),
+    ];
+    if let Ok(code) = QrCode::new(dialog.number.replace(' ', "")) {
+        let rendered = code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        lines.extend(rendered.lines().map(|row| Line::from(row.to_string())));
+        lines.push(Line::from(""));
+    }
+    let (state, color) = if dialog.verified {
+        ("Verified", Color::LightGreen)
+    } else {
+        ("Not verified", Color::LightYellow)
+    };
+    lines.push(Line::from(Span::styled(state, Style::default().fg(color))));
+    lines.push(Line::from("v: toggle verified • Esc: close"));
+
+    let area = frame.area();
+    let width = (lines.iter().map(Line::width).max().unwrap_or(0) as u16 + 4).min(area.width);
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+    let paragraph = Paragraph::new(lines).alignment(Alignment::Center).block(
+        Block::default()
+            .title("Safety number")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(app.accent)),
+    );
+    frame.render_widget(Clear, popup);
+    frame.render_widget(paragraph, popup);
+}
+
 fn draw_status(frame: &mut Frame<'_>, area: Rect, app: &App) {
     let status = match app.connection {
         ConnectionStatus::Disconnected => "Disconnected".into(),
@@ -303,10 +418,21 @@ fn draw_status(frame: &mut Frame<'_>, area: Rect, app: &App) {
             }
         }
         ConnectionStatus::Connecting(addr) => format!("Connecting to {addr}…"),
+        ConnectionStatus::Reconnecting { peer, attempt } => {
+            format!("Reconnecting to {peer}… (attempt {attempt})")
+        }
         ConnectionStatus::Connected { peer, ref name } => {
             format!("Connected to {name} ({peer})")
         }
     };
+    let mut status = if app.online_count() > 1 {
+        format!("{status} +{} more", app.online_count() - 1)
+    } else {
+        status
+    };
+    if let Some(name) = app.typing_peer() {
+        status.push_str(&format!(" · {name} is typing…"));
+    }
 
     let lines = vec![
         Line::from(vec![
@@ -345,20 +471,29 @@ fn draw_status(frame: &mut Frame<'_>, area: Rect, app: &App) {
 
 fn draw_transfers(frame: &mut Frame<'_>, area: Rect, app: &App) {
     if app.transfers.is_empty() {
-        let empty = Paragraph::new("No transfers")
-            .block(Block::default().title("Transfers").borders(Borders::ALL));
+        let empty = Paragraph::new("No transfers").block(
+            Block::default()
+                .title("Transfers (Ctrl+T)")
+                .borders(Borders::ALL),
+        );
         frame.render_widget(empty, area);
         return;
     }
 
+    let selected = app
+        .selected_transfer()
+        .filter(|_| app.panel_focus() == PanelFocus::Transfers);
+    // Scroll so the highlighted transfer stays visible.
+    let visible = (area.height / 3).max(1) as usize;
+    let skip = selected.map_or(0, |idx| (idx + 1).saturating_sub(visible));
     let mut offset = area.y;
-    for transfer in &app.transfers {
+    for (idx, transfer) in app.transfers.iter().enumerate().skip(skip) {
         let ratio = if transfer.total == 0 {
             0.0
         } else {
             transfer.transferred as f64 / transfer.total as f64
         };
-        let label = format!(
+        let mut label = format!(
             "{} {}",
             transfer.name,
             match transfer.direction {
@@ -366,15 +501,40 @@ fn draw_transfers(frame: &mut Frame<'_>, area: Rect, app: &App) {
                 TransferDirection::Outgoing => "⬆",
             }
         );
+        if let Some(entry) = transfer.entry.as_ref().filter(|_| !transfer.completed) {
+            let percent = (entry.transferred * 100)
+                .checked_div(entry.size)
+                .unwrap_or(100);
+            label.push_str(&format!(
+                " {}/{} {} {percent}%",
+                entry.index + 1,
+                entry.count,
+                entry.path
+            ));
+        }
+        if transfer.paused && !transfer.completed {
+            label.push_str(" (paused)");
+        }
+        if let Some(speed) = transfer.speed_label() {
+            label.push_str(&format!(" {speed}"));
+        }
+        let mut block = Block::default().borders(Borders::ALL);
+        if selected == Some(idx) {
+            block = block
+                .title("p pause/resume · x cancel")
+                .border_style(Style::default().fg(Color::LightYellow));
+        }
         let gauge = Gauge::default()
             .ratio(ratio.min(1.0))
             .label(label)
             .style(if transfer.completed {
                 Style::default().fg(Color::LightGreen)
+            } else if transfer.paused {
+                Style::default().fg(Color::DarkGray)
             } else {
                 Style::default().fg(app.accent)
             })
-            .block(Block::default().borders(Borders::ALL));
+            .block(block);
         let chunk = Rect::new(
             area.x,
             offset,
@@ -389,6 +549,28 @@ fn draw_transfers(frame: &mut Frame<'_>, area: Rect, app: &App) {
     }
 }
 
+fn draw_offers(frame: &mut Frame<'_>, area: Rect, app: &App) {
+    let labels = app.offer_labels();
+    let items: Vec<ListItem<'static>> = if labels.is_empty() {
+        vec![ListItem::new("No offers waiting")]
+    } else {
+        labels.into_iter().map(ListItem::new).collect()
+    };
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title("File Offers (Ctrl+O)")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(app.accent)),
+        )
+        .highlight_style(Style::default().fg(Color::LightYellow));
+    let mut state = ListState::default();
+    if app.panel_focus() == PanelFocus::Offers {
+        state.select(app.selected_offer());
+    }
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
 fn draw_discovery(frame: &mut Frame<'_>, area: Rect, app: &App) {
     let items: Vec<ListItem<'static>> = if app.discovered.is_empty() {
         vec![ListItem::new("No peers")]
@@ -426,10 +608,10 @@ fn draw_saved_peers(frame: &mut Frame<'_>, area: Rect, app: &App) {
     let items: Vec<ListItem<'static>> = if app.saved_peers.is_empty() {
         vec![ListItem::new("No saved peers yet")]
     } else {
-        app.saved_peers
-            .iter()
+        app.saved_peer_labels()
+            .into_iter()
             .enumerate()
-            .map(|(idx, peer)| ListItem::new(format!("{idx:>2}. {} ({})", peer.name, peer.addr)))
+            .map(|(idx, label)| ListItem::new(format!("{idx:>2}. {label}")))
             .collect()
     };
     let list = List::new(items).block(
@@ -460,12 +642,18 @@ fn draw_help(frame: &mut Frame<'_>, area: Rect, app: &App) {
         ("Ctrl+K", "Connect to peer"),
         ("Ctrl+P", "Focus discovered peers"),
         ("Ctrl+S", "Focus saved peers"),
+        ("Ctrl+T", "Focus transfers (p pause, x cancel)"),
+        ("Ctrl+O", "Focus file offers (x decline)"),
         ("Ctrl+X", "Disconnect from peer"),
+        ("Ctrl+N", "Switch active peer"),
+        ("Ctrl+V", "Verify safety number"),
         ("Tab", "Toggle help / autocomplete paths"),
         ("Ctrl+G", "Focus chat history"),
         ("Arrows", "Navigate focused chat"),
         ("Enter (panel)", "Connect to highlighted peer"),
         ("C (browse)", "Copy highlighted message"),
+        ("E/D/R (browse)", "Edit, delete, react to message"),
+        ("Q (browse)", "Reply to message"),
         ("Ctrl+F", "Send a file"),
         ("Ctrl+U", "Rename yourself"),
         ("Ctrl+D", "Discover peers"),
@@ -511,6 +699,9 @@ fn draw_input(frame: &mut Frame<'_>, area: Rect, app: &App) {
         Mode::Username => "Nickname",
         Mode::DiscoveryNetwork => "Discovery broadcast",
         Mode::IncomingFile(_) => "Save incoming file as",
+        Mode::EditMessage(_) => "Edit message",
+        Mode::React(_) => "Reaction",
+        Mode::Reply(_) => "Reply",
     };
     let input_height = rows[0].height.saturating_sub(2).max(1);
     let input_width = rows[0].width.saturating_sub(2).max(1);
@@ -554,7 +745,7 @@ fn draw_input(frame: &mut Frame<'_>, area: Rect, app: &App) {
             if let Some(rest) = app.input.strip_prefix("d/") {
                 let trimmed = rest.trim();
                 if trimmed.is_empty() {
-                    "Commands: d/clear, d/save, d/search, d/mark, d/last, d/status, d/purge, d/anon, d/help, d/history"
+                    "Commands: d/clear, d/save, d/search, d/mark, d/last, d/status, d/purge, d/anon, d/help, d/history, d/trust"
                 } else {
                     app.status_line.as_str()
                 }
@@ -613,6 +804,25 @@ fn measure_input(text: &str, width: usize) -> (usize, usize, usize) {
     (total_lines, cursor_line, cursor_col)
 }
 
+/// Cuts `text` to fit in `width` columns, ending it with `…` when something was cut.
+fn truncate_to_width(text: &str, width: usize) -> String {
+    if UnicodeWidthStr::width(text) <= width {
+        return text.to_string();
+    }
+    let mut used = UnicodeWidthChar::width('…').unwrap_or(1);
+    let mut out = String::new();
+    for ch in text.chars() {
+        let w = ch.width().unwrap_or(0);
+        if used + w > width {
+            break;
+        }
+        used += w;
+        out.push(ch);
+    }
+    out.push('…');
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -632,6 +842,13 @@ mod tests {
                 author: "system".into(),
                 text: format!("msg #{idx}"),
                 timestamp: time::OffsetDateTime::now_utc(),
+                id: None,
+                receipt: None,
+                peer: None,
+                edited: false,
+                deleted: false,
+                reactions: Vec::new(),
+                reply_to: None,
             });
         }
 
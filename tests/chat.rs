@@ -0,0 +1,242 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+use dezap::config::{AppConfig, CodecChoice};
+use dezap::protocol::PresenceState;
+use dezap::service::{DezapService, ServiceCommand, ServiceEvent};
+
+fn config(dir: &Path, name: &str) -> AppConfig {
+    let mut config = AppConfig::default();
+    config.identity.username = name.into();
+    config.listen.bind_addr = "127.0.0.1:0".parse().unwrap();
+    config.discovery.enabled = false;
+    config.paths.download_dir = dir.join(name).join("downloads");
+    config.paths.history_dir = dir.join(name).join("history");
+    config.paths.peers_file = dir.join(name).join("peers.json");
+    config.paths.chat_log = None;
+    config
+}
+
+async fn next_matching(
+    service: &mut DezapService,
+    pred: impl Fn(&ServiceEvent) -> bool,
+) -> ServiceEvent {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(10), service.next_event())
+            .await
+            .expect("event within timeout")
+            .expect("service running");
+        if pred(&event) {
+            return event;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires permission to bind UDP sockets"]
+async fn burst_of_messages_arrives_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut alice = DezapService::new(config(dir.path(), "alice"));
+    let mut bob = DezapService::new(config(dir.path(), "bob"));
+
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    alice
+        .send(ServiceCommand::Listen {
+            addr,
+            password: None,
+        })
+        .await
+        .unwrap();
+    next_matching(&mut alice, |e| matches!(e, ServiceEvent::Listening { .. })).await;
+    bob.send(ServiceCommand::Connect {
+        addr,
+        password: None,
+    })
+    .await
+    .unwrap();
+    next_matching(&mut bob, |e| matches!(e, ServiceEvent::PeerProfile { .. })).await;
+
+    let burst: Vec<String> = (0..50).map(|n| format!("message {n}")).collect();
+    for text in &burst {
+        bob.send(ServiceCommand::SendText {
+            peer: addr,
+            text: text.clone(),
+            reply_to: None,
+        })
+        .await
+        .unwrap();
+    }
+    tokio::spawn(async move { while bob.next_event().await.is_some() {} });
+
+    let mut received = Vec::new();
+    while received.len() < burst.len() {
+        if let ServiceEvent::MessageReceived { text, .. } = next_matching(&mut alice, |e| {
+            matches!(e, ServiceEvent::MessageReceived { .. })
+        })
+        .await
+        {
+            received.push(text);
+        }
+    }
+    assert_eq!(received, burst);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires permission to bind UDP sockets"]
+async fn throttled_transfer_outlives_the_idle_ttl() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sender = config(dir.path(), "alice");
+    // Sending takes about four times as long as an idle transfer is kept.
+    sender.limits.partial_ttl_secs = 1;
+    sender.limits.chunk_size_bytes = 16 * 1024;
+    sender.limits.transfer_upload_bytes_per_sec = 64 * 1024;
+    sender.transfer.codec = CodecChoice::None;
+    let mut alice = DezapService::new(sender);
+    let mut bob = DezapService::new(config(dir.path(), "bob"));
+    let source = dir.path().join("big.bin");
+    std::fs::write(&source, vec![7u8; 256 * 1024]).unwrap();
+
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    alice
+        .send(ServiceCommand::Listen {
+            addr,
+            password: None,
+        })
+        .await
+        .unwrap();
+    next_matching(&mut alice, |e| matches!(e, ServiceEvent::Listening { .. })).await;
+    bob.send(ServiceCommand::Connect {
+        addr,
+        password: None,
+    })
+    .await
+    .unwrap();
+    let ServiceEvent::PeerProfile { peer, .. } = next_matching(&mut alice, |e| {
+        matches!(e, ServiceEvent::PeerProfile { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    alice
+        .send(ServiceCommand::SendFile {
+            peer,
+            paths: vec![source],
+        })
+        .await
+        .unwrap();
+    let ServiceEvent::FileOffer(offer) =
+        next_matching(&mut bob, |e| matches!(e, ServiceEvent::FileOffer(_))).await
+    else {
+        unreachable!()
+    };
+    bob.send(ServiceCommand::AcceptFile {
+        peer: offer.peer,
+        id: offer.id,
+        path: dir.path().join("bob").join("big.bin"),
+        on_collision: None,
+    })
+    .await
+    .unwrap();
+    tokio::spawn(async move { while bob.next_event().await.is_some() {} });
+
+    let finished = next_matching(&mut alice, |e| match e {
+        ServiceEvent::FileTransfer(progress) => progress.completed,
+        ServiceEvent::TransferAborted { .. } => true,
+        _ => false,
+    })
+    .await;
+    assert!(
+        matches!(finished, ServiceEvent::FileTransfer(_)),
+        "{finished:?}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires permission to bind UDP sockets"]
+async fn presence_and_typing_are_sealed_and_delivered() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut alice = DezapService::new(config(dir.path(), "alice"));
+    let mut bob = DezapService::new(config(dir.path(), "bob"));
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    alice
+        .send(ServiceCommand::Listen {
+            addr,
+            password: None,
+        })
+        .await
+        .unwrap();
+    next_matching(&mut alice, |e| matches!(e, ServiceEvent::Listening { .. })).await;
+    bob.send(ServiceCommand::Connect {
+        addr,
+        password: None,
+    })
+    .await
+    .unwrap();
+    next_matching(&mut bob, |e| {
+        matches!(
+            e,
+            ServiceEvent::PeerPresence {
+                state: PresenceState::Online,
+                ..
+            }
+        )
+    })
+    .await;
+
+    alice
+        .send(ServiceCommand::SetPresence {
+            state: PresenceState::Busy,
+            status: Some("lunch".into()),
+        })
+        .await
+        .unwrap();
+    let presence = next_matching(&mut bob, |e| {
+        matches!(
+            e,
+            ServiceEvent::PeerPresence {
+                state: PresenceState::Busy,
+                ..
+            }
+        )
+    })
+    .await;
+    let ServiceEvent::PeerPresence { status, .. } = presence else {
+        unreachable!()
+    };
+    assert_eq!(status.as_deref(), Some("lunch"));
+    let ServiceEvent::PeerProfile { peer: bob_addr, .. } = next_matching(&mut alice, |e| {
+        matches!(e, ServiceEvent::PeerProfile { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    alice
+        .send(ServiceCommand::SetTyping {
+            peer: bob_addr,
+            typing: true,
+        })
+        .await
+        .unwrap();
+    next_matching(&mut bob, |e| {
+        matches!(e, ServiceEvent::PeerTyping { typing: true, .. })
+    })
+    .await;
+}